@@ -12,13 +12,48 @@ module! {
     authors: ["fermata"],
     description: "SKM is a simple linux kernel module written in rust",
     license: "GPL",
+    params: {
+        gravity_curve: u32 {
+            default: 0,
+            description: "Default gravity curve: 0 = modern guideline, 1 = classic NES",
+        },
+        start_level: u32 {
+            default: 1,
+            description: "Level new games start at (1-30)",
+        },
+        board_width: u32 {
+            default: 10,
+            description: "Board width in cells (4-16)",
+        },
+        board_height: u32 {
+            default: 20,
+            description: "Visible board height in cells (4-32)",
+        },
+        demo_idle_secs: u32 {
+            default: 0,
+            description: "Seconds without input before a demo game starts (0 = never)",
+        },
+        instances: u32 {
+            default: 0,
+            description: "Games /dev/tetris0.. to create instead of /dev/tetris (0-8)",
+        },
+        debugfs: u32 {
+            default: 1,
+            description: "Create the files in debugfs tetris/ (0 = don't, for locked-down systems)",
+        },
+        device_mode: u32 {
+            default: 0,
+            description: "Device node permissions, e.g. 0666 to let anyone play (0 = root only)",
+        },
+    },
 }
 
 struct SASTKernelModule {
-    _tetris_inner: kernel::sync::Arc<tetris::TetrisDeviceInner>,
-    _dev:
-        Pin<kernel::alloc::KBox<kernel::miscdevice::MiscDeviceRegistration<tetris::TetrisDevice>>>,
-    _debugfs: tetris::TetrisDebugFs,
+    _sysctl: tetris::TetrisSysctl,
+    _proc: tetris::TetrisProc,
+    _config: tetris::TetrisConfigSubsystem,
+    _control: tetris::MiscRegistration<tetris::TetrisControl, tetris::TetrisGames>,
+    _games: kernel::sync::Arc<tetris::TetrisGames>,
 }
 
 #[allow(unreachable_code)]
@@ -28,20 +63,41 @@ impl kernel::Module for SASTKernelModule {
         pr_info!("Am I built-in? {}\n", !cfg!(MODULE));
 
         pr_info!("Tetris kernel module loaded!\n");
-        pr_info!("Device: /dev/tetris\n");
-        pr_info!("Controls: a=left, d=right, s=down, w=rotate, space=drop, r=reset\n");
+        let name = tetris::device_name().to_str().unwrap_or("tetris");
+        match *module_parameters::instances.value() {
+            0 => {
+                pr_info!("Device: /dev/{}\n", name);
+                pr_info!("Events: /dev/{}-events\n", name);
+            }
+            n => {
+                pr_info!("Devices: /dev/{}0 to /dev/{}{}\n", name, name, n - 1);
+                pr_info!("Events: /dev/{}0-events to /dev/{}{}-events\n", name, name, n - 1);
+            }
+        }
+        pr_info!("Controls: a=left, d=right, s=soft drop, x=soft drop to floor, w=rotate, z=rotate ccw, c=hold, space=drop, r=reset, p=pause, q=end game, arrows=w/a/s/d, 4a=left x4\n");
 
 //      panic!("Try fix me!");
-        let _tetris_inner = tetris::create_tetris_inner()?;
-        let _dev = tetris::register_tetris_device(_tetris_inner.clone())?;
-        let _debugfs = tetris::register_tetris_debugfs(_tetris_inner.clone())?;
+        let _sysctl = tetris::register_tetris_sysctl()?;
+        let _games = tetris::create_tetris_games()?;
+        let _control = tetris::register_tetris_control(_games.clone())?;
+        let _config = tetris::register_tetris_configfs(_games.clone())?;
+        let _proc = tetris::register_tetris_proc(_games.clone())?;
+        tetris::register_tetris_bpf()?;
 
-        pr_info!("debugfs: /sys/kernel/debug/tetris/state\n");
+        pr_info!("Control: /dev/tetris-control\n");
+        pr_info!("Summary: /proc/tetris\n");
+        pr_info!("Tuning: /proc/sys/kernel/tetris/\n");
+
+        if *module_parameters::debugfs.value() != 0 {
+            pr_info!("debugfs: /sys/kernel/debug/tetris/\n");
+        }
 
         Ok(Self {
-            _tetris_inner,
-            _dev,
-            _debugfs,
+            _sysctl,
+            _proc,
+            _config,
+            _control,
+            _games,
         })
     }
 }
@@ -2,24 +2,188 @@
 // SPDX-License-Identifier: GPL-2.0
 
 //! Tetris game kernel module with character device interface
+//!
+//! The rules of the game are in `rules`, which uses nothing from the kernel
+//! so it can be tested on its own. The game played by them is in `engine`,
+//! drawing it in `render`, the character devices in `chardev` and the
+//! debugfs files in `debugfs`. What's left here ties them together: a
+//! device's game and its lock, the timers and work driving it, the numbered
+//! games and the configfs, procfs and BPF hooks.
+
+mod chardev;
+mod debugfs;
+mod engine;
+mod render;
+mod rules;
 
 use kernel::{
-    debugfs,
-    device,
-    fs::{File, Kiocb},
-    iov::{IovIterDest, IovIterSource},
-    miscdevice::{MiscDevice, MiscDeviceOptions, MiscDeviceRegistration},
+    bindings,
+    ffi::{c_char, c_int, c_ulong, c_void},
+    page::Page,
     prelude::*,
-    sync::Arc,
-    time,
-    types::ForeignOwnable,
+    sync::{
+        lock::{Backend, Guard, Lock},
+        poll::PollCondVar,
+        Arc, ArcBorrow, CondVar, SpinLock,
+    },
+    time::{
+        self,
+        hrtimer::{
+            ArcHrTimerHandle, HrTimer, HrTimerCallback, HrTimerCallbackContext, HrTimerHandle,
+            HrTimerPointer, HrTimerRestart, RelativeMode,
+        },
+        Delta,
+    },
+    workqueue::{self, DelayedWork, Work, WorkItem},
 };
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
 
-const BOARD_WIDTH: usize = 10;
-const BOARD_HEIGHT: usize = 20;
-const RENDER_BUFFER_SIZE: usize = 4096;
+pub(crate) use chardev::{register_tetris_control, MiscRegistration, TetrisControl};
+pub(crate) use debugfs::unregister_tetris_debugfs;
+use chardev::{
+    register_tetris_device, register_tetris_events, MmapState, TetrisDevice, TetrisEvents,
+    TetrisState, KEYS_PER_LOCK, MMAP_VERSION,
+};
+use debugfs::{register_tetris_debugfs, TetrisDebugFs, TetrisDebugPieces, TetrisDebugTotals};
+use engine::{ktime_now_ns, GravityCurve, ReplayInput, TetrisGame};
+use render::{GameView, RenderOptions, TextFrame};
+
+/// Most numbered games (`instances` parameter), each named by one digit.
+const INSTANCES_MAX: usize = 8;
+
+/// Counts of a few `TetrisStats` counters over every game since load, for
+/// debugfs `tetris/global_stats`. Resetting a game's stats leaves them.
+struct TetrisTotals {
+    games_played: AtomicU64,
+    reads: PerCpuTotal,
+    writes: PerCpuTotal,
+    ioctls: PerCpuTotal,
+    resets: AtomicU64,
+    pieces_locked: AtomicU64,
+    lines_cleared: AtomicU64,
+    hard_drops: AtomicU64,
+}
+
+static TOTALS: TetrisTotals = TetrisTotals {
+    games_played: AtomicU64::new(0),
+    reads: PerCpuTotal::READS,
+    writes: PerCpuTotal::WRITES,
+    ioctls: PerCpuTotal::IOCTLS,
+    resets: AtomicU64::new(0),
+    pieces_locked: AtomicU64::new(0),
+    lines_cleared: AtomicU64::new(0),
+    hard_drops: AtomicU64::new(0),
+};
+
+/// A `TetrisStats` counter that also counts into one of `TOTALS`.
+struct Counter {
+    count: AtomicU64,
+    total: &'static AtomicU64,
+}
+
+impl Counter {
+    const fn new(total: &'static AtomicU64) -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total,
+        }
+    }
+
+    fn fetch_add(&self, n: u64, order: Ordering) -> u64 {
+        self.total.fetch_add(n, order);
+        self.count.fetch_add(n, order)
+    }
+
+    fn load(&self, order: Ordering) -> u64 {
+        self.count.load(order)
+    }
+
+    /// Only this game's count; the total keeps going.
+    fn store(&self, n: u64, order: Ordering) {
+        self.count.store(n, order)
+    }
+}
+
+// Counters kept per CPU, which live in tetris_percpu.c.
+extern "C" {
+    fn tetris_percpu_alloc() -> *mut u64;
+    fn tetris_percpu_free(counter: *mut u64);
+    fn tetris_percpu_add(counter: *mut u64, total: c_int, n: u64);
+    fn tetris_percpu_sum(counter: *mut u64) -> u64;
+    fn tetris_percpu_zero(counter: *mut u64);
+    fn tetris_percpu_total(total: c_int) -> u64;
+}
+
+/// A total in `TOTALS` kept per CPU, by its `enum tetris_total` slot in
+/// tetris_percpu.h.
+#[derive(Clone, Copy)]
+struct PerCpuTotal(c_int);
+
+impl PerCpuTotal {
+    const READS: Self = Self(0);
+    const WRITES: Self = Self(1);
+    const IOCTLS: Self = Self(2);
+    /// Not counted into any total.
+    const NONE: Self = Self(-1);
+
+    fn load(self, _order: Ordering) -> u64 {
+        // SAFETY: `self` is a slot of `enum tetris_total`.
+        unsafe { tetris_percpu_total(self.0) }
+    }
+}
+
+/// A `TetrisStats` counter bumped on every read, write, ioctl or tick, so
+/// by many CPUs at once. Each CPU counts in its own copy, also counting
+/// into `total`, and reading it adds the copies up.
+struct PerCpuCounter {
+    counter: *mut u64,
+    total: PerCpuTotal,
+}
+
+// SAFETY: The counter is only changed through `this_cpu_add()` and zeroed, and only read by
+// adding it up; none of that minds which thread does it.
+unsafe impl Send for PerCpuCounter {}
+
+// SAFETY: As above.
+unsafe impl Sync for PerCpuCounter {}
+
+impl PerCpuCounter {
+    fn new(total: PerCpuTotal) -> Result<Self> {
+        // SAFETY: Just allocates.
+        let counter = unsafe { tetris_percpu_alloc() };
+        if counter.is_null() {
+            return Err(ENOMEM);
+        }
+        Ok(Self { counter, total })
+    }
+
+    fn fetch_add(&self, n: u64, _order: Ordering) {
+        // SAFETY: `counter` is ours until dropped, and `total` a slot or -1.
+        unsafe { tetris_percpu_add(self.counter, self.total.0, n) }
+    }
+
+    fn load(&self, _order: Ordering) -> u64 {
+        // SAFETY: `counter` is ours until dropped.
+        unsafe { tetris_percpu_sum(self.counter) }
+    }
+
+    /// Only this game's count; the total keeps going.
+    fn store(&self, n: u64, _order: Ordering) {
+        // Only ever reset.
+        debug_assert_eq!(n, 0);
+        // SAFETY: `counter` is ours until dropped.
+        unsafe { tetris_percpu_zero(self.counter) }
+    }
+}
+
+impl Drop for PerCpuCounter {
+    fn drop(&mut self) {
+        // SAFETY: Allocated by `new()`, and nothing can use it anymore.
+        unsafe { tetris_percpu_free(self.counter) }
+    }
+}
 
 /// Lightweight counters for observability via debugfs.
 ///
@@ -29,27 +193,47 @@ const RENDER_BUFFER_SIZE: usize = 4096;
 #[allow(dead_code)]
 struct TetrisStats {
     opens: AtomicU64,
-    reads: AtomicU64,
+    reads: PerCpuCounter,
     bytes_read: AtomicU64,
-    writes: AtomicU64,
+    /// Text frames rendered for reads, and those copied from one another
+    /// file rendered instead (see `TetrisDeviceInner::text_frame`).
+    frames_rendered: AtomicU64,
+    frames_cached: AtomicU64,
+    writes: PerCpuCounter,
     bytes_written: AtomicU64,
-    ioctls: AtomicU64,
+    ioctls: PerCpuCounter,
     invalid_ioctls: AtomicU64,
+    gravity_ticks: PerCpuCounter,
     invalid_inputs: AtomicU64,
 
     // High-level gameplay counters.
-    resets: AtomicU64,
+    /// Games started, the first one included.
+    games_played: Counter,
+    resets: Counter,
     pieces_spawned: AtomicU64,
-    pieces_locked: AtomicU64,
-    lines_cleared: AtomicU64,
+    pieces_locked: Counter,
+    lines_cleared: Counter,
     score_gained: AtomicU64,
+    soft_drop_cells: AtomicU64,
+    hard_drop_cells: AtomicU64,
+    tspins: AtomicU64,
+    tspin_minis: AtomicU64,
+    perfect_clears: AtomicU64,
+    sprints_completed: AtomicU64,
+    /// Fastest sprint since load, 0 if none completed.
+    sprint_best_ns: AtomicU64,
+    garbage_sent: AtomicU64,
+    garbage_received: AtomicU64,
+    auto_shifts: AtomicU64,
+    demo_games: AtomicU64,
+    games_abandoned: AtomicU64,
 
     // Input/action counters (attempted + succeeded where it makes sense).
     left: AtomicU64,
     right: AtomicU64,
     down: AtomicU64,
     rotate: AtomicU64,
-    drop: AtomicU64,
+    drop: Counter,
 
     left_ok: AtomicU64,
     right_ok: AtomicU64,
@@ -60,29 +244,45 @@ struct TetrisStats {
 }
 
 impl TetrisStats {
-    fn new() -> Self {
-        let now = <time::Monotonic as time::ClockSource>::ktime_get() as u64;
-        Self {
+    fn new() -> Result<Self> {
+        let now = ktime_now_ns();
+        Ok(Self {
             opens: AtomicU64::new(0),
-            reads: AtomicU64::new(0),
+            reads: PerCpuCounter::new(TOTALS.reads)?,
             bytes_read: AtomicU64::new(0),
-            writes: AtomicU64::new(0),
+            frames_rendered: AtomicU64::new(0),
+            frames_cached: AtomicU64::new(0),
+            writes: PerCpuCounter::new(TOTALS.writes)?,
             bytes_written: AtomicU64::new(0),
-            ioctls: AtomicU64::new(0),
+            ioctls: PerCpuCounter::new(TOTALS.ioctls)?,
             invalid_ioctls: AtomicU64::new(0),
+            gravity_ticks: PerCpuCounter::new(PerCpuTotal::NONE)?,
             invalid_inputs: AtomicU64::new(0),
 
-            resets: AtomicU64::new(0),
+            games_played: Counter::new(&TOTALS.games_played),
+            resets: Counter::new(&TOTALS.resets),
             pieces_spawned: AtomicU64::new(0),
-            pieces_locked: AtomicU64::new(0),
-            lines_cleared: AtomicU64::new(0),
+            pieces_locked: Counter::new(&TOTALS.pieces_locked),
+            lines_cleared: Counter::new(&TOTALS.lines_cleared),
             score_gained: AtomicU64::new(0),
+            soft_drop_cells: AtomicU64::new(0),
+            hard_drop_cells: AtomicU64::new(0),
+            tspins: AtomicU64::new(0),
+            tspin_minis: AtomicU64::new(0),
+            perfect_clears: AtomicU64::new(0),
+            sprints_completed: AtomicU64::new(0),
+            sprint_best_ns: AtomicU64::new(0),
+            garbage_sent: AtomicU64::new(0),
+            garbage_received: AtomicU64::new(0),
+            auto_shifts: AtomicU64::new(0),
+            demo_games: AtomicU64::new(0),
+            games_abandoned: AtomicU64::new(0),
 
             left: AtomicU64::new(0),
             right: AtomicU64::new(0),
             down: AtomicU64::new(0),
             rotate: AtomicU64::new(0),
-            drop: AtomicU64::new(0),
+            drop: Counter::new(&TOTALS.hard_drops),
 
             left_ok: AtomicU64::new(0),
             right_ok: AtomicU64::new(0),
@@ -90,7 +290,7 @@ impl TetrisStats {
             rotate_ok: AtomicU64::new(0),
 
             created_ns: AtomicU64::new(now),
-        }
+        })
     }
 
     #[allow(dead_code)]
@@ -99,17 +299,33 @@ impl TetrisStats {
         self.opens.store(0, Ordering::Relaxed);
         self.reads.store(0, Ordering::Relaxed);
         self.bytes_read.store(0, Ordering::Relaxed);
+        self.frames_rendered.store(0, Ordering::Relaxed);
+        self.frames_cached.store(0, Ordering::Relaxed);
         self.writes.store(0, Ordering::Relaxed);
         self.bytes_written.store(0, Ordering::Relaxed);
         self.ioctls.store(0, Ordering::Relaxed);
         self.invalid_ioctls.store(0, Ordering::Relaxed);
+        self.gravity_ticks.store(0, Ordering::Relaxed);
         self.invalid_inputs.store(0, Ordering::Relaxed);
 
+        self.games_played.store(0, Ordering::Relaxed);
         self.resets.store(0, Ordering::Relaxed);
         self.pieces_spawned.store(0, Ordering::Relaxed);
         self.pieces_locked.store(0, Ordering::Relaxed);
         self.lines_cleared.store(0, Ordering::Relaxed);
         self.score_gained.store(0, Ordering::Relaxed);
+        self.soft_drop_cells.store(0, Ordering::Relaxed);
+        self.hard_drop_cells.store(0, Ordering::Relaxed);
+        self.tspins.store(0, Ordering::Relaxed);
+        self.tspin_minis.store(0, Ordering::Relaxed);
+        self.perfect_clears.store(0, Ordering::Relaxed);
+        self.sprints_completed.store(0, Ordering::Relaxed);
+        self.sprint_best_ns.store(0, Ordering::Relaxed);
+        self.garbage_sent.store(0, Ordering::Relaxed);
+        self.garbage_received.store(0, Ordering::Relaxed);
+        self.auto_shifts.store(0, Ordering::Relaxed);
+        self.demo_games.store(0, Ordering::Relaxed);
+        self.games_abandoned.store(0, Ordering::Relaxed);
 
         self.left.store(0, Ordering::Relaxed);
         self.right.store(0, Ordering::Relaxed);
@@ -125,892 +341,1437 @@ impl TetrisStats {
 
     #[allow(dead_code)]
     fn uptime_ns(&self) -> u64 {
-        let now = <time::Monotonic as time::ClockSource>::ktime_get() as u64;
+        let now = ktime_now_ns();
         now.saturating_sub(self.created_ns.load(Ordering::Relaxed))
     }
 }
 
-/// Ioctl command codes
-const TETRIS_IOCTL_LEFT: u32 = 0x8000;
-const TETRIS_IOCTL_RIGHT: u32 = 0x8001;
-const TETRIS_IOCTL_DOWN: u32 = 0x8002;
-const TETRIS_IOCTL_ROTATE: u32 = 0x8003;
-const TETRIS_IOCTL_DROP: u32 = 0x8004;
-const TETRIS_IOCTL_RESET: u32 = 0x8005;
-
-/// Tetromino shapes (7 standard pieces)
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum TetrominoType {
-    I,
-    O,
-    T,
-    S,
-    Z,
-    J,
-    L,
-}
-
-/// Precomputed shape matrix for all rotations
-#[derive(Debug, Clone, Copy)]
-struct ShapeMatrix {
-    rotations: [[[bool; 4]; 4]; 4],
-}
-
-impl ShapeMatrix {
-    const fn from_base(base: [[bool; 4]; 4]) -> Self {
-        let mut rotations = [[[false; 4]; 4]; 4];
-        rotations[0] = base;
-        rotations[1] = Self::rotate_once(base);
-        rotations[2] = Self::rotate_once(rotations[1]);
-        rotations[3] = Self::rotate_once(rotations[2]);
-        Self { rotations }
-    }
-
-    const fn rotate_once(matrix: [[bool; 4]; 4]) -> [[bool; 4]; 4] {
-        let mut rotated = [[false; 4]; 4];
-        let mut i = 0;
-        while i < 4 {
-            let mut j = 0;
-            while j < 4 {
-                rotated[j][3 - i] = matrix[i][j];
-                j += 1;
-            }
-            i += 1;
-        }
-        rotated
-    }
-}
-
-/// Tetromino piece with position and rotation
-#[derive(Debug, Clone, Copy)]
-struct Tetromino {
-    piece_type: TetrominoType,
-    x: i32,
-    y: i32,
-    rotation: u8,
-}
-
-impl Tetromino {
-    const SHAPES: [ShapeMatrix; 7] = [
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [true, true, true, true],
-            [false, false, false, false],
-            [false, false, false, false],
-        ]),
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [false, true, true, false],
-            [false, true, true, false],
-            [false, false, false, false],
-        ]),
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [false, true, false, false],
-            [true, true, true, false],
-            [false, false, false, false],
-        ]),
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [false, true, true, false],
-            [true, true, false, false],
-            [false, false, false, false],
-        ]),
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [true, true, false, false],
-            [false, true, true, false],
-            [false, false, false, false],
-        ]),
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [true, false, false, false],
-            [true, true, true, false],
-            [false, false, false, false],
-        ]),
-        ShapeMatrix::from_base([
-            [false, false, false, false],
-            [false, false, true, false],
-            [true, true, true, false],
-            [false, false, false, false],
-        ]),
-    ];
-
-    fn new(piece_type: TetrominoType) -> Self {
-        Self {
-            piece_type,
-            x: (BOARD_WIDTH / 2) as i32 - 2,
-            y: 0,
-            rotation: 0,
-        }
-    }
-
-    fn get_shape(&self) -> [[bool; 4]; 4] {
-        let idx = match self.piece_type {
-            TetrominoType::I => 0,
-            TetrominoType::O => 1,
-            TetrominoType::T => 2,
-            TetrominoType::S => 3,
-            TetrominoType::Z => 4,
-            TetrominoType::J => 5,
-            TetrominoType::L => 6,
+/// BPF bots, attached as `tetris_bot_ops`, and the game state the kfuncs
+/// read (see tetris_bpf.c). Only built when the kernel can give the module
+/// BTF.
+#[cfg(all(CONFIG_BPF_JIT, CONFIG_DEBUG_INFO_BTF_MODULES))]
+mod bpf {
+    use super::engine::{BotView, PREVIEW_MAX};
+    use super::rules::{Tetromino, BOARD_MAX_ROWS};
+    use super::*;
+
+    /// Mirrors `struct tetris_bot_ctx` in tetris_bpf.h.
+    #[repr(C)]
+    struct BotContext {
+        rows: [u16; BOARD_MAX_ROWS],
+        cols: u8,
+        nr_rows: u8,
+        piece: u8,
+        preview_count: u8,
+        preview: [u8; PREVIEW_MAX],
+    }
+
+    /// Mirrors `struct tetris_bpf_state` in tetris_bpf.h.
+    #[repr(C)]
+    struct BpfState {
+        score: u64,
+        rows: [u16; BOARD_MAX_ROWS],
+        cols: u8,
+        nr_rows: u8,
+        piece: i8,
+        rotation: u8,
+        x: i32,
+        y: i32,
+    }
+
+    extern "C" {
+        fn tetris_bpf_init() -> c_int;
+        fn tetris_bpf_place(ctx: *mut BotContext) -> i32;
+        fn tetris_bpf_publish(state: *const BpfState);
+    }
+
+    pub(super) fn init() -> Result {
+        // SAFETY: Called once, from module init.
+        kernel::error::to_result(unsafe { tetris_bpf_init() })
+    }
+
+    /// Hand the kfuncs a copy of `game`.
+    pub(super) fn publish(game: &TetrisGame) {
+        let piece = game.current_piece;
+        let state = BpfState {
+            score: game.score,
+            rows: game.row_masks(),
+            cols: game.cols() as u8,
+            nr_rows: game.rows() as u8,
+            piece: piece.map_or(-1, |p| p.piece_type.index() as i8),
+            rotation: piece.map_or(0, |p| p.rotation % 4),
+            x: piece.map_or(0, |p| p.x),
+            y: piece.map_or(0, |p| p.y),
         };
-        Self::SHAPES[idx].rotations[(self.rotation % 4) as usize]
-    }
-
-    fn get_bounds(&self, shape: &[[bool; 4]; 4]) -> (i32, i32, i32, i32) {
-        let (mut min_x, mut min_y, mut max_x, mut max_y) = (4, 4, 0, 0);
-        for i in 0..4 {
-            for j in 0..4 {
-                if shape[i][j] {
-                    min_x = min_x.min(j as i32);
-                    min_y = min_y.min(i as i32);
-                    max_x = max_x.max(j as i32);
-                    max_y = max_y.max(i as i32);
-                }
-            }
+        // SAFETY: `state` is valid for the call, which copies it.
+        unsafe { tetris_bpf_publish(&state) };
+    }
+
+    /// The attached bot's placement for the active piece as
+    /// `(rotation, x)`, if there is a bot and its answer fits.
+    pub(super) fn placement(view: &BotView) -> Option<(u8, i32)> {
+        let piece = view.piece;
+        let mut ctx = BotContext {
+            rows: view.masks,
+            cols: view.board.cols() as u8,
+            nr_rows: view.board.rows() as u8,
+            piece: piece.piece_type.index() as u8,
+            preview_count: view.preview_count as u8,
+            preview: view.preview.map(|t| t.index() as u8),
+        };
+        // SAFETY: `ctx` is valid for the call and not kept after it.
+        let ret = unsafe { tetris_bpf_place(&mut ctx) };
+        if ret < 0 {
+            return None;
         }
-        (min_x, min_y, max_x, max_y)
+
+        let mut candidate = Tetromino {
+            rotation: (ret >> 8) as u8 & 3,
+            ..piece
+        };
+        let (min_x, _, _, _) = candidate.get_bounds();
+        candidate.x = (ret & 0xff) - min_x;
+        if view.board.collides(&candidate) {
+            return None;
+        }
+        Some((candidate.rotation, candidate.x))
     }
 }
 
-/// Simple PRNG for kernel space
-struct PRNG {
-    state: u64,
+/// Register the BPF bot hook, if the kernel supports it.
+pub(crate) fn register_tetris_bpf() -> Result {
+    #[cfg(all(CONFIG_BPF_JIT, CONFIG_DEBUG_INFO_BTF_MODULES))]
+    bpf::init()?;
+    Ok(())
 }
 
-impl PRNG {
-    fn new(seed: u64) -> Self {
-        Self {
-            state: seed.wrapping_add(1),
-        }
-    }
-
-    fn next(&mut self) -> u64 {
-        self.state = self.state.wrapping_mul(6364136223846793005);
-        self.state = self.state.wrapping_add(1442695040888963407);
-        self.state
-    }
-
-    fn next_range(&mut self, max: u32) -> u32 {
-        (self.next() % max as u64) as u32
-    }
-}
-
-/// Game state
-struct TetrisGame {
-    board: [[bool; BOARD_WIDTH]; BOARD_HEIGHT],
-    current_piece: Option<Tetromino>,
-    score: u32,
-    game_over: bool,
-    next_piece_type: TetrominoType,
-    bag: [TetrominoType; 7],
-    bag_idx: usize,
-    prng: PRNG,
-}
-
-impl TetrisGame {
-    fn new() -> Self {
-        /*
-         * Seed with a fast-changing clock value and mix in an address so that
-         * successive opens aren't identical even if `ktime_get()` resolution is low.
-         */
-        let seed_time = <time::Monotonic as time::ClockSource>::ktime_get() as u64;
-        let addr_mix = (&seed_time as *const u64 as usize) as u64;
-        let prng = PRNG::new(seed_time ^ addr_mix ^ 0x2026);
-
-        let mut game = Self {
-            board: [[false; BOARD_WIDTH]; BOARD_HEIGHT],
-            current_piece: None,
-            score: 0,
-            game_over: false,
-            next_piece_type: TetrominoType::I,
-            bag: [
-                TetrominoType::I,
-                TetrominoType::O,
-                TetrominoType::T,
-                TetrominoType::S,
-                TetrominoType::Z,
-                TetrominoType::J,
-                TetrominoType::L,
-            ],
-            bag_idx: 7,
-            prng,
-        };
+/// What drives gravity; the `tick` module parameter, which lives in
+/// tetris_param.c. Either way, `TetrisDeviceInner::gravity_tick()` runs
+/// every `gravity_ns`.
+#[derive(Clone, Copy, PartialEq)]
+enum TickDriver {
+    /// `gravity_timer`, ticking in hardirq context.
+    HrTimer = 0,
+    /// `gravity_work` on the system workqueue, ticking in process context.
+    Workqueue = 1,
+}
 
-        game.next_piece_type = game.next_piece_from_bag();
-        game
+extern "C" {
+    fn tetris_game_mode() -> c_int;
+    fn tetris_tick_driver() -> c_int;
+    fn tetris_device_name() -> *const c_char;
+}
+
+/// The `device_name` parameter, which lives in tetris_param.c: what the
+/// game device nodes are called, `tetris` unless set.
+pub(crate) fn device_name() -> &'static core::ffi::CStr {
+    // SAFETY: The parameter can only be set at load, so the string stays as it is.
+    unsafe { core::ffi::CStr::from_ptr(tetris_device_name()) }
+}
+
+// The knobs in /proc/sys/kernel/tetris/, which live in tetris_sysctl.c.
+extern "C" {
+    fn tetris_sysctl_register() -> c_int;
+    fn tetris_sysctl_unregister();
+    fn tetris_sysctl_gravity_base_ms() -> c_int;
+    fn tetris_sysctl_lock_delay_ms() -> c_int;
+    fn tetris_sysctl_das_ms() -> c_int;
+    fn tetris_sysctl_arr_ms() -> c_int;
+    fn tetris_sysctl_render_flags() -> c_int;
+}
+
+/// Read a sysctl in ms, as ns. They are never negative.
+fn sysctl_ns(knob: unsafe extern "C" fn() -> c_int) -> u64 {
+    // SAFETY: Just reads the sysctl.
+    unsafe { knob() }.max(0) as u64 * 1_000_000
+}
+
+/// Keeps /proc/sys/kernel/tetris/ registered.
+pub(crate) struct TetrisSysctl(());
+
+impl Drop for TetrisSysctl {
+    fn drop(&mut self) {
+        // SAFETY: Registered by `register_tetris_sysctl()`, removed only here.
+        unsafe { tetris_sysctl_unregister() };
     }
+}
+
+pub(crate) fn register_tetris_sysctl() -> Result<TetrisSysctl> {
+    // SAFETY: Called once, from module init.
+    kernel::error::to_result(unsafe { tetris_sysctl_register() })?;
+    Ok(TetrisSysctl(()))
+}
 
-    fn reset(&mut self, stats: &TetrisStats) {
-        self.board = [[false; BOARD_WIDTH]; BOARD_HEIGHT];
-        self.current_piece = None;
-        self.score = 0;
-        self.game_over = false;
-        self.spawn_piece(stats);
+fn tick_driver() -> TickDriver {
+    // SAFETY: Just reads the parameter.
+    match unsafe { tetris_tick_driver() } {
+        1 => TickDriver::Workqueue,
+        _ => TickDriver::HrTimer,
     }
+}
 
-    fn spawn_piece(&mut self, stats: &TetrisStats) {
-        if self.game_over {
-            return;
-        }
+#[pin_data]
+pub(crate) struct TetrisDeviceInner {
+    /// The game's core: what moving, locking and clearing pieces change.
+    /// Taken with interrupts off, as the gravity tick takes it in hardirq
+    /// context, so nothing under it sleeps or allocates.
+    #[pin]
+    game: IrqSpinLock<TetrisGame>,
+    /// The slow path: how text frames are drawn, held while drawing one
+    /// from a `GameView`. Taken before the game lock when both are needed.
+    #[pin]
+    render: kernel::sync::Mutex<RenderOptions>,
+    #[pin]
+    stats: TetrisStats,
+    /// Periodic gravity tick with `tick=hrtimer`. Runs the tick itself, in
+    /// hardirq context, unless it is a slow one for `gravity_work`.
+    #[pin]
+    gravity_timer: HrTimer<TetrisDeviceInner>,
+    /// The same with `tick=workqueue`, re-queueing itself while
+    /// `gravity_queued` is set. With `tick=hrtimer`, runs the slow ticks
+    /// the timer leaves to it.
+    #[pin]
+    gravity_work: DelayedWork<TetrisDeviceInner, 3>,
+    gravity_queued: AtomicBool,
+    /// Current gravity period, published by the tick for the next one.
+    gravity_ns: AtomicU64,
+    /// Repeats a held direction (DAS/ARR). Re-queues itself while a key is
+    /// held; an early run just re-queues for the remaining time.
+    #[pin]
+    shift_work: DelayedWork<TetrisDeviceInner, 1>,
+    /// Feeds timed replay playback its inputs, re-queueing itself for the
+    /// next one until the replay is over.
+    #[pin]
+    replay_work: DelayedWork<TetrisDeviceInner, 2>,
+    /// Wakes the autopilot thread when the autopilot is switched on, when a
+    /// cascade waits to be settled, or when it is to exit.
+    #[pin]
+    autopilot_wake: CondVar,
+    /// Tells the autopilot thread to exit. Only changed under the game
+    /// lock, so the thread can't miss it between checking and waiting.
+    autopilot_stop: AtomicBool,
+    /// Wakes pollers when `frame_gen` moves on.
+    #[pin]
+    frame_wait: PollCondVar,
+    /// `frame_gen` as last published.
+    frame_gen: AtomicU64,
+    /// `event_seq` as last published, along with `frame_gen`: every event
+    /// changes the frame. Lets poll look at both without the game lock.
+    event_seq: AtomicU64,
+    /// The last text frame rendered for a read. Files reading the game copy
+    /// it while it is current instead of each rendering the frame under the
+    /// game lock, so only the first reader of a frame waits on the game.
+    #[pin]
+    text_frame: SpinLock<Option<Arc<TextFrame>>>,
+    /// What `mmap()` maps: an `MmapState` kept up to date.
+    state_page: Page,
+    /// `MmapState::seq`, only changed under the game lock.
+    state_seq: AtomicU32,
+    /// The scores and state, for sysfs, `/proc/tetris` and
+    /// `TETRIS_IOCTL_GET_STATE` to read without waiting on the game lock.
+    summary: SummarySeq,
+    /// Files open on the game device.
+    players: AtomicU32,
+}
 
-        let new_piece = Tetromino::new(self.next_piece_type);
+kernel::impl_has_hr_timer! {
+    impl HasHrTimer<Self> for TetrisDeviceInner {
+        mode: RelativeMode<time::Monotonic>,
+        field: self.gravity_timer
+    }
+}
 
-        if self.check_collision(&new_piece) {
-            self.game_over = true;
-            return;
-        }
+impl HrTimerCallback for TetrisDeviceInner {
+    type Pointer<'a> = Arc<Self>;
 
-        self.current_piece = Some(new_piece);
-        self.next_piece_type = self.next_piece_from_bag();
+    fn run(this: ArcBorrow<'_, Self>, mut ctx: HrTimerCallbackContext<'_, Self>) -> HrTimerRestart {
+        if !this.gravity_tick(true) {
+            Self::queue_gravity(this.into(), 0);
+        }
 
-        stats.pieces_spawned.fetch_add(1, Ordering::Relaxed);
+        let period = this.gravity_ns.load(Ordering::Relaxed);
+        ctx.forward_now(Delta::from_nanos(period as i64));
+        HrTimerRestart::Restart
     }
+}
 
-    fn next_piece_from_bag(&mut self) -> TetrominoType {
-        if self.bag_idx >= self.bag.len() {
-            self.shuffle_bag();
-            self.bag_idx = 0;
+impl TetrisDeviceInner {
+    /// Advance the game by a gravity step. Called from the gravity timer in
+    /// hardirq context or from `gravity_work`, so only takes the game lock.
+    /// In hardirq context a tick that `tick_is_slow()` is left undone and
+    /// false returned, for the caller to hand it to `gravity_work`.
+    fn gravity_tick(&self, in_hardirq: bool) -> bool {
+        let mut game = self.lock_game();
+        if game.paused() {
+            return true;
+        }
+        // Before the check, so a demo doesn't start on a tick in hardirq.
+        game.check_demo_idle(&self.stats);
+        if in_hardirq && game.tick_is_slow() {
+            return false;
+        }
+        game.frame_changed();
+        game.check_time_limit();
+        // A piece that just spawned gets a full period before it falls.
+        // Playback brings its own gravity.
+        if game.playback.is_none() && !game.check_spawn_delay(&self.stats) {
+            if game.demo {
+                game = self.bot_turn(game, |game, placement| {
+                    game.demo_step(&self.stats, placement)
+                });
+            } else if game.current_piece.is_some() {
+                self.stats.gravity_ticks.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::Gravity);
+                game.move_down(&self.stats);
+            }
         }
 
-        let piece = self.bag[self.bag_idx];
-        self.bag_idx += 1;
-        piece
+        // Pick up level changes and entry delays for the next timer expiry.
+        self.gravity_ns.store(game.next_tick_ns(), Ordering::Relaxed);
+        true
     }
+}
+
+kernel::impl_has_delayed_work! {
+    impl HasDelayedWork<Self, 3> for TetrisDeviceInner { self.gravity_work }
+}
 
-    fn shuffle_bag(&mut self) {
-        /* Fisher-Yates shuffle. */
-        let mut i = self.bag.len();
-        while i > 1 {
-            i -= 1;
-            let j = self.prng.next_range((i + 1) as u32) as usize;
-            let tmp = self.bag[i];
-            self.bag[i] = self.bag[j];
-            self.bag[j] = tmp;
+impl WorkItem<3> for TetrisDeviceInner {
+    type Pointer = Arc<Self>;
+
+    fn run(this: Arc<Self>) {
+        this.gravity_tick(false);
+        if this.gravity_queued.load(Ordering::Acquire) {
+            let delay_ns = this.gravity_ns.load(Ordering::Relaxed);
+            Self::queue_gravity(this, delay_ns);
         }
     }
 }
 
-impl TetrisGame {
-    fn is_out_of_bounds(board_x: i32, board_y: i32) -> bool {
-        board_x < 0
-            || board_x >= BOARD_WIDTH as i32
-            || board_y < 0
-            || board_y >= BOARD_HEIGHT as i32
-    }
+kernel::impl_has_delayed_work! {
+    impl HasDelayedWork<Self, 1> for TetrisDeviceInner { self.shift_work }
+}
 
-    fn check_collision(&self, piece: &Tetromino) -> bool {
-        let shape = piece.get_shape();
-        let (min_x, min_y, max_x, max_y) = piece.get_bounds(&shape);
+impl WorkItem<1> for TetrisDeviceInner {
+    type Pointer = Arc<Self>;
 
-        for i in min_y..=max_y {
-            for j in min_x..=max_x {
-                if shape[i as usize][j as usize] {
-                    let board_x = piece.x + j;
-                    let board_y = piece.y + i;
+    fn run(this: Arc<Self>) {
+        let mut game = this.lock_game();
+        let Some((moved, delay_ns)) = game.auto_shift() else {
+            return;
+        };
+        game.frame_changed();
+        drop(game);
 
-                    if Self::is_out_of_bounds(board_x, board_y) {
-                        return true;
-                    }
+        this.stats
+            .auto_shifts
+            .fetch_add(moved as u64, Ordering::Relaxed);
+        Self::queue_auto_shift(this, delay_ns);
+    }
+}
 
-                    if self.board[board_y as usize][board_x as usize] {
-                        return true;
-                    }
-                }
+kernel::impl_has_delayed_work! {
+    impl HasDelayedWork<Self, 2> for TetrisDeviceInner { self.replay_work }
+}
+
+impl WorkItem<2> for TetrisDeviceInner {
+    type Pointer = Arc<Self>;
+
+    fn run(this: Arc<Self>) {
+        let mut game = this.lock_game();
+        game.frame_changed();
+        // Inputs recorded in the same ms go in together, up to
+        // `KEYS_PER_LOCK` of them; the work comes straight back for more.
+        let mut held = 0;
+        let delay_ns = loop {
+            let step = game.replay_step(&this.stats);
+            game = this.settle_cascade(game);
+            match step {
+                Some(0) if held + 1 < KEYS_PER_LOCK => held += 1,
+                Some(delay_ns) => break delay_ns,
+                None => return,
             }
-        }
-        false
+        };
+        drop(game);
+
+        Self::queue_replay(this, delay_ns);
     }
+}
 
-    fn move_left(&mut self) -> bool {
-        if let Some(mut piece) = self.current_piece {
-            piece.x -= 1;
-            if !self.check_collision(&piece) {
-                self.current_piece = Some(piece);
-                return true;
-            }
+/// What can be read of a game without locking it, as of the last time
+/// the game lock was let go. Plain integers only: a reader may copy one
+/// torn by a write, and only finds out after.
+struct GameSummary {
+    state: TetrisState,
+    high_score: u64,
+}
+
+/// A `GameSummary` written under the game lock and read without it, as
+/// with the kernel's `seqcount_t`: `seq` is odd while a write is under way,
+/// and readers retry if it was odd or changed while they copied.
+struct SummarySeq {
+    seq: AtomicU32,
+    summary: UnsafeCell<GameSummary>,
+}
+
+// SAFETY: Writes are serialized by the game lock, and readers throw away any copy a write
+// overlapped; any bytes are a valid `GameSummary`.
+unsafe impl Sync for SummarySeq {}
+
+impl SummarySeq {
+    fn new(summary: GameSummary) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            summary: UnsafeCell::new(summary),
         }
-        false
     }
 
-    fn move_right(&mut self) -> bool {
-        if let Some(mut piece) = self.current_piece {
-            piece.x += 1;
-            if !self.check_collision(&piece) {
-                self.current_piece = Some(piece);
-                return true;
-            }
-        }
-        false
+    /// Only with the game lock held.
+    fn write(&self, summary: GameSummary) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        // SAFETY: The game lock keeps out other writers, and readers only copy it.
+        unsafe { core::ptr::write_volatile(self.summary.get(), summary) };
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
     }
 
-    fn move_down(&mut self, stats: &TetrisStats) -> bool {
-        if let Some(mut piece) = self.current_piece {
-            piece.y += 1;
-            if !self.check_collision(&piece) {
-                self.current_piece = Some(piece);
-                return true;
-            } else {
-                self.lock_piece(stats);
-                return false;
+    fn read(&self) -> GameSummary {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                // SAFETY: Any bytes are a valid `GameSummary`; a copy torn by a write is
+                // noticed below and thrown away.
+                let summary = unsafe { core::ptr::read_volatile(self.summary.get()) };
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return summary;
+                }
             }
+            core::hint::spin_loop();
         }
-        false
     }
+}
 
-    fn rotate(&mut self) -> bool {
-        if let Some(mut piece) = self.current_piece {
-            piece.rotation = (piece.rotation + 1) % 4;
-            if !self.check_collision(&piece) {
-                self.current_piece = Some(piece);
-                return true;
-            }
-        }
-        false
+// The game lock's irqsave wrappers, which live in tetris_lock.c.
+extern "C" {
+    fn tetris_lock_irqsave(lock: *mut bindings::spinlock_t) -> c_ulong;
+    fn tetris_trylock_irqsave(lock: *mut bindings::spinlock_t, flags: *mut c_ulong) -> bool;
+    fn tetris_unlock_irqrestore(lock: *mut bindings::spinlock_t, flags: c_ulong);
+}
+
+/// A spinlock taken with interrupts off, so the gravity timer can take it
+/// in hardirq context. Nothing under it may sleep, so nothing allocates.
+struct IrqSpinLockBackend;
+
+type IrqSpinLock<T> = Lock<T, IrqSpinLockBackend>;
+
+// SAFETY: The wrappers in tetris_lock.c are `spin_lock_irqsave()` and friends, which give
+// mutual exclusion; the flags saved by locking are the ones unlocking restores.
+unsafe impl Backend for IrqSpinLockBackend {
+    type State = bindings::spinlock_t;
+    type GuardState = c_ulong;
+
+    unsafe fn init(
+        ptr: *mut Self::State,
+        name: *const c_char,
+        key: *mut bindings::lock_class_key,
+    ) {
+        // SAFETY: The safety requirements ensure that `ptr` is valid for writes, and `name` and
+        // `key` are valid for read indefinitely.
+        unsafe { bindings::__spin_lock_init(ptr, name, key) }
     }
 
-    fn hard_drop(&mut self, stats: &TetrisStats) {
-        while self.move_down(stats) {}
+    unsafe fn lock(ptr: *mut Self::State) -> c_ulong {
+        // SAFETY: The safety requirements of this function ensure that `ptr` points to valid
+        // memory, and that it has been initialised before.
+        unsafe { tetris_lock_irqsave(ptr) }
     }
 
-    fn lock_piece(&mut self, stats: &TetrisStats) {
-        if let Some(piece) = self.current_piece.take() {
-            let shape = piece.get_shape();
-            let (min_x, min_y, max_x, max_y) = piece.get_bounds(&shape);
+    unsafe fn try_lock(ptr: *mut Self::State) -> Option<c_ulong> {
+        let mut flags = 0;
+        // SAFETY: As for `lock()`.
+        unsafe { tetris_trylock_irqsave(ptr, &mut flags) }.then_some(flags)
+    }
 
-            for i in min_y..=max_y {
-                for j in min_x..=max_x {
-                    if shape[i as usize][j as usize] {
-                        let board_x = piece.x + j;
-                        let board_y = piece.y + i;
+    unsafe fn unlock(ptr: *mut Self::State, flags: &c_ulong) {
+        // SAFETY: The safety requirements of this function ensure that the caller is the owner
+        // of the lock, and `flags` is what locking it saved.
+        unsafe { tetris_unlock_irqrestore(ptr, *flags) }
+    }
 
-                        if !Self::is_out_of_bounds(board_x, board_y) {
-                            self.board[board_y as usize][board_x as usize] = true;
-                        }
-                    }
-                }
-            }
+    unsafe fn assert_is_held(ptr: *mut Self::State) {
+        // SAFETY: The `ptr` pointer is guaranteed to be valid and initialized before use.
+        unsafe { bindings::spin_assert_is_held(ptr) }
+    }
+}
 
-            stats.pieces_locked.fetch_add(1, Ordering::Relaxed);
+/// The game lock, taken by something that may change the game. Dropping it
+/// publishes the new state.
+struct GameGuard<'a> {
+    guard: Guard<'a, TetrisGame, IrqSpinLockBackend>,
+    inner: &'a TetrisDeviceInner,
+    /// `frame_gen` as last published.
+    frame_gen: u64,
+    /// How long taking the lock took, and when it was taken.
+    wait_ns: u64,
+    locked_ns: u64,
+    /// Trace event to fire with those times on letting go.
+    trace: LockTrace,
+}
 
-            let (lines, score_delta) = self.clear_lines();
-            if lines > 0 {
-                stats.lines_cleared.fetch_add(lines as u64, Ordering::Relaxed);
-            }
-            if score_delta > 0 {
-                stats
-                    .score_gained
-                    .fetch_add(score_delta as u64, Ordering::Relaxed);
-            }
+/// What a `GameGuard` was taken for, as far as tetris_trace.h cares.
+#[derive(Clone, Copy)]
+enum LockTrace {
+    None,
+    /// This ioctl command.
+    Ioctl(u32),
+    /// Rendering a frame this long.
+    Render(u32),
+}
 
-            self.spawn_piece(stats);
-        }
-    }
+extern "C" {
+    fn tetris_trace_ioctl(cmd: u32, wait_ns: u64, held_ns: u64);
+    fn tetris_trace_render(len: u32, wait_ns: u64, held_ns: u64);
+}
+
+impl core::ops::Deref for GameGuard<'_> {
+    type Target = TetrisGame;
 
-    fn clear_lines(&mut self) -> (u32, u32) {
-        let mut lines_cleared = 0;
-        let mut write_idx = BOARD_HEIGHT;
+    fn deref(&self) -> &TetrisGame {
+        &self.guard
+    }
+}
 
-        for y in (0..BOARD_HEIGHT).rev() {
-            let line_full = (0..BOARD_WIDTH).all(|x| self.board[y][x]);
+impl core::ops::DerefMut for GameGuard<'_> {
+    fn deref_mut(&mut self) -> &mut TetrisGame {
+        &mut self.guard
+    }
+}
 
-            if line_full {
-                lines_cleared += 1;
-            } else {
-                write_idx -= 1;
-                if write_idx != y {
-                    self.board[write_idx] = self.board[y];
-                }
-            }
+impl GameGuard<'_> {
+    /// Let readers outside the lock see the current state: wake pollers if
+    /// the frame changed, and update the state page and BPF programs' copy.
+    /// A cascade waiting to settle wakes the autopilot thread for it.
+    fn publish(&mut self) {
+        if self.guard.settle_in_ns().is_some() {
+            self.inner.autopilot_wake.notify_all();
         }
-
-        while write_idx > 0 {
-            write_idx -= 1;
-            self.board[write_idx] = [false; BOARD_WIDTH];
+        if self.guard.frame_gen != self.frame_gen {
+            self.frame_gen = self.guard.frame_gen;
+            self.inner
+                .event_seq
+                .store(self.guard.event_seq, Ordering::Release);
+            self.inner.frame_gen.store(self.frame_gen, Ordering::Release);
+            self.inner.frame_wait.notify_all();
         }
+        self.guard.note_high_score();
+        self.inner.summary.write(self.guard.summary());
+        self.inner.update_state_page(&self.guard);
+        #[cfg(all(CONFIG_BPF_JIT, CONFIG_DEBUG_INFO_BTF_MODULES))]
+        bpf::publish(&self.guard);
+    }
 
-        let mut score_delta = 0;
-        if lines_cleared > 0 {
-            score_delta = match lines_cleared {
-                1 => 100,
-                2 => 300,
-                3 => 500,
-                _ => 800,
-            };
-            self.score += score_delta;
+    /// Let go of the lock, returning how long it was waited for and then
+    /// held, for a trace event fired after work done outside it.
+    fn unlock(self) -> (u64, u64) {
+        let times = (self.wait_ns, ktime_now_ns().saturating_sub(self.locked_ns));
+        drop(self);
+        times
+    }
+
+    /// Sleep until the frame changes, dropping the lock meanwhile. Returns
+    /// whether a signal cut the wait short.
+    fn wait_frame(&mut self) -> bool {
+        let signal = self.inner.frame_wait.wait_interruptible(&mut self.guard);
+        // Whoever changed the frame has already woken everyone.
+        self.frame_gen = self.guard.frame_gen;
+        // Sleeping isn't holding the lock.
+        self.wait_ns = 0;
+        self.locked_ns = ktime_now_ns();
+        signal
+    }
+}
+
+impl Drop for GameGuard<'_> {
+    fn drop(&mut self) {
+        self.publish();
+        let held_ns = ktime_now_ns().saturating_sub(self.locked_ns);
+        match self.trace {
+            LockTrace::None => {}
+            // SAFETY: Just fires the trace event.
+            LockTrace::Ioctl(cmd) => unsafe { tetris_trace_ioctl(cmd, self.wait_ns, held_ns) },
+            // SAFETY: Just fires the trace event.
+            LockTrace::Render(len) => unsafe { tetris_trace_render(len, self.wait_ns, held_ns) },
         }
+    }
+}
 
-        (lines_cleared, score_delta)
+impl TetrisDeviceInner {
+    /// Rewrite the state page from `game`, under the game lock.
+    fn update_state_page(&self, game: &TetrisGame) {
+        let piece = game.current_piece;
+        let state = MmapState {
+            seq: 0,
+            version: MMAP_VERSION,
+            cols: game.cols() as u8,
+            rows: game.rows() as u8,
+            score: game.score,
+            lines: game.lines,
+            level: game.level,
+            piece: piece.map_or(-1, |p| p.piece_type.index() as i8),
+            rotation: piece.map_or(0, |p| p.rotation % 4),
+            game_over: game.game_over as u8,
+            paused: game.paused() as u8,
+            x: piece.map_or(0, |p| p.x),
+            y: piece.map_or(0, |p| p.y),
+            board: game.row_masks(),
+        };
+        let seq = self.state_seq.load(Ordering::Relaxed);
+        let body = core::mem::size_of::<u32>();
+
+        // SAFETY: Each source is valid for the length given, and everything fits in the page.
+        // Writing can't fail with those bounds.
+        unsafe {
+            let _ = self.state_page.write_raw((&(seq + 1) as *const u32).cast(), 0, body);
+            fence(Ordering::Release);
+            let _ = self.state_page.write_raw(
+                (&state as *const MmapState).cast::<u8>().add(body),
+                body,
+                core::mem::size_of::<MmapState>() - body,
+            );
+            fence(Ordering::Release);
+            let _ = self.state_page.write_raw((&(seq + 2) as *const u32).cast(), 0, body);
+        }
+        self.state_seq.store(seq.wrapping_add(2), Ordering::Relaxed);
     }
 
-    fn render_to_buffer(&self, buffer: &mut [u8]) -> usize {
-        let mut pos = 0;
+    /// The shared text frame, if it is one a file that last read frame
+    /// `seen` hasn't read and it is still current.
+    fn shared_frame(&self, seen: u64) -> Option<Arc<TextFrame>> {
+        let frame = self.text_frame.lock().clone()?;
+        let gen = self.frame_gen.load(Ordering::Acquire);
+        (frame.gen != seen && frame.is_current(gen)).then_some(frame)
+    }
 
-        for i in 0..buffer.len() {
-            buffer[i] = b' ';
+    /// Render `view`'s text frame into `buffer` and share it, or copy the
+    /// shared one if another file has rendered it meanwhile. `buffer` must
+    /// hold `RENDER_BUFFER_SIZE` bytes. Renders one at a time, under
+    /// `render` rather than the game lock.
+    fn render_shared(&self, view: &GameView, buffer: &mut [u8]) -> Result<usize> {
+        let options = self.render.lock();
+        let shared = self.text_frame.lock().clone();
+        if let Some(shared) = shared.filter(|frame| frame.is_current(view.frame_gen)) {
+            let len = shared.bytes.len();
+            buffer[..len].copy_from_slice(&shared.bytes);
+            self.stats.frames_cached.fetch_add(1, Ordering::Relaxed);
+            return Ok(len);
         }
 
-        let mut display_board = self.board;
+        let len = view.render_to_buffer(&options, buffer);
+        self.stats.frames_rendered.fetch_add(1, Ordering::Relaxed);
+
+        let mut bytes = KVVec::with_capacity(len, GFP_KERNEL)?;
+        bytes.extend_from_slice(&buffer[..len], GFP_KERNEL)?;
+        let frame = Arc::new(
+            TextFrame {
+                gen: view.frame_gen,
+                valid_until_ns: view.valid_until_ns,
+                bytes,
+            },
+            GFP_KERNEL,
+        )?;
+        // The old frame may be freed here; not under the spinlock.
+        let old = self.text_frame.lock().replace(frame);
+        drop(old);
+        Ok(len)
+    }
 
-        if let Some(piece) = self.current_piece {
-            let shape = piece.get_shape();
-            let (min_x, min_y, max_x, max_y) = piece.get_bounds(&shape);
+    /// Lock the game to change it; see `GameGuard`.
+    fn lock_game(&self) -> GameGuard<'_> {
+        let start_ns = ktime_now_ns();
+        let guard = self.game.lock();
+        let locked_ns = ktime_now_ns();
+        GameGuard {
+            frame_gen: guard.frame_gen,
+            guard,
+            inner: self,
+            wait_ns: locked_ns - start_ns,
+            locked_ns,
+            trace: LockTrace::None,
+        }
+    }
 
-            for i in min_y..=max_y {
-                for j in min_x..=max_x {
-                    if shape[i as usize][j as usize] {
-                        let board_x = piece.x + j;
-                        let board_y = piece.y + i;
+    /// Take the bot's next step on `game` with `step`, searching for its
+    /// placement on a `BotView` after letting go of the lock: too long to
+    /// keep interrupts off for, with a BPF bot above all. If the game moved
+    /// on meanwhile, the step is skipped, to be tried again on the next one.
+    /// Process context only. Returns the lock, taken again.
+    fn bot_turn<'a>(
+        &'a self,
+        mut game: GameGuard<'a>,
+        step: impl FnOnce(&mut TetrisGame, Option<(u8, i32)>),
+    ) -> GameGuard<'a> {
+        let Some(view) = game.bot_view() else {
+            step(&mut game, None);
+            return game;
+        };
+        drop(game);
+        let placement = view.placement();
+        let mut game = self.lock_game();
+        if game.frame_gen == view.frame_gen {
+            step(&mut game, placement);
+        }
+        game
+    }
 
-                        if !Self::is_out_of_bounds(board_x, board_y) {
-                            display_board[board_y as usize][board_x as usize] = true;
-                        }
-                    }
+    /// Settle the cascade `game` has due, if any (see
+    /// `TetrisGame::settle_in_ns()`). Like the bot's search, that is done
+    /// on a copy after letting go of the lock, and done again if the game
+    /// moved on meanwhile. Process context only. Returns the lock, taken
+    /// again.
+    fn settle_cascade<'a>(&'a self, mut game: GameGuard<'a>) -> GameGuard<'a> {
+        while game.settle_in_ns() == Some(0) {
+            let (mut board, frame_gen) = (game.board, game.frame_gen);
+            drop(game);
+            let cleared = board.cascade();
+            game = self.lock_game();
+            if game.frame_gen == frame_gen {
+                // Playback already recorded it, as the input that got here.
+                if game.playback.is_none() {
+                    game.record(ReplayInput::Settle);
                 }
+                game.finish_cascade(&self.stats, board, cleared);
             }
         }
+        game
+    }
 
-        let top_border = b"\xE2\x95\x94";
-        let horizontal = b"\xE2\x95\x90";
-        let top_right = b"\xE2\x95\x97\n";
+    /// Run the auto shift work in `delay_ns`. If it is already pending it
+    /// fires early and re-queues itself, so the error is ignored.
+    fn queue_auto_shift(this: Arc<Self>, delay_ns: u64) {
+        let ms = u32::try_from(delay_ns.div_ceil(1_000_000)).unwrap_or(u32::MAX);
+        let _ = workqueue::system().enqueue_delayed::<_, 1>(this, time::msecs_to_jiffies(ms));
+    }
 
-        pos += Self::write_bytes(buffer, pos, top_border);
-        for _ in 0..BOARD_WIDTH {
-            pos += Self::write_bytes(buffer, pos, horizontal);
-            pos += Self::write_bytes(buffer, pos, horizontal);
-        }
-        pos += Self::write_bytes(buffer, pos, top_right);
+    /// Run the gravity work in `delay_ns`. With `tick=workqueue` only
+    /// `start_tetris_gravity()` and the work itself queue it, so it is never
+    /// already pending; with `tick=hrtimer` the timer queues it for slow
+    /// ticks, and one already pending does the tick, so the error is ignored.
+    fn queue_gravity(this: Arc<Self>, delay_ns: u64) {
+        let ms = u32::try_from(delay_ns.div_ceil(1_000_000)).unwrap_or(u32::MAX);
+        let _ = workqueue::system().enqueue_delayed::<_, 3>(this, time::msecs_to_jiffies(ms));
+    }
 
-        let left_border = b"\xE2\x95\x91";
-        let right_border = b"\xE2\x95\x91\n";
-        let filled = b"\xE2\x96\x88\xE2\x96\x88";
-        let empty = b"  ";
+    /// Run the replay work in `delay_ns`. If it is already pending it picks
+    /// up the current playback when it runs, so the error is ignored.
+    fn queue_replay(this: Arc<Self>, delay_ns: u64) {
+        let ms = u32::try_from(delay_ns.div_ceil(1_000_000)).unwrap_or(u32::MAX);
+        let _ = workqueue::system().enqueue_delayed::<_, 2>(this, time::msecs_to_jiffies(ms));
+    }
+}
 
-        for row in &display_board {
-            pos += Self::write_bytes(buffer, pos, left_border);
-            for &cell in row {
-                let bytes: &[u8] = if cell { filled } else { empty };
-                pos += Self::write_bytes(buffer, pos, bytes);
-            }
-            pos += Self::write_bytes(buffer, pos, right_border);
-        }
+pub(crate) fn create_tetris_inner() -> Result<Arc<TetrisDeviceInner>> {
+    let game = TetrisGame::new()?;
+    let stats = TetrisStats::new()?;
+    let summary = SummarySeq::new(game.summary());
+    let inner = Arc::pin_init(
+        pin_init!(TetrisDeviceInner {
+            game <- IrqSpinLock::new(
+                game,
+                c"TetrisDeviceInner::game",
+                kernel::static_lock_class!()
+            ),
+            render <- kernel::new_mutex!(RenderOptions::from_sysctl()),
+            stats,
+            gravity_timer <- HrTimer::new(),
+            gravity_work <- kernel::new_delayed_work!("TetrisDeviceInner::gravity_work"),
+            gravity_queued: AtomicBool::new(false),
+            gravity_ns: AtomicU64::new(GravityCurve::Modern.period_ns(1)),
+            shift_work <- kernel::new_delayed_work!("TetrisDeviceInner::shift_work"),
+            replay_work <- kernel::new_delayed_work!("TetrisDeviceInner::replay_work"),
+            autopilot_wake <- kernel::new_condvar!("TetrisDeviceInner::autopilot_wake"),
+            autopilot_stop: AtomicBool::new(false),
+            frame_wait <- kernel::new_poll_condvar!("TetrisDeviceInner::frame_wait"),
+            frame_gen: AtomicU64::new(1),
+            event_seq: AtomicU64::new(0),
+            text_frame <- kernel::new_spinlock!(None),
+            state_page: Page::alloc_page(GFP_KERNEL | __GFP_ZERO)?,
+            state_seq: AtomicU32::new(0),
+            summary,
+            players: AtomicU32::new(0),
+        }),
+        GFP_KERNEL,
+    )?;
 
-        let bottom_left = b"\xE2\x95\x9A";
-        let bottom_right = b"\xE2\x95\x9D\n";
+    let mut game = inner.lock_game();
+    inner.stats.games_played.fetch_add(1, Ordering::Relaxed);
+    game.spawn_piece(&inner.stats);
+    // The module parameters decide the first period.
+    inner
+        .gravity_ns
+        .store(game.gravity_period_ns(), Ordering::Relaxed);
+    drop(game);
+    Ok(inner)
+}
+
+/// Keeps the gravity tick running; dropping it stops the tick.
+pub(crate) struct TetrisGravity {
+    inner: Arc<TetrisDeviceInner>,
+    /// `None` when ticking from the workqueue.
+    timer: Option<ArcHrTimerHandle<TetrisDeviceInner>>,
+}
 
-        pos += Self::write_bytes(buffer, pos, bottom_left);
-        for _ in 0..BOARD_WIDTH {
-            pos += Self::write_bytes(buffer, pos, horizontal);
-            pos += Self::write_bytes(buffer, pos, horizontal);
+impl Drop for TetrisGravity {
+    fn drop(&mut self) {
+        // Both wait for a tick under way, so none can run after the module is gone.
+        if let Some(timer) = &mut self.timer {
+            timer.cancel();
+        } else {
+            self.inner.gravity_queued.store(false, Ordering::Release);
         }
-        pos += Self::write_bytes(buffer, pos, bottom_right);
+        // The timer may have left a slow tick to the work, and a tick under
+        // way may have seen `gravity_queued` still set and re-queued once
+        // more; flush until a flush finds nothing to wait for.
+        // SAFETY: `gravity_work` was initialized in `create_tetris_inner()` and `self.inner`
+        // keeps it alive; `work` is the first member of `struct delayed_work`.
+        while unsafe {
+            bindings::flush_delayed_work(
+                Work::raw_get(DelayedWork::raw_as_work(&self.inner.gravity_work)).cast(),
+            )
+        } {}
+
+        // With nothing held the auto shift work doesn't re-queue, so
+        // flushing runs any pending one now and leaves none behind.
+        self.inner.game.lock().shift_held = None;
+        // SAFETY: `shift_work` was initialized in `create_tetris_inner()` and `self.inner`
+        // keeps it alive; `work` is the first member of `struct delayed_work`.
+        unsafe {
+            bindings::flush_delayed_work(
+                Work::raw_get(DelayedWork::raw_as_work(&self.inner.shift_work)).cast(),
+            )
+        };
 
-        pos += Self::write_bytes(buffer, pos, b"Score: ");
-        pos += Self::write_number(buffer, pos, self.score);
-        pos += Self::write_bytes(buffer, pos, b"\n");
+        // Likewise the replay work stops once there is nothing to play.
+        self.inner.game.lock().stop_playback();
+        // SAFETY: as above, for `replay_work`.
+        unsafe {
+            bindings::flush_delayed_work(
+                Work::raw_get(DelayedWork::raw_as_work(&self.inner.replay_work)).cast(),
+            )
+        };
+    }
+}
 
-        if self.game_over {
-            pos += Self::write_bytes(buffer, pos, b"GAME OVER!\n");
-        }
+/// Keeps the autopilot thread running; dropping it stops the thread. The
+/// thread also settles cascades, which is too slow for the game lock.
+pub(crate) struct TetrisAutopilot {
+    /// The thread only has a raw pointer; this keeps it valid.
+    inner: Arc<TetrisDeviceInner>,
+    /// The thread, with a reference of ours: it may have exited by the
+    /// time `kthread_stop()` gets to it.
+    task: *mut bindings::task_struct,
+}
+
+// SAFETY: `task` is only used to stop the thread, which any task may do.
+unsafe impl Send for TetrisAutopilot {}
+
+// SAFETY: `TetrisAutopilot` has no `&self` methods.
+unsafe impl Sync for TetrisAutopilot {}
 
-        pos
+impl Drop for TetrisAutopilot {
+    fn drop(&mut self) {
+        let game = self.inner.game.lock();
+        self.inner.autopilot_stop.store(true, Ordering::Relaxed);
+        self.inner.autopilot_wake.notify_all();
+        drop(game);
+        // SAFETY: `task` is the thread started in `start_tetris_autopilot()`, which holds a
+        // reference to it for us, so it is still there even if the thread has exited.
+        // `kthread_stop()` waits for it to do so.
+        unsafe {
+            bindings::kthread_stop(self.task);
+            bindings::put_task_struct(self.task);
+        }
     }
+}
 
-    fn write_bytes(buffer: &mut [u8], pos: usize, bytes: &[u8]) -> usize {
-        let mut written = 0;
-        for &byte in bytes {
-            if pos + written < buffer.len() {
-                buffer[pos + written] = byte;
-                written += 1;
+/// Body of the autopilot thread. `data` is the `TetrisDeviceInner` to play.
+unsafe extern "C" fn autopilot_thread(data: *mut c_void) -> c_int {
+    // SAFETY: `TetrisAutopilot` keeps `data` alive until this thread has exited.
+    let inner = unsafe { &*data.cast::<TetrisDeviceInner>() };
+    let mut game = inner.lock_game();
+
+    // Checked under the game lock, which the wait only lets go of once it
+    // is waiting, so the wake-up that comes with the flag can't be missed.
+    while !inner.autopilot_stop.load(Ordering::Relaxed) {
+        // Kthreads don't take signals, so there is no early return to handle.
+        // The thread is also the game's slow path: a cascade holds up the
+        // game until it settles, so that comes first.
+        if let Some(wait_ns) = game.settle_in_ns() {
+            if wait_ns == 0 {
+                game = inner.settle_cascade(game);
             } else {
-                break;
+                let ms = u32::try_from(wait_ns.div_ceil(1_000_000)).unwrap_or(u32::MAX);
+                let _ = inner
+                    .autopilot_wake
+                    .wait_interruptible_timeout(&mut game.guard, time::msecs_to_jiffies(ms));
+            }
+            continue;
+        }
+        match game.autopilot_ms {
+            // Off: sleep until it is switched on.
+            0 => {
+                let _ = inner.autopilot_wake.wait_interruptible(&mut game.guard);
+            }
+            ms => {
+                game.check_spawn_delay(&inner.stats);
+                game = inner.bot_turn(game, |game, placement| {
+                    game.autopilot_step(&inner.stats, placement)
+                });
+                game.frame_changed();
+                game.publish();
+                // The search let go of the lock; look again before sleeping.
+                if !inner.autopilot_stop.load(Ordering::Relaxed) {
+                    let _ = inner
+                        .autopilot_wake
+                        .wait_interruptible_timeout(&mut game.guard, time::msecs_to_jiffies(ms));
+                }
             }
         }
-        written
     }
+    0
+}
 
-    fn write_number(buffer: &mut [u8], pos: usize, mut num: u32) -> usize {
-        let mut digits = [0u8; 10];
-        let mut digit_count = 0;
+pub(crate) fn start_tetris_autopilot(inner: Arc<TetrisDeviceInner>) -> Result<TetrisAutopilot> {
+    let data = &*inner as *const TetrisDeviceInner as *mut c_void;
+    // SAFETY: `autopilot_thread` has the signature kthreads expect, and the returned
+    // `TetrisAutopilot` keeps `data` alive until the thread has stopped.
+    let task = unsafe {
+        bindings::kthread_create_on_node(
+            Some(autopilot_thread),
+            data,
+            bindings::NUMA_NO_NODE,
+            c"tetris-autopilot".as_ptr(),
+        )
+    };
+    let task = kernel::error::from_err_ptr(task)?;
+    // SAFETY: `task` was just created and hasn't been started or stopped. The reference is
+    // `TetrisAutopilot`'s, put back once the thread has stopped.
+    unsafe {
+        bindings::get_task_struct(task);
+        bindings::wake_up_process(task);
+    }
+    Ok(TetrisAutopilot { inner, task })
+}
 
-        if num == 0 {
-            digits[0] = b'0';
-            digit_count = 1;
-        } else {
-            while num > 0 && digit_count < 10 {
-                digits[digit_count] = (num % 10) as u8 + b'0';
-                num /= 10;
-                digit_count += 1;
-            }
+pub(crate) fn start_tetris_gravity(inner: Arc<TetrisDeviceInner>) -> TetrisGravity {
+    let first_ns = inner.gravity_ns.load(Ordering::Relaxed);
+    let timer = match tick_driver() {
+        TickDriver::HrTimer => Some(inner.clone().start(Delta::from_nanos(first_ns as i64))),
+        TickDriver::Workqueue => {
+            inner.gravity_queued.store(true, Ordering::Release);
+            TetrisDeviceInner::queue_gravity(inner.clone(), first_ns);
+            None
         }
+    };
+    TetrisGravity { inner, timer }
+}
 
-        let mut written = 0;
-        for i in (0..digit_count).rev() {
-            if pos + written < buffer.len() {
-                buffer[pos + written] = digits[i];
-                written += 1;
+/// A device node name. `MiscDeviceOptions` wants it `'static`, so it is
+/// kept next to the registration instead, which must go first.
+struct DeviceName(KVec<u8>);
+
+impl DeviceName {
+    /// `parts` joined, e.g. `tetris0` `-events`.
+    fn new(parts: &[&[u8]]) -> Result<Self> {
+        let mut name = KVec::new();
+        for part in parts {
+            if part.is_empty() || part.contains(&0) || part.contains(&b'/') {
+                return Err(EINVAL);
             }
+            name.extend_from_slice(part, GFP_KERNEL)?;
         }
-        written
+        name.push(0, GFP_KERNEL)?;
+        Ok(Self(name))
+    }
+
+    fn as_cstr(&self) -> &core::ffi::CStr {
+        // SAFETY: `new()` ends the bytes with their only nul.
+        unsafe { core::ffi::CStr::from_bytes_with_nul_unchecked(&self.0) }
+    }
+
+    /// # Safety
+    ///
+    /// Whatever holds on to the result must be gone before `self` is.
+    unsafe fn as_static(&self) -> &'static core::ffi::CStr {
+        // SAFETY: The bytes are on the heap, so they stay put when `self` moves, and the
+        // caller keeps the result no longer than `self`.
+        unsafe { &*(self.as_cstr() as *const core::ffi::CStr) }
     }
 }
 
-/// Device state
-pub(crate) struct TetrisDevice {
+/// One game with its devices, debugfs files, gravity and autopilot. Dropping
+/// it takes them all down.
+pub(crate) struct TetrisInstance {
+    #[cfg_attr(not(any(CONFIG_CONFIGFS_FS, CONFIG_PROC_FS)), allow(dead_code))]
     inner: Arc<TetrisDeviceInner>,
+    _dev: MiscRegistration<TetrisDevice, TetrisDeviceInner>,
+    _events: MiscRegistration<TetrisEvents, TetrisDeviceInner>,
+    _debugfs: Option<TetrisDebugFs>,
+    _gravity: TetrisGravity,
+    _autopilot: TetrisAutopilot,
+    // After the registrations, which point to them.
+    #[cfg_attr(not(any(CONFIG_CONFIGFS_FS, CONFIG_PROC_FS)), allow(dead_code))]
+    name: DeviceName,
+    _events_name: DeviceName,
 }
 
+impl TetrisInstance {
+    /// Start a game on `/dev/<name>` and `/dev/<name>-events`, with its
+    /// debugfs files in `dir` if there is one.
+    fn new(name: DeviceName, dir: Option<kernel::debugfs::Dir>) -> Result<Self> {
+        let events_name = DeviceName::new(&[name.as_cstr().to_bytes(), b"-events"])?;
+        let inner = create_tetris_inner()?;
+        // SAFETY: The names are dropped after the registrations.
+        let (dev_name, events) = unsafe { (name.as_static(), events_name.as_static()) };
+        Ok(Self {
+            _dev: register_tetris_device(inner.clone(), dev_name)?,
+            _events: register_tetris_events(inner.clone(), events)?,
+            _debugfs: dir
+                .map(|dir| register_tetris_debugfs(inner.clone(), dir))
+                .transpose()?,
+            _gravity: start_tetris_gravity(inner.clone()),
+            _autopilot: start_tetris_autopilot(inner.clone())?,
+            inner,
+            name,
+            _events_name: events_name,
+        })
+    }
+}
+
+/// Every game the module runs: `/dev/tetris` with its files right in
+/// debugfs `tetris/`, and numbered and named ones, `/dev/tetris0` or
+/// `/dev/tetris-<name>`, each in a debugfs subdirectory of the same name.
+/// With the `debugfs` parameter 0, none of it is in debugfs.
 #[pin_data]
-pub(crate) struct TetrisDeviceInner {
+pub(crate) struct TetrisGames {
+    debugfs: Option<kernel::debugfs::Dir>,
+    _totals_file: Option<Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugTotals>>>>,
+    _pieces_file: Option<Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugPieces>>>>,
+    /// `/dev/tetris`, unless the `instances` parameter is set.
+    #[cfg_attr(not(CONFIG_PROC_FS), allow(dead_code))]
+    main: Option<TetrisInstance>,
+    /// Numbered games, by number.
     #[pin]
-    game: kernel::sync::Mutex<TetrisGame>,
+    numbered: kernel::sync::Mutex<[Option<TetrisInstance>; INSTANCES_MAX]>,
+    /// Games made in configfs.
     #[pin]
-    stats: TetrisStats,
+    #[cfg_attr(not(any(CONFIG_CONFIGFS_FS, CONFIG_PROC_FS)), allow(dead_code))]
+    named: kernel::sync::Mutex<KVec<TetrisInstance>>,
 }
 
-impl TetrisDevice {
-    fn new(inner: Arc<TetrisDeviceInner>) -> Result<Arc<Self>> {
-        Ok(Arc::new(Self { inner }, GFP_KERNEL)?)
+impl TetrisGames {
+    fn start(&self, number: usize) -> Result<TetrisInstance> {
+        let name = DeviceName::new(&[device_name().to_bytes(), &[b'0' + number as u8]])?;
+        let dir = self.debugfs.as_ref().map(|dir| dir.subdir(name.as_cstr()));
+        TetrisInstance::new(name, dir)
     }
-}
 
-#[vtable]
-impl MiscDevice for TetrisDevice {
-    type Ptr = Arc<TetrisDevice>;
+    /// Start `/dev/tetris-<name>`. Returns its game to tune.
+    #[cfg(CONFIG_CONFIGFS_FS)]
+    fn create_named(&self, name: &[u8]) -> Result<Arc<TetrisDeviceInner>> {
+        let name = DeviceName::new(&[device_name().to_bytes(), b"-", name])?;
+        let mut named = self.named.lock();
+        if named
+            .iter()
+            .any(|instance| instance.name.as_cstr() == name.as_cstr())
+        {
+            return Err(EEXIST);
+        }
+        let dir = self.debugfs.as_ref().map(|dir| dir.subdir(name.as_cstr()));
+        let instance = TetrisInstance::new(name, dir)?;
+        let inner = instance.inner.clone();
+        named.push(instance, GFP_KERNEL)?;
+        Ok(inner)
+    }
 
-    fn open(_file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
-        // `Device::set_drvdata` / `drvdata_borrow` live on `Device<CoreInternal>`.
-        let dev = misc.device();
-        // SAFETY: the miscdevice's `this_device` is a valid `struct device *` for the lifetime of
-        // the registration.
-        let dev_ci: &device::Device<device::CoreInternal> = unsafe { &*(dev as *const _ as *const _) };
+    /// Stop the named game playing `inner`.
+    #[cfg(CONFIG_CONFIGFS_FS)]
+    fn destroy_named(&self, inner: &Arc<TetrisDeviceInner>) {
+        self.named
+            .lock()
+            .retain(|instance| !Arc::ptr_eq(&instance.inner, inner));
+    }
 
-        // SAFETY: we stored an `Arc<TetrisDeviceInner>` in drvdata in `register_tetris_device()` and
-        // we haven't called drvdata_obtain.
-        let inner = unsafe { dev_ci.drvdata_borrow::<Arc<TetrisDeviceInner>>() };
+    /// Start the lowest numbered game not running. Returns its number.
+    fn create(&self) -> Result<usize> {
+        let mut numbered = self.numbered.lock();
+        let number = numbered.iter().position(Option::is_none).ok_or(ENOSPC)?;
+        numbered[number] = Some(self.start(number)?);
+        Ok(number)
+    }
 
-        // `inner` is `Pin<&Arc<_>>`; we just need a cloned `Arc<_>`.
-        let inner = (*inner).clone();
+    /// The running numbered games, bit N set for `/dev/tetrisN`.
+    fn running(&self) -> u32 {
+        let numbered = self.numbered.lock();
+        (0..INSTANCES_MAX)
+            .filter(|&number| numbered[number].is_some())
+            .fold(0, |mask, number| mask | 1 << number)
+    }
 
-        inner.stats.opens.fetch_add(1, Ordering::Relaxed);
+    /// Stop numbered game `number`. Under the lock, so its names are free
+    /// again before a `create()` can want them.
+    fn destroy(&self, number: usize) -> Result {
+        let mut numbered = self.numbered.lock();
+        let instance = numbered.get_mut(number).ok_or(EINVAL)?;
+        instance.take().ok_or(ENOENT)?;
+        Ok(())
+    }
 
-        TetrisDevice::new(inner)
+    /// Call `f` with the device name and game of each running game: the
+    /// main one, then numbered, then named ones.
+    #[cfg(CONFIG_PROC_FS)]
+    fn for_each(&self, mut f: impl FnMut(&core::ffi::CStr, &TetrisDeviceInner)) {
+        let numbered = self.numbered.lock();
+        let named = self.named.lock();
+        for instance in self
+            .main
+            .iter()
+            .chain(numbered.iter().flatten())
+            .chain(named.iter())
+        {
+            f(instance.name.as_cstr(), &instance.inner);
+        }
     }
+}
 
-    fn read_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterDest<'_>) -> Result<usize> {
-        let device = kiocb.file();
-        device.inner.stats.reads.fetch_add(1, Ordering::Relaxed);
-        let game = device.inner.game.lock();
+/// Start the games the parameters ask for: `/dev/tetris`, or with
+/// `instances` set that many numbered ones, named after `device_name`.
+pub(crate) fn create_tetris_games() -> Result<Arc<TetrisGames>> {
+    let count = *crate::module_parameters::instances.value() as usize;
+    if count > INSTANCES_MAX {
+        return Err(EINVAL);
+    }
 
-        let mut buffer = kernel::alloc::KVec::new();
-        buffer.resize(RENDER_BUFFER_SIZE, 0, GFP_KERNEL)?;
+    // With debugfs not built in or locked down, `Dir` quietly makes
+    // nothing; the parameter is for when it is there but unwanted.
+    let debugfs = (*crate::module_parameters::debugfs.value() != 0)
+        .then(|| kernel::debugfs::Dir::new(c"tetris"));
+    let totals_file = debugfs
+        .as_ref()
+        .map(|dir| {
+            kernel::alloc::KBox::pin_init(
+                dir.read_only_file(c"global_stats", TetrisDebugTotals),
+                GFP_KERNEL,
+            )
+        })
+        .transpose()?;
+    let pieces_file = debugfs
+        .as_ref()
+        .map(|dir| {
+            kernel::alloc::KBox::pin_init(
+                dir.read_only_file(c"pieces", TetrisDebugPieces),
+                GFP_KERNEL,
+            )
+        })
+        .transpose()?;
+    let main = if count == 0 {
+        let name = DeviceName::new(&[device_name().to_bytes()])?;
+        Some(TetrisInstance::new(name, debugfs.clone())?)
+    } else {
+        None
+    };
+    let games = Arc::pin_init(
+        pin_init!(TetrisGames {
+            debugfs,
+            _totals_file: totals_file,
+            _pieces_file: pieces_file,
+            main,
+            numbered <- kernel::new_mutex!(core::array::from_fn(|_| None)),
+            named <- kernel::new_mutex!(KVec::new()),
+        }),
+        GFP_KERNEL,
+    )?;
 
-        let len = game.render_to_buffer(&mut buffer);
+    let mut numbered = games.numbered.lock();
+    for (number, instance) in numbered.iter_mut().enumerate().take(count) {
+        *instance = Some(games.start(number)?);
+    }
+    drop(numbered);
+    Ok(games)
+}
 
-        let bytes_to_copy = core::cmp::min(len, iov.len());
-        let copied = iov.copy_to_iter(&buffer[..bytes_to_copy]);
+/// configfs `tetris/`: `mkdir <name>` starts a game on `/dev/tetris-<name>`,
+/// with its board size, gravity curve and randomizer as attributes, and
+/// `rmdir` stops it.
+#[cfg(CONFIG_CONFIGFS_FS)]
+mod config {
+    use super::rules::Randomizer;
+    use super::*;
+    use kernel::{
+        configfs::{AttributeOperations, Group, GroupOperations},
+        page::PAGE_SIZE,
+    };
+
+    pub(crate) struct TetrisConfigFs {
+        pub(super) games: Arc<TetrisGames>,
+    }
 
-        drop(game);
+    #[vtable]
+    impl GroupOperations for TetrisConfigFs {
+        type Child = ConfigGame;
+
+        fn make_group(
+            &self,
+            name: &core::ffi::CStr,
+        ) -> Result<impl PinInit<Group<ConfigGame>, Error>> {
+            let item_type = kernel::configfs_attrs! {
+                container: Group<ConfigGame>,
+                data: ConfigGame,
+                attributes: [
+                    width: 0,
+                    height: 1,
+                    gravity: 2,
+                    randomizer: 3,
+                ],
+            };
 
-        device
-            .inner
-            .stats
-            .bytes_read
-            .fetch_add(copied as u64, Ordering::Relaxed);
+            let group_name = name.try_into()?;
+            let inner = self.games.create_named(name.to_bytes())?;
+            Ok(Group::new(
+                group_name,
+                item_type,
+                ConfigGame {
+                    games: self.games.clone(),
+                    inner,
+                },
+            ))
+        }
+    }
 
-        Ok(copied)
+    /// A game made by `mkdir`
+    pub(crate) struct ConfigGame {
+        games: Arc<TetrisGames>,
+        inner: Arc<TetrisDeviceInner>,
     }
 
-    fn write_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterSource<'_>) -> Result<usize> {
-        let device = kiocb.file();
-        device.inner.stats.writes.fetch_add(1, Ordering::Relaxed);
+    impl Drop for ConfigGame {
+        fn drop(&mut self) {
+            self.games.destroy_named(&self.inner);
+        }
+    }
 
-        let mut buffer = [0u8; 1];
-        let len = iov.copy_from_iter(&mut buffer);
+    impl ConfigGame {
+        /// Resize the board and start a new game, as `TETRIS_IOCTL_SET_SIZE`
+        /// does.
+        fn set_size(&self, width: Option<usize>, height: Option<usize>) -> Result {
+            let mut game = self.inner.lock_game();
+            let width = width.unwrap_or(game.width);
+            let height = height.unwrap_or(game.height);
+            game.set_size(width, height)?;
+            self.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+            game.reset(&self.inner.stats);
+            game.frame_changed();
+            Ok(())
+        }
+    }
 
-        device
-            .inner
-            .stats
-            .bytes_written
-            .fetch_add(len as u64, Ordering::Relaxed);
+    fn show_value(page: &mut [u8; PAGE_SIZE], value: u64) -> Result<usize> {
+        let len = TetrisGame::write_number(page, 0, value);
+        page[len] = b'\n';
+        Ok(len + 1)
+    }
 
-        if len > 0 {
-            let mut game = device.inner.game.lock();
-            match buffer[0] {
-                b'a' | b'A' => {
-                    device.inner.stats.left.fetch_add(1, Ordering::Relaxed);
-                    if game.move_left() {
-                        device.inner.stats.left_ok.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-                b'd' | b'D' => {
-                    device.inner.stats.right.fetch_add(1, Ordering::Relaxed);
-                    if game.move_right() {
-                        device.inner.stats.right_ok.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-                b's' | b'S' => {
-                    device.inner.stats.down.fetch_add(1, Ordering::Relaxed);
-                    if game.move_down(&device.inner.stats) {
-                        device.inner.stats.down_ok.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-                b'w' | b'W' => {
-                    device.inner.stats.rotate.fetch_add(1, Ordering::Relaxed);
-                    if game.rotate() {
-                        device.inner.stats.rotate_ok.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-                b' ' => {
-                    device.inner.stats.drop.fetch_add(1, Ordering::Relaxed);
-                    game.hard_drop(&device.inner.stats);
-                }
-                b'r' | b'R' => {
-                    device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
-                    game.reset(&device.inner.stats);
-                }
-                _ => {
-                    device
-                        .inner
-                        .stats
-                        .invalid_inputs
-                        .fetch_add(1, Ordering::Relaxed);
-                }
-            }
+    fn parse_value(page: &[u8]) -> Result<u32> {
+        core::str::from_utf8(page)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .ok_or(EINVAL)
+    }
+
+    #[vtable]
+    impl AttributeOperations<0> for ConfigGame {
+        type Data = ConfigGame;
+
+        fn show(config: &ConfigGame, page: &mut [u8; PAGE_SIZE]) -> Result<usize> {
+            show_value(page, config.inner.game.lock().width as u64)
         }
 
-        Ok(len)
+        fn store(config: &ConfigGame, page: &[u8]) -> Result {
+            config.set_size(Some(parse_value(page)? as usize), None)
+        }
     }
 
-    fn ioctl(
-        device: <Self::Ptr as ForeignOwnable>::Borrowed<'_>,
-        _file: &File,
-        cmd: u32,
-        _arg: usize,
-    ) -> Result<isize> {
-        device.inner.stats.ioctls.fetch_add(1, Ordering::Relaxed);
-        let mut game = device.inner.game.lock();
-
-        match cmd {
-            TETRIS_IOCTL_LEFT => {
-                device.inner.stats.left.fetch_add(1, Ordering::Relaxed);
-                if game.move_left() {
-                    device.inner.stats.left_ok.fetch_add(1, Ordering::Relaxed);
-                }
-            }
-            TETRIS_IOCTL_RIGHT => {
-                device.inner.stats.right.fetch_add(1, Ordering::Relaxed);
-                if game.move_right() {
-                    device.inner.stats.right_ok.fetch_add(1, Ordering::Relaxed);
-                }
-            }
-            TETRIS_IOCTL_DOWN => {
-                device.inner.stats.down.fetch_add(1, Ordering::Relaxed);
-                if game.move_down(&device.inner.stats) {
-                    device.inner.stats.down_ok.fetch_add(1, Ordering::Relaxed);
-                }
-            }
-            TETRIS_IOCTL_ROTATE => {
-                device.inner.stats.rotate.fetch_add(1, Ordering::Relaxed);
-                if game.rotate() {
-                    device.inner.stats.rotate_ok.fetch_add(1, Ordering::Relaxed);
-                }
-            }
-            TETRIS_IOCTL_DROP => {
-                device.inner.stats.drop.fetch_add(1, Ordering::Relaxed);
-                game.hard_drop(&device.inner.stats);
-            }
-            TETRIS_IOCTL_RESET => {
-                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
-                game.reset(&device.inner.stats);
-            }
-            _ => {
-                device
-                    .inner
-                    .stats
-                    .invalid_ioctls
-                    .fetch_add(1, Ordering::Relaxed);
-                return Err(EINVAL);
-            }
+    #[vtable]
+    impl AttributeOperations<1> for ConfigGame {
+        type Data = ConfigGame;
+
+        fn show(config: &ConfigGame, page: &mut [u8; PAGE_SIZE]) -> Result<usize> {
+            show_value(page, config.inner.game.lock().height as u64)
         }
 
-        Ok(0)
+        fn store(config: &ConfigGame, page: &[u8]) -> Result {
+            config.set_size(None, Some(parse_value(page)? as usize))
+        }
     }
-}
 
-#[allow(dead_code)]
-struct TetrisDebugState {
-    inner: Arc<TetrisDeviceInner>,
-}
+    /// The gravity curve, as for `TETRIS_IOCTL_SET_GRAVITY`.
+    #[vtable]
+    impl AttributeOperations<2> for ConfigGame {
+        type Data = ConfigGame;
 
-struct TetrisDebugStats {
-    inner: Arc<TetrisDeviceInner>,
-}
+        fn show(config: &ConfigGame, page: &mut [u8; PAGE_SIZE]) -> Result<usize> {
+            show_value(page, config.inner.game.lock().gravity_curve as u64)
+        }
 
-#[allow(dead_code)]
-struct TetrisDebugStatsReset {
-    inner: Arc<TetrisDeviceInner>,
-}
+        fn store(config: &ConfigGame, page: &[u8]) -> Result {
+            let curve = GravityCurve::from_raw(parse_value(page)?).ok_or(EINVAL)?;
+            let mut game = config.inner.lock_game();
+            game.gravity_curve = curve;
+            config
+                .inner
+                .gravity_ns
+                .store(game.gravity_period_ns(), Ordering::Relaxed);
+            Ok(())
+        }
+    }
 
-impl core::fmt::Debug for TetrisDebugState {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        let game = self.inner.game.lock();
+    /// The randomizer, as for `TETRIS_IOCTL_SET_RANDOMIZER`.
+    #[vtable]
+    impl AttributeOperations<3> for ConfigGame {
+        type Data = ConfigGame;
 
-        writeln!(f, "score: {}", game.score)?;
-        writeln!(f, "game_over: {}", game.game_over)?;
-        writeln!(f, "next_piece: {:?}", game.next_piece_type)?;
-
-        match game.current_piece {
-            Some(p) => {
-                writeln!(
-                    f,
-                    "current_piece: type={:?} x={} y={} rotation={}",
-                    p.piece_type, p.x, p.y, p.rotation
-                )?;
-            }
-            None => {
-                writeln!(f, "current_piece: (none)")?;
-            }
+        fn show(config: &ConfigGame, page: &mut [u8; PAGE_SIZE]) -> Result<usize> {
+            show_value(page, config.inner.game.lock().bag.randomizer as u64)
         }
 
-        writeln!(f, "board:")?;
-        for y in 0..BOARD_HEIGHT {
-            for x in 0..BOARD_WIDTH {
-                let c = if game.board[y][x] { '#' } else { '.' };
-                write!(f, "{}", c)?;
-            }
-            writeln!(f)?;
+        fn store(config: &ConfigGame, page: &[u8]) -> Result {
+            let randomizer = Randomizer::from_raw(parse_value(page)?).ok_or(EINVAL)?;
+            let mut game = config.inner.lock_game();
+            game.bag.set_randomizer(randomizer);
+            Ok(())
         }
-
-        Ok(())
     }
 }
 
-impl core::fmt::Debug for TetrisDebugStats {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        let s = &self.inner.stats;
-
-        // key=value per line for easy scripting.
-        writeln!(f, "uptime_ns={}", s.uptime_ns())?;
-
-        writeln!(f, "opens={}", s.opens.load(Ordering::Relaxed))?;
-        writeln!(f, "reads={}", s.reads.load(Ordering::Relaxed))?;
-        writeln!(f, "bytes_read={}", s.bytes_read.load(Ordering::Relaxed))?;
-        writeln!(f, "writes={}", s.writes.load(Ordering::Relaxed))?;
-        writeln!(f, "bytes_written={}", s.bytes_written.load(Ordering::Relaxed))?;
-        writeln!(f, "ioctls={}", s.ioctls.load(Ordering::Relaxed))?;
-        writeln!(f, "invalid_ioctls={}", s.invalid_ioctls.load(Ordering::Relaxed))?;
-        writeln!(f, "invalid_inputs={}", s.invalid_inputs.load(Ordering::Relaxed))?;
-
-        writeln!(f, "resets={}", s.resets.load(Ordering::Relaxed))?;
-        writeln!(f, "pieces_spawned={}", s.pieces_spawned.load(Ordering::Relaxed))?;
-        writeln!(f, "pieces_locked={}", s.pieces_locked.load(Ordering::Relaxed))?;
-        writeln!(f, "lines_cleared={}", s.lines_cleared.load(Ordering::Relaxed))?;
-        writeln!(f, "score_gained={}", s.score_gained.load(Ordering::Relaxed))?;
-
-        writeln!(f, "left={}", s.left.load(Ordering::Relaxed))?;
-        writeln!(f, "left_ok={}", s.left_ok.load(Ordering::Relaxed))?;
-        writeln!(f, "right={}", s.right.load(Ordering::Relaxed))?;
-        writeln!(f, "right_ok={}", s.right_ok.load(Ordering::Relaxed))?;
-        writeln!(f, "down={}", s.down.load(Ordering::Relaxed))?;
-        writeln!(f, "down_ok={}", s.down_ok.load(Ordering::Relaxed))?;
-        writeln!(f, "rotate={}", s.rotate.load(Ordering::Relaxed))?;
-        writeln!(f, "rotate_ok={}", s.rotate_ok.load(Ordering::Relaxed))?;
-        writeln!(f, "drop={}", s.drop.load(Ordering::Relaxed))?;
-
-        // Include a couple of live game fields for correlation.
-        let game = self.inner.game.lock();
-        writeln!(f, "current_score={}", game.score)?;
-        writeln!(f, "game_over={}", game.game_over)?;
-
-        Ok(())
-    }
+/// The configfs subsystem, while registered; nothing without configfs.
+pub(crate) struct TetrisConfigSubsystem {
+    #[cfg(CONFIG_CONFIGFS_FS)]
+    _subsystem: Pin<kernel::alloc::KBox<kernel::configfs::Subsystem<config::TetrisConfigFs>>>,
 }
 
-impl core::fmt::Debug for TetrisDebugStatsReset {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        writeln!(f, "write any value to reset counters")
+pub(crate) fn register_tetris_configfs(games: Arc<TetrisGames>) -> Result<TetrisConfigSubsystem> {
+    #[cfg(CONFIG_CONFIGFS_FS)]
+    {
+        let item_type = kernel::configfs_attrs! {
+            container: kernel::configfs::Subsystem<config::TetrisConfigFs>,
+            data: config::TetrisConfigFs,
+            child: config::ConfigGame,
+            attributes: [],
+        };
+        let subsystem = kernel::configfs::Subsystem::new(
+            c"tetris",
+            item_type,
+            config::TetrisConfigFs { games },
+        );
+        Ok(TetrisConfigSubsystem {
+            _subsystem: kernel::alloc::KBox::pin_init(subsystem, GFP_KERNEL)?,
+        })
+    }
+    #[cfg(not(CONFIG_CONFIGFS_FS))]
+    {
+        let _ = games;
+        Ok(TetrisConfigSubsystem {})
     }
 }
 
-// NOTE: debugfs provides a blanket `Writer` impl for any `Debug` type.
-// We intentionally avoid a custom `Writer` impl here to prevent conflicting implementations.
-
-pub(crate) struct TetrisDebugFs {
-    _dir: debugfs::Dir,
-    _state_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugState>>>,
-    _stats_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugStats>>>,
-    _stats_reset_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugStatsReset>>>,
+/// `/proc/tetris`, a line per running game for admins to see who plays
+/// what. Removing it waits for readers, so they can use `games` until then.
+pub(crate) struct TetrisProc {
+    #[cfg(CONFIG_PROC_FS)]
+    entry: *mut bindings::proc_dir_entry,
+    _games: Arc<TetrisGames>,
 }
 
-pub(crate) fn register_tetris_debugfs(inner: Arc<TetrisDeviceInner>) -> Result<TetrisDebugFs> {
-    let dir = debugfs::Dir::new(c"tetris");
-
-    let _state_file = kernel::alloc::KBox::pin_init(
-        dir.read_only_file(c"state", TetrisDebugState { inner: inner.clone() }),
-        GFP_KERNEL,
-    )?;
+// SAFETY: `entry` is only used to remove the file, which any thread may do.
+unsafe impl Send for TetrisProc {}
 
-    let _stats_file = kernel::alloc::KBox::pin_init(
-        dir.read_only_file(c"stats", TetrisDebugStats { inner: inner.clone() }),
-        GFP_KERNEL,
-    )?;
-
-    let _stats_reset_file = kernel::alloc::KBox::pin_init(
-        dir.read_only_file(c"stats_reset", TetrisDebugStatsReset { inner: inner.clone() }),
-        GFP_KERNEL,
-    )?;
-
-    Ok(TetrisDebugFs {
-        _dir: dir,
-        _state_file,
-        _stats_file,
-        _stats_reset_file,
-    })
-}
+// SAFETY: `&TetrisProc` allows nothing.
+unsafe impl Sync for TetrisProc {}
 
-pub(crate) fn unregister_tetris_debugfs() {
-    // Everything is RAII via module fields now.
+impl Drop for TetrisProc {
+    fn drop(&mut self) {
+        #[cfg(CONFIG_PROC_FS)]
+        // SAFETY: `entry` came from `proc_create_single_data()` and is removed only here.
+        unsafe {
+            bindings::proc_remove(self.entry)
+        };
+    }
 }
 
-pub(crate) fn create_tetris_inner() -> Result<Arc<TetrisDeviceInner>> {
-    let inner = Arc::pin_init(
-        pin_init!(TetrisDeviceInner {
-            game <- kernel::new_mutex!(TetrisGame::new()),
-            stats: TetrisStats::new(),
-        }),
-        GFP_KERNEL,
-    )?;
-
-    inner.game.lock().spawn_piece(&inner.stats);
-    Ok(inner)
+/// Show `/proc/tetris`.
+#[cfg(CONFIG_PROC_FS)]
+unsafe extern "C" fn tetris_proc_show(m: *mut bindings::seq_file, _v: *mut c_void) -> c_int {
+    // SAFETY: procfs passes the `seq_file` of this read, whose `private` is the
+    // `TetrisGames` of `register_tetris_proc()`, alive until the file is removed.
+    let (seq, games) = unsafe {
+        (
+            kernel::seq_file::SeqFile::from_raw(m),
+            &*(*m).private.cast::<TetrisGames>(),
+        )
+    };
+    kernel::seq_print!(
+        seq,
+        "{:<24} {:>7} {:>10} {:>5} {:<9} {:>9}\n",
+        "device",
+        "players",
+        "score",
+        "level",
+        "state",
+        "uptime"
+    );
+    games.for_each(|name, inner| {
+        let secs = inner.stats.uptime_ns() / 1_000_000_000;
+        let state = inner.summary.read().state;
+        kernel::seq_print!(
+            seq,
+            "{:<24} {:>7} {:>10} {:>5} {:<9} {:>3}:{:02}:{:02}\n",
+            name.to_str().unwrap_or("?"),
+            inner.players.load(Ordering::Relaxed),
+            state.score,
+            state.level,
+            state.state_name(),
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+    });
+    0
 }
 
-pub(crate) fn register_tetris_device(
-    inner: Arc<TetrisDeviceInner>,
-) -> Result<Pin<kernel::alloc::KBox<MiscDeviceRegistration<TetrisDevice>>>> {
-    let reg = kernel::alloc::KBox::pin_init(
-        MiscDeviceRegistration::register(MiscDeviceOptions { name: c"tetris" }),
-        GFP_KERNEL,
-    )?;
-
-    let dev = reg.device();
-    // SAFETY: `dev` points to a live `struct device` for the lifetime of the registration.
-    let dev_ci: &device::Device<device::CoreInternal> = unsafe { &*(dev as *const _ as *const _) };
-
-    // Store the shared inner as drvdata for this miscdevice's `struct device`.
-    // We store an `Arc<TetrisDeviceInner>` as the drvdata object.
-    dev_ci.set_drvdata(unsafe {
-        pin_init::init_from_closure(move |slot| {
-            // SAFETY: `slot` is a valid pointer to uninitialized storage for `Arc<TetrisDeviceInner>`.
-            core::ptr::write(slot, inner);
-            Ok(())
+pub(crate) fn register_tetris_proc(games: Arc<TetrisGames>) -> Result<TetrisProc> {
+    #[cfg(CONFIG_PROC_FS)]
+    {
+        // SAFETY: `games` outlives the file, see `TetrisProc`.
+        let entry = unsafe {
+            bindings::proc_create_single_data(
+                c"tetris".as_ptr(),
+                0o444,
+                core::ptr::null_mut(),
+                Some(tetris_proc_show),
+                Arc::as_ptr(&games).cast_mut().cast(),
+            )
+        };
+        if entry.is_null() {
+            return Err(ENOMEM);
+        }
+        Ok(TetrisProc {
+            entry,
+            _games: games,
         })
-    })?;
-
-    Ok(reg)
+    }
+    #[cfg(not(CONFIG_PROC_FS))]
+    {
+        Ok(TetrisProc { _games: games })
+    }
 }
-
-// NOTE: `TetrisDevice` instances are created in `MiscDevice::open`.
-// (Removed unused `create_tetris_device` helper.)
@@ -0,0 +1,2048 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! The character devices: `/dev/tetris`, its events device and the control
+//! device, their ioctl ABI and sysfs attributes.
+
+use kernel::{
+    bindings,
+    cred::Kuid,
+    device,
+    ffi::{c_char, c_int},
+    fs::{file::flags, File, Kiocb},
+    iov::{IovIterDest, IovIterSource},
+    miscdevice::{MiscDevice, MiscDeviceOptions, MiscDeviceRegistration},
+    mm::virt::VmaNew,
+    page::PAGE_SIZE,
+    prelude::*,
+    sync::{poll::PollTable, Arc, ArcBorrow},
+    ioctl::{_IO, _IOR, _IOW},
+    transmute::{AsBytes, FromBytes},
+    types::ForeignOwnable,
+    uaccess::{UserPtr, UserSlice},
+};
+
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use super::{
+    create_tetris_inner, start_tetris_autopilot, start_tetris_gravity, tetris_game_mode,
+    tetris_trace_render, GameSummary, LockTrace, TetrisAutopilot, TetrisDeviceInner, TetrisGames,
+    TetrisGravity,
+};
+use super::engine::{
+    BotView, GameMode, GravityCurve, KeyAction, KeyMap, ReplayInput, Shift, TetrisGame, ARE_MAX_MS,
+    AUTOSHIFT_MAX_MS, BOARD_BLOB_MAX, CLEAR_DELAY_MAX_MS, EVENT_RECORD_LEN, EVENT_RING_LEN,
+    GARBAGE_MAX, PREVIEW_MAX, REPLAY_BLOB_MAX, START_LEVEL_MAX, STATE_MAX_LEN,
+};
+use super::render::{GameView, RenderOptions, BINARY_FRAME_LEN, RENDER_ALL, RENDER_BUFFER_SIZE};
+use super::rules::{Randomizer, Rotation, BOARD_MAX_ROWS};
+
+/// Most bytes a single write takes; the caller writes the rest again.
+pub(super) const WRITE_BATCH_MAX: usize = 256;
+
+/// Largest repeat count a written key can take (`4a` = left four times).
+const REPEAT_MAX: u32 = 100;
+
+/// Most written keys played per hold of the game lock. A write can ask for
+/// `WRITE_BATCH_MAX` x `REPEAT_MAX` of them, and the lock keeps interrupts
+/// off, so `write_keys()` lets it go after this many and takes it again.
+/// Replay playback does the same with replayed inputs.
+pub(super) const KEYS_PER_LOCK: u32 = 16;
+
+/// Slowest autopilot `TETRIS_IOCTL_SET_AUTOPILOT` accepts, in ms per move.
+const AUTOPILOT_MAX_MS: usize = 10000;
+
+/// Fastest timed playback `TETRIS_IOCTL_SET_REPLAY_SPEED` accepts, in %.
+const REPLAY_SPEED_MAX: usize = 10000;
+
+/// Layout version of the `mmap()` state page.
+pub(super) const MMAP_VERSION: u16 = 1;
+
+/// `_IOC_TYPE` of the tetris ioctls. Commands taking a number take it as the
+/// argument itself and are plain `_IO`; only those taking a pointer encode
+/// a direction and the size of what it points to.
+const TETRIS_IOC_MAGIC: u32 = 0xf7;
+
+/// Ioctl command codes
+const TETRIS_IOCTL_LEFT: u32 = _IO(TETRIS_IOC_MAGIC, 0x00);
+
+const TETRIS_IOCTL_RIGHT: u32 = _IO(TETRIS_IOC_MAGIC, 0x01);
+
+const TETRIS_IOCTL_DOWN: u32 = _IO(TETRIS_IOC_MAGIC, 0x02);
+
+const TETRIS_IOCTL_ROTATE: u32 = _IO(TETRIS_IOC_MAGIC, 0x03);
+
+const TETRIS_IOCTL_DROP: u32 = _IO(TETRIS_IOC_MAGIC, 0x04);
+
+const TETRIS_IOCTL_RESET: u32 = _IO(TETRIS_IOC_MAGIC, 0x05);
+
+/// Set the number of preview pieces (`arg` = 1..=PREVIEW_MAX).
+const TETRIS_IOCTL_SET_PREVIEW: u32 = _IO(TETRIS_IOC_MAGIC, 0x06);
+
+const TETRIS_IOCTL_ROTATE_CCW: u32 = _IO(TETRIS_IOC_MAGIC, 0x07);
+
+/// Scored soft drop: `arg` = 0 drops one cell, non-zero drops until contact.
+/// `TETRIS_IOCTL_DOWN` stays an unscored gravity step.
+const TETRIS_IOCTL_SOFT_DROP: u32 = _IO(TETRIS_IOC_MAGIC, 0x08);
+
+/// Select the gravity curve (`arg`: 0 = modern, 1 = NES).
+const TETRIS_IOCTL_SET_GRAVITY: u32 = _IO(TETRIS_IOC_MAGIC, 0x09);
+
+/// Toggle 20G instant gravity (`arg`: 0 = off, non-zero = on).
+const TETRIS_IOCTL_SET_20G: u32 = _IO(TETRIS_IOC_MAGIC, 0x0a);
+
+/// Switch rule set and start a new game (`arg`: 0 = marathon, 1 = sprint,
+/// 2 = ultra).
+const TETRIS_IOCTL_SET_MODE: u32 = _IO(TETRIS_IOC_MAGIC, 0x0b);
+
+/// Final time of the last completed sprint in milliseconds; `-ENODATA` if the
+/// current game is not a finished sprint.
+const TETRIS_IOCTL_GET_SPRINT_TIME: u32 = _IO(TETRIS_IOC_MAGIC, 0x0c);
+
+/// Toggle invisible mode (`arg`: 0 = off, non-zero = on).
+const TETRIS_IOCTL_SET_INVISIBLE: u32 = _IO(TETRIS_IOC_MAGIC, 0x0d);
+
+/// Toggle big mode and start a new game (`arg`: 0 = off, non-zero = on).
+const TETRIS_IOCTL_SET_BIG: u32 = _IO(TETRIS_IOC_MAGIC, 0x0e);
+
+/// Select the line clear algorithm (`arg`: 0 = naive, non-zero = cascade).
+const TETRIS_IOCTL_SET_CASCADE: u32 = _IO(TETRIS_IOC_MAGIC, 0x0f);
+
+/// Queue `arg` lines of incoming garbage for versus play.
+const TETRIS_IOCTL_ADD_GARBAGE: u32 = _IO(TETRIS_IOC_MAGIC, 0x10);
+
+/// Return the garbage lines this game has sent since the last call.
+const TETRIS_IOCTL_TAKE_ATTACK: u32 = _IO(TETRIS_IOC_MAGIC, 0x11);
+
+/// Select the randomizer (`arg`: 0 = 7-bag, 1 = 14-bag), from the next bag.
+const TETRIS_IOCTL_SET_RANDOMIZER: u32 = _IO(TETRIS_IOC_MAGIC, 0x12);
+
+/// Set the entry delay between lock and spawn in ms (0 disables it).
+const TETRIS_IOCTL_SET_ARE: u32 = _IO(TETRIS_IOC_MAGIC, 0x13);
+
+/// Key-down for a direction (`arg`: 0 = left, 1 = right); moves once, then
+/// auto-repeats after DAS until released.
+const TETRIS_IOCTL_SHIFT_PRESS: u32 = _IO(TETRIS_IOC_MAGIC, 0x14);
+
+/// Key-up for a direction (`arg`: 0 = left, 1 = right).
+const TETRIS_IOCTL_SHIFT_RELEASE: u32 = _IO(TETRIS_IOC_MAGIC, 0x15);
+
+/// Set the delayed auto shift in ms.
+const TETRIS_IOCTL_SET_DAS: u32 = _IO(TETRIS_IOC_MAGIC, 0x16);
+
+/// Set the auto repeat rate in ms (0 = slide straight to the wall).
+const TETRIS_IOCTL_SET_ARR: u32 = _IO(TETRIS_IOC_MAGIC, 0x17);
+
+/// Pause the game: gravity stops and movement is rejected.
+const TETRIS_IOCTL_PAUSE: u32 = _IO(TETRIS_IOC_MAGIC, 0x18);
+
+/// Resume a paused game.
+const TETRIS_IOCTL_RESUME: u32 = _IO(TETRIS_IOC_MAGIC, 0x19);
+
+/// Copy the score to the `u64` that `arg` points to.
+const TETRIS_IOCTL_GET_SCORE: u32 = _IOR::<u64>(TETRIS_IOC_MAGIC, 0x1a);
+
+/// Set the starting level (1..=`START_LEVEL_MAX`) and start a new game.
+const TETRIS_IOCTL_SET_START_LEVEL: u32 = _IO(TETRIS_IOC_MAGIC, 0x1b);
+
+/// Resize the board and start a new game: `arg` = width | height << 16.
+const TETRIS_IOCTL_SET_SIZE: u32 = _IO(TETRIS_IOC_MAGIC, 0x1c);
+
+/// Color pieces with ANSI escape codes (`arg`: 0 = off, non-zero = on).
+const TETRIS_IOCTL_SET_COLOR: u32 = _IO(TETRIS_IOC_MAGIC, 0x1d);
+
+/// Start every frame with a VT100 clear and cursor home (`arg`: 0 = off,
+/// non-zero = on), so `cat` in a loop redraws in place.
+const TETRIS_IOCTL_SET_CLEAR_SCREEN: u32 = _IO(TETRIS_IOC_MAGIC, 0x1e);
+
+/// Draw the frame with plain ASCII (`arg`: 0 = UTF-8 box drawing, non-zero
+/// = ASCII) for consoles without UTF-8.
+const TETRIS_IOCTL_SET_ASCII: u32 = _IO(TETRIS_IOC_MAGIC, 0x1f);
+
+/// Keep cleared lines flashing for this many ms before they collapse
+/// (0 = collapse at once). Movement results report the lines only once
+/// they are gone.
+const TETRIS_IOCTL_SET_CLEAR_DELAY: u32 = _IO(TETRIS_IOC_MAGIC, 0x20);
+
+/// Copy the current game's replay to userspace. The argument points to a
+/// `struct { __u64 data; __u64 size; }` describing the user buffer; at
+/// most `size` bytes are copied and the full blob length is returned, so a
+/// short buffer can be retried.
+///
+/// The blob is little endian: a `REPLAY_HEADER_LEN` byte header
+///
+/// ```text
+///  0  magic "TRPL"     4  u16 version     6  u16 flags (1 = truncated)
+///  8  u64 seed        16  u64 score      24  u32 event count
+/// 28  u8 mode, u8 randomizer, u8 width, u8 height
+/// 32  u8 start level, u8 options (1 = big, 2 = cascade, 4 = 20G), u16 ARE ms
+/// 36  u16 clear delay ms, u16 reserved
+/// ```
+///
+/// followed by one u32 per input: the `ReplayInput` in bits 0-3 and the
+/// game time since the previous input, in ms, in bits 4-31. Options are
+/// the ones in force when the blob was taken; garbage is not recorded.
+/// Debugfs `replay` holds the same blob.
+const TETRIS_IOCTL_GET_REPLAY: u32 = _IOW::<UserBuffer>(TETRIS_IOC_MAGIC, 0x21);
+
+/// Play back a replay blob from `TETRIS_IOCTL_GET_REPLAY` on a fresh game
+/// with the recorded seed and options; the argument describes the user
+/// buffer the same way. At speed 0 the whole replay runs before the ioctl
+/// returns: 0 if it ended on the recorded score, 1 if not. Otherwise it
+/// plays in the background and the result shows in the frame. Any other
+/// input stops it.
+const TETRIS_IOCTL_PLAY_REPLAY: u32 = _IOW::<UserBuffer>(TETRIS_IOC_MAGIC, 0x22);
+
+/// Playback speed in % of real time, 0 = instant (default 100).
+const TETRIS_IOCTL_SET_REPLAY_SPEED: u32 = _IO(TETRIS_IOC_MAGIC, 0x23);
+
+/// Copy the whole game to userspace, so a helper can carry it across a
+/// module reload. Same argument and return value as
+/// `TETRIS_IOCTL_GET_REPLAY`. The blob is little endian:
+///
+/// ```text
+/// magic "TSAV", u16 version, u16 reserved
+/// u8 width, height, mode, randomizer, start level,
+///    options (1 = big, 2 = cascade, 4 = 20G), game over,
+///    end reason (0 = none, else `EndReason` + 1), paused,
+///    pending (0 = none, 1 = spawn, 2 = line clear), last T-spin,
+///    preview count, bag index
+/// u64 score, soft drop score, hard drop score
+/// u32 lines, level; i32 combo, max combo; u32 garbage pending, attack out
+/// u64 elapsed ns, seed, PRNG state
+/// u8 bag[14], preview[5] (`TetrominoType` order)
+/// u8 piece present, type, i8 x, i8 y, u8 rotation
+/// u8 held piece (0xff = none), hold used
+/// u32 dealt[7], placed[7]
+/// u8 cells, row by row from the top hidden row (see `Cell::to_raw`)
+/// ```
+///
+/// Timing options, input state and the replay are not saved.
+const TETRIS_IOCTL_SAVE_STATE: u32 = _IOW::<UserBuffer>(TETRIS_IOC_MAGIC, 0x24);
+
+/// Replace the game with one saved by `TETRIS_IOCTL_SAVE_STATE`; the
+/// argument describes the user buffer the same way. A restored game
+/// can't be replayed from its seed, so its replay starts out truncated.
+const TETRIS_IOCTL_LOAD_STATE: u32 = _IOW::<UserBuffer>(TETRIS_IOC_MAGIC, 0x25);
+
+/// Start a new game dealt from the given seed, so tests and replays get
+/// the same pieces every time. Root or the game's owner only.
+const TETRIS_IOCTL_SET_SEED: u32 = _IO(TETRIS_IOC_MAGIC, 0x26);
+
+/// Let the built-in bot play, one move every `arg` ms (0 = off). Games it
+/// loses are restarted, so it can soak test the engine unattended.
+const TETRIS_IOCTL_SET_AUTOPILOT: u32 = _IO(TETRIS_IOC_MAGIC, 0x27);
+
+/// Ask the bot where the active piece should go. Returns the leftmost
+/// column the piece should cover in bits 0-7 and its rotation (0 = spawn,
+/// clockwise) in bits 8-9, or `ENODATA` if there is no active piece.
+const TETRIS_IOCTL_HINT: u32 = _IO(TETRIS_IOC_MAGIC, 0x28);
+
+/// Whether this file plays the shared game (0) or a private one of its own
+/// (1), as the `mode` module parameter was when it was opened.
+const TETRIS_IOCTL_GET_SHARING: u32 = _IO(TETRIS_IOC_MAGIC, 0x29);
+
+/// What `read()` on this file returns (`FrameFormat`): 0 = the text frame,
+/// 1 = a fixed `BINARY_FRAME_LEN` byte frame for programs, little endian:
+///
+/// ```text
+///  0  magic "TFRM"        4  u16 version      6  u8 columns, u8 rows
+///  8  u64 score          16  u32 lines       20  u32 level
+/// 24  u8 flags (1 = game over, 2 = paused), hidden rows, preview count, 0
+/// 28  u8 piece (0xff = none, else `TetrominoType` order), rotation, i8 x, i8 y
+/// 32  u8 preview[5], u8 reserved[3]
+/// 40  u8 cells[BOARD_MAX_ROWS][BOARD_MAX_WIDTH] (see `Cell::to_raw`)
+/// ```
+///
+/// Rows count from the top, hidden rows first; the piece is not drawn into
+/// the cells, and its `x`/`y` place the top left of its 4x4 shape matrix.
+const TETRIS_IOCTL_SET_FORMAT: u32 = _IO(TETRIS_IOC_MAGIC, 0x2a);
+
+/// Copy a `TetrisState` snapshot of the game to the struct `arg` points to.
+const TETRIS_IOCTL_GET_STATE: u32 = _IOR::<TetrisState>(TETRIS_IOC_MAGIC, 0x2b);
+
+/// Apply a whole `TetrisConfig` from the struct `arg` points to at once.
+/// Nothing changes unless every field is valid.
+const TETRIS_IOCTL_SET_CONFIG: u32 = _IOW::<TetrisConfig>(TETRIS_IOC_MAGIC, 0x2c);
+
+/// Copy a `TetrisVersion` describing this build's interface to the struct
+/// `arg` points to.
+const TETRIS_IOCTL_GET_VERSION: u32 = _IOR::<TetrisVersion>(TETRIS_IOC_MAGIC, 0x2d);
+
+/// Copy the board to userspace, for bots that don't want to parse frames.
+/// Same argument and return value as `TETRIS_IOCTL_GET_REPLAY`. The blob
+/// is a u8 column count, row count, hidden row count and 0, then a byte
+/// per cell, row by row from the top hidden row (see `Cell::to_raw`). The
+/// active piece is not in it; `TETRIS_IOCTL_GET_STATE` has that.
+const TETRIS_IOCTL_GET_BOARD: u32 = _IOW::<UserBuffer>(TETRIS_IOC_MAGIC, 0x2e);
+
+/// Hold the active piece, as the `c` key does. Returns a movement result
+/// with `TETRIS_RESULT_MOVED` set if the piece went into hold.
+const TETRIS_IOCTL_HOLD: u32 = _IO(TETRIS_IOC_MAGIC, 0x2f);
+
+/// Install the key map `arg` points to: for each byte written, the
+/// `KeyAction` it does, or 0 for none. The digits 1-9 and ESC start repeat
+/// counts and escape sequences, so they can't be bound.
+const TETRIS_IOCTL_SET_KEYMAP: u32 = _IOW::<[u8; 256]>(TETRIS_IOC_MAGIC, 0x30);
+
+/// Abandon the game, as the `q` key does: it ends on the spot, with its
+/// time, replay and game over event as for any other end. Fails with
+/// `EINVAL` if the game is already over.
+const TETRIS_IOCTL_END: u32 = _IO(TETRIS_IOC_MAGIC, 0x31);
+
+/// Copy a `TetrisHighScore` with the best score on this device since the
+/// module was loaded to the struct `arg` points to.
+const TETRIS_IOCTL_GET_HIGH_SCORE: u32 = _IOR::<TetrisHighScore>(TETRIS_IOC_MAGIC, 0x32);
+
+/// `/dev/tetris-control`: start the lowest numbered game not running.
+/// Returns its number N; it plays on `/dev/tetrisN`, with events on
+/// `/dev/tetrisN-events`. Fails with `ENOSPC` if all are running.
+const TETRIS_CONTROL_CREATE: u32 = _IO(TETRIS_IOC_MAGIC, 0x80);
+
+/// `/dev/tetris-control`: returns the running numbered games, bit N set
+/// for `/dev/tetrisN`.
+const TETRIS_CONTROL_LIST: u32 = _IO(TETRIS_IOC_MAGIC, 0x81);
+
+/// `/dev/tetris-control`: stop numbered game `arg`. Files still open on it
+/// keep their board, but nothing falls any more. Fails with `ENOENT` if it
+/// isn't running.
+const TETRIS_CONTROL_DESTROY: u32 = _IO(TETRIS_IOC_MAGIC, 0x82);
+
+/// Commands were once numbered from this, in `_IOC_NR` order, without
+/// magic, direction or size. Those numbers still work for now but warn;
+/// they will go away in a later release.
+const TETRIS_IOCTL_LEGACY_BASE: u32 = 0x8000;
+
+/// The commands that had such numbers, by `_IOC_NR`.
+const TETRIS_LEGACY_IOCTLS: [u32; 45] = [
+    TETRIS_IOCTL_LEFT,
+    TETRIS_IOCTL_RIGHT,
+    TETRIS_IOCTL_DOWN,
+    TETRIS_IOCTL_ROTATE,
+    TETRIS_IOCTL_DROP,
+    TETRIS_IOCTL_RESET,
+    TETRIS_IOCTL_SET_PREVIEW,
+    TETRIS_IOCTL_ROTATE_CCW,
+    TETRIS_IOCTL_SOFT_DROP,
+    TETRIS_IOCTL_SET_GRAVITY,
+    TETRIS_IOCTL_SET_20G,
+    TETRIS_IOCTL_SET_MODE,
+    TETRIS_IOCTL_GET_SPRINT_TIME,
+    TETRIS_IOCTL_SET_INVISIBLE,
+    TETRIS_IOCTL_SET_BIG,
+    TETRIS_IOCTL_SET_CASCADE,
+    TETRIS_IOCTL_ADD_GARBAGE,
+    TETRIS_IOCTL_TAKE_ATTACK,
+    TETRIS_IOCTL_SET_RANDOMIZER,
+    TETRIS_IOCTL_SET_ARE,
+    TETRIS_IOCTL_SHIFT_PRESS,
+    TETRIS_IOCTL_SHIFT_RELEASE,
+    TETRIS_IOCTL_SET_DAS,
+    TETRIS_IOCTL_SET_ARR,
+    TETRIS_IOCTL_PAUSE,
+    TETRIS_IOCTL_RESUME,
+    TETRIS_IOCTL_GET_SCORE,
+    TETRIS_IOCTL_SET_START_LEVEL,
+    TETRIS_IOCTL_SET_SIZE,
+    TETRIS_IOCTL_SET_COLOR,
+    TETRIS_IOCTL_SET_CLEAR_SCREEN,
+    TETRIS_IOCTL_SET_ASCII,
+    TETRIS_IOCTL_SET_CLEAR_DELAY,
+    TETRIS_IOCTL_GET_REPLAY,
+    TETRIS_IOCTL_PLAY_REPLAY,
+    TETRIS_IOCTL_SET_REPLAY_SPEED,
+    TETRIS_IOCTL_SAVE_STATE,
+    TETRIS_IOCTL_LOAD_STATE,
+    TETRIS_IOCTL_SET_SEED,
+    TETRIS_IOCTL_SET_AUTOPILOT,
+    TETRIS_IOCTL_HINT,
+    TETRIS_IOCTL_GET_SHARING,
+    TETRIS_IOCTL_SET_FORMAT,
+    TETRIS_IOCTL_GET_STATE,
+    TETRIS_IOCTL_SET_CONFIG,
+];
+
+/// Version of the device interface as a whole, bumped on incompatible
+/// changes; additions show up in `TetrisVersion::features` instead.
+const TETRIS_ABI_VERSION: u32 = 1;
+
+/// `TetrisVersion::features` bits, for optional parts of the interface.
+const FEATURE_COLOR: u32 = 1 << 0;
+
+const FEATURE_BINARY_FRAMES: u32 = 1 << 1;
+
+/// Holding a piece (`TETRIS_IOCTL_HOLD`).
+const FEATURE_HOLD: u32 = 1 << 2;
+
+const FEATURE_MMAP: u32 = 1 << 3;
+
+const FEATURE_EVENTS: u32 = 1 << 4;
+
+/// The BPF bot hook, if the kernel could build it.
+const FEATURE_BPF: u32 = 1 << 5;
+
+/// `{ __u64 data; __u64 size; }` describing a user buffer, as the blob
+/// ioctls take it.
+type UserBuffer = [u64; 2];
+
+impl TetrisGame {
+    /// Apply `config` if every field of it is valid; see
+    /// `TETRIS_IOCTL_SET_CONFIG`. The caller applies `config.render`, which
+    /// isn't the game's.
+    fn set_config(&mut self, config: &TetrisConfig) -> Result {
+        let gravity_curve = GravityCurve::from_raw(config.gravity).ok_or(EINVAL)?;
+        let randomizer = Randomizer::from_raw(config.randomizer).ok_or(EINVAL)?;
+        if config.render & !RENDER_ALL != 0
+            || config.das_ms as usize > AUTOSHIFT_MAX_MS
+            || config.arr_ms as usize > AUTOSHIFT_MAX_MS
+            || !(1..=PREVIEW_MAX).contains(&(config.preview as usize))
+        {
+            return Err(EINVAL);
+        }
+
+        self.gravity_curve = gravity_curve;
+        if randomizer != self.bag.randomizer {
+            // Deal a fresh bag of the new kind once the preview needs one.
+            self.bag.set_randomizer(randomizer);
+        }
+        self.das_ns = config.das_ms as u64 * 1_000_000;
+        self.arr_ns = config.arr_ms as u64 * 1_000_000;
+        self.preview_count = config.preview as usize;
+        Ok(())
+    }
+
+    /// What `TetrisDeviceInner::summary` publishes.
+    pub(super) fn summary(&self) -> GameSummary {
+        GameSummary {
+            state: self.state(),
+            high_score: self.high_score,
+        }
+    }
+
+    /// Snapshot for `TETRIS_IOCTL_GET_STATE`.
+    pub(super) fn state(&self) -> TetrisState {
+        let piece = self.current_piece;
+        TetrisState {
+            score: self.score,
+            lines: self.lines,
+            level: self.level,
+            x: piece.map_or(0, |p| p.x),
+            y: piece.map_or(0, |p| p.y),
+            piece: piece.map_or(-1, |p| p.piece_type.index() as i8),
+            rotation: piece.map_or(0, |p| p.rotation % 4),
+            hold: self.hold.map_or(-1, |p| p.index() as i8),
+            game_over: self.game_over as u8,
+            paused: self.paused() as u8,
+            preview_count: self.preview_count as u8,
+            next: self.preview.map(|p| p.index() as i8),
+            reserved: [0; 5],
+        }
+    }
+}
+
+/// Translate a command from before `_IOC` encoding to its current number,
+/// warning the first time one turns up.
+fn legacy_ioctl(cmd: u32) -> u32 {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    let index = cmd.wrapping_sub(TETRIS_IOCTL_LEGACY_BASE) as usize;
+    let Some(&new) = TETRIS_LEGACY_IOCTLS.get(index) else {
+        return cmd;
+    };
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        pr_warn!(
+            "tetris: ioctl {:#x} uses a deprecated number, use {:#x} instead\n",
+            cmd,
+            new
+        );
+    }
+    new
+}
+
+/// Copy `blob` to the user buffer described by the `{ data, size }` pair at
+/// `arg`, truncated to fit. Returns the full length so callers can retry.
+fn copy_blob_to_user(arg: usize, blob: &[u8]) -> Result<isize> {
+    let mut desc = UserSlice::new(UserPtr::from_addr(arg), 16).reader();
+    let data: u64 = desc.read()?;
+    let size: u64 = desc.read()?;
+    let len = blob.len().min(size as usize);
+    UserSlice::new(UserPtr::from_addr(data as usize), len)
+        .writer()
+        .write_slice(&blob[..len])?;
+    Ok(blob.len() as isize)
+}
+
+/// Read the user buffer described by the `{ data, size }` pair at `arg`,
+/// refusing ones over `max` bytes.
+fn read_blob_from_user(arg: usize, max: usize) -> Result<KVVec<u8>> {
+    let mut desc = UserSlice::new(UserPtr::from_addr(arg), 16).reader();
+    let data: u64 = desc.read()?;
+    let size = usize::try_from(desc.read::<u64>()?).map_err(|_| EINVAL)?;
+    if size > max {
+        return Err(EINVAL);
+    }
+    let mut blob = KVVec::new();
+    blob.resize(size, 0, GFP_KERNEL)?;
+    UserSlice::new(UserPtr::from_addr(data as usize), size)
+        .reader()
+        .read_slice(&mut blob)?;
+    Ok(blob)
+}
+
+/// Device state
+#[pin_data]
+pub(crate) struct TetrisDevice {
+    inner: Arc<TetrisDeviceInner>,
+    /// `frame_gen` of the last frame this file read, 0 before the first.
+    frame_seen: AtomicU64,
+    /// `FrameFormat` this file reads.
+    format: AtomicU32,
+    /// `EscapeState` of this file's writes.
+    escape: AtomicU8,
+    /// Repeat count written so far for the next key, 0 for none.
+    repeat: AtomicU32,
+    /// The frame being read.
+    #[pin]
+    frame: kernel::sync::Mutex<FrameSnapshot>,
+    /// Keeps `inner` going if it is this file's own game.
+    private: Option<PrivateGame>,
+    /// Effective user that opened this file, credited with its inputs.
+    uid: Kuid,
+}
+
+/// Whether opens share one game or each get their own; the `mode` module
+/// parameter, which lives in tetris_param.c.
+#[derive(Clone, Copy, PartialEq)]
+enum GameSharing {
+    Shared = 0,
+    Private = 1,
+}
+
+fn game_sharing() -> GameSharing {
+    // SAFETY: Just reads the parameter.
+    match unsafe { tetris_game_mode() } {
+        1 => GameSharing::Private,
+        _ => GameSharing::Shared,
+    }
+}
+
+/// What reading a file returns; see `TETRIS_IOCTL_SET_FORMAT`.
+#[derive(Clone, Copy, PartialEq)]
+enum FrameFormat {
+    Text = 0,
+    Binary = 1,
+}
+
+impl FrameFormat {
+    fn from_raw(raw: usize) -> Option<Self> {
+        match raw {
+            0 => Some(FrameFormat::Text),
+            1 => Some(FrameFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// A key decoded from the bytes written
+#[derive(Clone, Copy)]
+enum WrittenKey {
+    /// A plain byte, looked up in the key map.
+    Byte(u8),
+    /// An arrow key, which is not rebindable.
+    Arrow(KeyAction),
+}
+
+/// Where a file's writes are in an escape sequence, so arrow keys from a
+/// raw terminal (`ESC [ A`, or `ESC O A` in application cursor mode) work
+/// even when split across writes.
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum EscapeState {
+    Ground = 0,
+    /// After `ESC`.
+    Escape = 1,
+    /// After `ESC [` or `ESC O`, up to the final byte.
+    Sequence = 2,
+}
+
+impl EscapeState {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            1 => EscapeState::Escape,
+            2 => EscapeState::Sequence,
+            _ => EscapeState::Ground,
+        }
+    }
+
+    /// Take the next byte written. Returns the key it completes, if any:
+    /// a plain byte, or an arrow for rotating (up), soft drop (down) or
+    /// moving sideways. Other sequences are swallowed whole.
+    fn feed(&mut self, byte: u8) -> Option<WrittenKey> {
+        const ESC: u8 = 0x1b;
+
+        match (*self, byte) {
+            (_, ESC) => {
+                *self = EscapeState::Escape;
+                None
+            }
+            (EscapeState::Ground, _) => Some(WrittenKey::Byte(byte)),
+            (EscapeState::Escape, b'[' | b'O') => {
+                *self = EscapeState::Sequence;
+                None
+            }
+            // Parameters and intermediates, e.g. the modifiers in
+            // `ESC [ 1 ; 5 A`.
+            (EscapeState::Sequence, 0x20..=0x3f) => None,
+            (EscapeState::Sequence, _) => {
+                *self = EscapeState::Ground;
+                let action = match byte {
+                    b'A' => KeyAction::RotateCw,
+                    b'B' => KeyAction::SoftDrop,
+                    b'C' => KeyAction::Right,
+                    b'D' => KeyAction::Left,
+                    _ => return None,
+                };
+                Some(WrittenKey::Arrow(action))
+            }
+            (EscapeState::Escape, _) => {
+                *self = EscapeState::Ground;
+                None
+            }
+        }
+    }
+}
+
+/// Gravity and autopilot of a game played by a single open file.
+struct PrivateGame {
+    _gravity: TetrisGravity,
+    _autopilot: TetrisAutopilot,
+}
+
+/// A file's copy of the frame it is reading.
+struct FrameSnapshot {
+    bytes: KVVec<u8>,
+    /// Seeked back to the start: the next read repeats this frame instead
+    /// of rendering a new one.
+    rewound: bool,
+}
+
+/// Layout of the read-only page `mmap()` maps, version `MMAP_VERSION`. It is
+/// updated whenever the game lock is let go; readers retry while `seq` is
+/// odd or changes under them:
+///
+/// ```text
+/// do {
+///         seq = READ_ONCE(page->seq);
+///         smp_rmb();
+///         copy = *page;
+///         smp_rmb();
+/// } while ((seq & 1) || seq != READ_ONCE(page->seq));
+/// ```
+#[repr(C)]
+pub(super) struct MmapState {
+    pub(super) seq: u32,
+    pub(super) version: u16,
+    pub(super) cols: u8,
+    /// Counting the hidden rows at the top.
+    pub(super) rows: u8,
+    pub(super) score: u64,
+    pub(super) lines: u32,
+    pub(super) level: u32,
+    /// `TetrominoType` order, -1 without an active piece.
+    pub(super) piece: i8,
+    pub(super) rotation: u8,
+    pub(super) game_over: u8,
+    pub(super) paused: u8,
+    /// Top left of the piece's 4x4 shape matrix.
+    pub(super) x: i32,
+    pub(super) y: i32,
+    /// One bit per column, top row first.
+    pub(super) board: [u16; BOARD_MAX_ROWS],
+}
+
+/// What `TETRIS_IOCTL_GET_STATE` copies out; in C:
+///
+/// ```text
+/// struct tetris_state {
+///         __u64 score;
+///         __u32 lines, level;
+///         __s32 x, y;
+///         __s8  piece, rotation, hold;
+///         __u8  game_over, paused, preview_count;
+///         __s8  next[5];
+///         __u8  reserved[5];
+/// };
+/// ```
+#[repr(C)]
+pub(super) struct TetrisState {
+    pub(super) score: u64,
+    lines: u32,
+    pub(super) level: u32,
+    /// Top left of the piece's 4x4 shape matrix, hidden rows included.
+    x: i32,
+    y: i32,
+    /// `TetrominoType` order, -1 without an active piece.
+    piece: i8,
+    rotation: u8,
+    /// The held piece, -1 for none.
+    hold: i8,
+    game_over: u8,
+    paused: u8,
+    /// How many of `next` the frame shows; all of them are valid.
+    preview_count: u8,
+    /// Upcoming pieces, the next one first.
+    next: [i8; PREVIEW_MAX],
+    reserved: [u8; 5],
+}
+
+// SAFETY: `TetrisState` is `repr(C)` with no padding, and any bytes are valid for its fields.
+unsafe impl AsBytes for TetrisState {}
+
+impl TetrisState {
+    /// "playing", "paused" or "game over".
+    pub(super) fn state_name(&self) -> &'static str {
+        if self.game_over != 0 {
+            "game over"
+        } else if self.paused != 0 {
+            "paused"
+        } else {
+            "playing"
+        }
+    }
+}
+
+/// What `TETRIS_IOCTL_GET_VERSION` copies out; in C:
+///
+/// ```text
+/// struct tetris_version {
+///         __u32 abi_version;      /* TETRIS_ABI_VERSION */
+///         __u32 features;         /* FEATURE_* bits */
+///         __u16 state_size;       /* struct tetris_state */
+///         __u16 config_size;      /* struct tetris_config */
+///         __u16 frame_size;       /* binary frame */
+///         __u16 event_size;       /* /dev/tetris-events record */
+///         __u16 mmap_size;        /* mmap() state */
+///         __u16 reserved;
+/// };
+/// ```
+#[repr(C)]
+struct TetrisVersion {
+    abi_version: u32,
+    features: u32,
+    state_size: u16,
+    config_size: u16,
+    frame_size: u16,
+    event_size: u16,
+    mmap_size: u16,
+    reserved: u16,
+}
+
+// SAFETY: `TetrisVersion` is `repr(C)` with no padding, and any bytes are valid for its fields.
+unsafe impl AsBytes for TetrisVersion {}
+
+/// What `TETRIS_IOCTL_GET_HIGH_SCORE` copies out; in C:
+///
+/// ```text
+/// struct tetris_high_score {
+///         __u64 score;
+///         __u32 uid;              /* (__u32)-1 if nobody has scored */
+///         __u32 reserved;
+/// };
+/// ```
+#[repr(C)]
+struct TetrisHighScore {
+    score: u64,
+    uid: u32,
+    reserved: u32,
+}
+
+// SAFETY: `TetrisHighScore` is `repr(C)` with no padding, and any bytes are valid for its fields.
+unsafe impl AsBytes for TetrisHighScore {}
+
+impl TetrisVersion {
+    fn new() -> Self {
+        let mut features =
+            FEATURE_COLOR | FEATURE_BINARY_FRAMES | FEATURE_HOLD | FEATURE_MMAP | FEATURE_EVENTS;
+        if cfg!(all(CONFIG_BPF_JIT, CONFIG_DEBUG_INFO_BTF_MODULES)) {
+            features |= FEATURE_BPF;
+        }
+        TetrisVersion {
+            abi_version: TETRIS_ABI_VERSION,
+            features,
+            state_size: core::mem::size_of::<TetrisState>() as u16,
+            config_size: core::mem::size_of::<TetrisConfig>() as u16,
+            frame_size: BINARY_FRAME_LEN as u16,
+            event_size: EVENT_RECORD_LEN as u16,
+            mmap_size: core::mem::size_of::<MmapState>() as u16,
+            reserved: 0,
+        }
+    }
+}
+
+/// What `TETRIS_IOCTL_SET_CONFIG` reads; in C:
+///
+/// ```text
+/// struct tetris_config {
+///         __u32 gravity;          /* 0 = modern, 1 = NES */
+///         __u32 randomizer;       /* 0 = 7-bag, 1 = 14-bag */
+///         __u32 render;           /* 1 = color, 2 = clear screen,
+///                                    4 = ASCII, 8 = invisible */
+///         __u32 das_ms, arr_ms;
+///         __u32 preview;          /* 1..5 */
+/// };
+/// ```
+#[repr(C)]
+struct TetrisConfig {
+    gravity: u32,
+    randomizer: u32,
+    render: u32,
+    das_ms: u32,
+    arr_ms: u32,
+    preview: u32,
+}
+
+// SAFETY: `TetrisConfig` is `repr(C)` with no padding, and any bytes are valid for its fields.
+unsafe impl FromBytes for TetrisConfig {}
+
+/// Whether the file `kiocb` refers to is in `O_NONBLOCK` mode.
+fn nonblocking<T: ForeignOwnable>(kiocb: &Kiocb<'_, T>) -> bool {
+    // SAFETY: The kiocb of a read or write in progress refers to a live file.
+    let file = unsafe { File::from_raw_file((*kiocb.as_raw()).ki_filp) };
+    file.flags() & flags::O_NONBLOCK != 0
+}
+
+/// `dev` with the drvdata accessors, which live on `Device<CoreInternal>`.
+fn core_internal(dev: &device::Device) -> &device::Device<device::CoreInternal> {
+    // SAFETY: `Device` is a transparent wrapper of `struct device` in every context.
+    unsafe { &*(dev as *const _ as *const _) }
+}
+
+/// What a misc device was registered for by `register_misc()`.
+fn registered_data<T: MiscDevice, D: Send + Sync + 'static>(
+    misc: &MiscDeviceRegistration<T>,
+) -> Arc<D> {
+    // SAFETY: we stored an `Arc<D>` in drvdata in `register_misc()`, and
+    // `MiscRegistration` only takes it back once nothing can open the device.
+    let data = unsafe { core_internal(misc.device()).drvdata_borrow::<Arc<D>>() };
+
+    // `data` is `Pin<&Arc<_>>`; we just need a cloned `Arc<_>`.
+    (*data).clone()
+}
+
+impl TetrisDevice {
+    fn new(
+        inner: Arc<TetrisDeviceInner>,
+        private: Option<PrivateGame>,
+        uid: Kuid,
+    ) -> Result<Arc<Self>> {
+        let device = Arc::pin_init(
+            pin_init!(Self {
+                inner,
+                frame_seen: AtomicU64::new(0),
+                format: AtomicU32::new(FrameFormat::Text as u32),
+                escape: AtomicU8::new(EscapeState::Ground as u8),
+                repeat: AtomicU32::new(0),
+                frame <- kernel::new_mutex!(FrameSnapshot {
+                    bytes: KVVec::new(),
+                    rewound: false,
+                }),
+                private,
+                uid,
+            }),
+            GFP_KERNEL,
+        )?;
+        device.inner.players.fetch_add(1, Ordering::Relaxed);
+        Ok(device)
+    }
+}
+
+impl TetrisDeviceInner {
+    /// Play `bytes` written by `player` as keys. Escape sequences and repeat
+    /// counts may run on from the last write, and on into the next one, in
+    /// `escape` and `repeat`. Takes the game lock for `KEYS_PER_LOCK` keys
+    /// at a time.
+    pub(super) fn write_keys(
+        &self,
+        bytes: &[u8],
+        player: Kuid,
+        escape: &mut EscapeState,
+        repeat: &mut u32,
+    ) {
+        let mut game = None;
+        let mut held = 0;
+        for &byte in bytes {
+            let Some(key) = escape.feed(byte) else {
+                continue;
+            };
+            // A count can't start with 0, which stays a key.
+            if let WrittenKey::Byte(digit @ b'0'..=b'9') = key {
+                if *repeat > 0 || digit != b'0' {
+                    *repeat = (*repeat * 10 + (digit - b'0') as u32).min(REPEAT_MAX);
+                    continue;
+                }
+            }
+            for _ in 0..core::mem::take(repeat).max(1) {
+                if held == KEYS_PER_LOCK {
+                    game = None;
+                    held = 0;
+                }
+                let game = game.get_or_insert_with(|| {
+                    let mut game = self.lock_game();
+                    game.frame_changed();
+                    game
+                });
+                self.apply_key(game, key, player);
+                held += 1;
+            }
+        }
+    }
+
+    /// Apply a key written by `player`, as the next input of the batch.
+    fn apply_key(&self, game: &mut TetrisGame, key: WrittenKey, player: Kuid) {
+        // Same order as for any input: catch up on deadlines, then act.
+        game.check_spawn_delay(&self.stats);
+        game.note_input(&self.stats);
+        game.player = Some(player);
+        let action = match key {
+            WrittenKey::Byte(byte) => game.keymap.action(byte),
+            WrittenKey::Arrow(action) => Some(action),
+        };
+        match action {
+            Some(KeyAction::Left) => {
+                self.stats.left.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::Left);
+                if game.move_left() {
+                    self.stats.left_ok.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(KeyAction::Right) => {
+                self.stats.right.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::Right);
+                if game.move_right() {
+                    self.stats.right_ok.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(KeyAction::SoftDrop) => {
+                self.stats.down.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::SoftDrop);
+                if game.soft_drop(&self.stats, false) > 0 {
+                    self.stats.down_ok.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(KeyAction::SoftDropToFloor) => {
+                self.stats.down.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::SoftDropToFloor);
+                if game.soft_drop(&self.stats, true) > 0 {
+                    self.stats.down_ok.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(KeyAction::RotateCw) => {
+                self.stats.rotate.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::RotateCw);
+                if game.rotate(Rotation::Cw) {
+                    self.stats.rotate_ok.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(KeyAction::RotateCcw) => {
+                self.stats.rotate.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::RotateCcw);
+                if game.rotate(Rotation::Ccw) {
+                    self.stats.rotate_ok.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(KeyAction::HardDrop) => {
+                self.stats.drop.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::HardDrop);
+                game.hard_drop(&self.stats);
+            }
+            Some(KeyAction::Hold) => {
+                game.record(ReplayInput::Hold);
+                game.hold_piece(&self.stats);
+            }
+            Some(KeyAction::Reset) => {
+                self.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.reset(&self.stats);
+            }
+            Some(KeyAction::Pause) => {
+                if game.pause() {
+                    game.record(ReplayInput::Pause);
+                } else if game.resume() {
+                    game.record(ReplayInput::Resume);
+                }
+            }
+            Some(KeyAction::End) => {
+                game.record(ReplayInput::End);
+                game.abandon(&self.stats);
+            }
+            None => {
+                self.stats.invalid_inputs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[vtable]
+impl MiscDevice for TetrisDevice {
+    type Ptr = Arc<TetrisDevice>;
+
+    fn open(file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        let inner: Arc<TetrisDeviceInner> = registered_data(misc);
+        inner.stats.opens.fetch_add(1, Ordering::Relaxed);
+
+        if game_sharing() == GameSharing::Private {
+            let inner = create_tetris_inner()?;
+            inner.game.lock().owner = Some(file.cred().euid());
+            let private = PrivateGame {
+                _gravity: start_tetris_gravity(inner.clone()),
+                _autopilot: start_tetris_autopilot(inner.clone())?,
+            };
+            return TetrisDevice::new(inner, Some(private), file.cred().euid());
+        }
+
+        inner.game.lock().owner.get_or_insert(file.cred().euid());
+        TetrisDevice::new(inner, None, file.cred().euid())
+    }
+
+    fn release(device: Self::Ptr, _file: &File) {
+        device.inner.players.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Each frame reads as a file of its own: a read at offset 0 renders a
+    /// new one (unless `llseek()` rewound to repeat it), later reads continue
+    /// it, and the read at its end returns 0 (EOF) and rewinds for the next
+    /// frame.
+    fn read_iter(mut kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterDest<'_>) -> Result<usize> {
+        let device = kiocb.file();
+        device.inner.stats.reads.fetch_add(1, Ordering::Relaxed);
+        let pos = usize::try_from(kiocb.ki_pos()).map_err(|_| EINVAL)?;
+        let mut frame = device.frame.lock();
+
+        if pos == 0 && !core::mem::take(&mut frame.rewound) {
+            let binary = device.format.load(Ordering::Relaxed) == FrameFormat::Binary as u32;
+            let seen = device.frame_seen.load(Ordering::Relaxed);
+            // A frame another file rendered is copied without the game lock.
+            if let Some(shared) = (!binary).then(|| device.inner.shared_frame(seen)).flatten() {
+                frame.bytes.clear();
+                frame.bytes.extend_from_slice(&shared.bytes, GFP_KERNEL)?;
+                device.frame_seen.store(shared.gen, Ordering::Relaxed);
+                device
+                    .inner
+                    .stats
+                    .frames_cached
+                    .fetch_add(1, Ordering::Relaxed);
+            } else {
+                // Made room for before locking, which can't allocate.
+                frame.bytes.resize(RENDER_BUFFER_SIZE, 0, GFP_KERNEL)?;
+                let mut game = device.inner.lock_game();
+                game.check_spawn_delay(&device.inner.stats);
+                // Readers only get frames they haven't seen: event-driven
+                // ones are told to come back, the rest sleep, so `cat` keeps
+                // pace with the game.
+                while game.frame_gen == device.frame_seen.load(Ordering::Relaxed) {
+                    if nonblocking(&kiocb) {
+                        return Err(EAGAIN);
+                    }
+                    if game.wait_frame() {
+                        return Err(ERESTARTSYS);
+                    }
+                    game.check_spawn_delay(&device.inner.stats);
+                }
+
+                device.frame_seen.store(game.frame_gen, Ordering::Relaxed);
+                let len = if binary {
+                    let len = game.render_binary(&mut frame.bytes);
+                    game.trace = LockTrace::Render(len as u32);
+                    len
+                } else {
+                    // Drawing the text is slow; only the copy holds up the game.
+                    let view = GameView::new(&game);
+                    let (wait_ns, held_ns) = game.unlock();
+                    let len = device.inner.render_shared(&view, &mut frame.bytes)?;
+                    // SAFETY: Just fires the trace event.
+                    unsafe { tetris_trace_render(len as u32, wait_ns, held_ns) };
+                    len
+                };
+                frame.bytes.truncate(len);
+            }
+        }
+
+        if pos >= frame.bytes.len() {
+            *kiocb.ki_pos_mut() = 0;
+            return Ok(0);
+        }
+        let copied = iov.copy_to_iter(&frame.bytes[pos..]);
+        *kiocb.ki_pos_mut() += copied as i64;
+        drop(frame);
+
+        device
+            .inner
+            .stats
+            .bytes_read
+            .fetch_add(copied as u64, Ordering::Relaxed);
+
+        Ok(copied)
+    }
+
+    /// Map the game's state page (see `MmapState`), read-only.
+    fn mmap(device: ArcBorrow<'_, TetrisDevice>, _file: &File, vma: &VmaNew) -> Result {
+        if vma.end() - vma.start() != PAGE_SIZE || vma.pgoff() != 0 {
+            return Err(EINVAL);
+        }
+        vma.try_clear_maywrite()?;
+        vma.set_dontexpand();
+        vma.set_mixedmap().vm_insert_page(vma.start(), &device.inner.state_page)
+    }
+
+    /// Every recognised key in the buffer is applied in order; other bytes
+    /// are counted as invalid inputs and skipped. The game lock is let go
+    /// every `KEYS_PER_LOCK` keys, so other inputs and ticks can land in
+    /// between the keys of one write. A key can be prefixed with a repeat
+    /// count, up to `REPEAT_MAX`. At most `WRITE_BATCH_MAX` bytes are taken
+    /// per call.
+    fn write_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterSource<'_>) -> Result<usize> {
+        let device = kiocb.file();
+        device.inner.stats.writes.fetch_add(1, Ordering::Relaxed);
+
+        let mut buffer = [0u8; WRITE_BATCH_MAX];
+        let len = iov.copy_from_iter(&mut buffer);
+
+        device
+            .inner
+            .stats
+            .bytes_written
+            .fetch_add(len as u64, Ordering::Relaxed);
+
+        let mut escape = EscapeState::from_raw(device.escape.load(Ordering::Relaxed));
+        let mut repeat = device.repeat.load(Ordering::Relaxed);
+        device
+            .inner
+            .write_keys(&buffer[..len], device.uid, &mut escape, &mut repeat);
+        device.escape.store(escape as u8, Ordering::Relaxed);
+        device.repeat.store(repeat, Ordering::Relaxed);
+
+        Ok(len)
+    }
+
+    fn ioctl(
+        device: <Self::Ptr as ForeignOwnable>::Borrowed<'_>,
+        file: &File,
+        cmd: u32,
+        arg: usize,
+    ) -> Result<isize> {
+        device.inner.stats.ioctls.fetch_add(1, Ordering::Relaxed);
+        let cmd = legacy_ioctl(cmd);
+        // Spectators poll this; it needn't wait for the game lock.
+        if cmd == TETRIS_IOCTL_GET_STATE {
+            let state = device.inner.summary.read().state;
+            UserSlice::new(UserPtr::from_addr(arg), core::mem::size_of::<TetrisState>())
+                .writer()
+                .write(&state)?;
+            return Ok(0);
+        }
+        // Drawing options belong to the slow path, not the game, which
+        // only takes them as input further down.
+        if matches!(
+            cmd,
+            TETRIS_IOCTL_SET_INVISIBLE
+                | TETRIS_IOCTL_SET_COLOR
+                | TETRIS_IOCTL_SET_CLEAR_SCREEN
+                | TETRIS_IOCTL_SET_ASCII
+        ) {
+            let mut options = device.inner.render.lock();
+            let on = arg != 0;
+            match cmd {
+                TETRIS_IOCTL_SET_INVISIBLE => options.invisible = on,
+                TETRIS_IOCTL_SET_COLOR => options.color = on,
+                TETRIS_IOCTL_SET_CLEAR_SCREEN => options.clear_screen = on,
+                _ => options.ascii = on,
+            }
+        }
+        // Blobs are copied out under the game lock, so they get room first.
+        let mut blob = KVVec::with_capacity(
+            match cmd {
+                TETRIS_IOCTL_GET_REPLAY => REPLAY_BLOB_MAX,
+                TETRIS_IOCTL_GET_BOARD => BOARD_BLOB_MAX,
+                TETRIS_IOCTL_SAVE_STATE => STATE_MAX_LEN,
+                _ => 0,
+            },
+            GFP_KERNEL,
+        )?;
+        // SAFETY: `capable()` only looks at the current task's credentials.
+        let admin = cmd == TETRIS_IOCTL_SET_SEED
+            && unsafe { bindings::capable(bindings::CAP_SYS_ADMIN as i32) };
+        let mut game = device.inner.lock_game();
+        game.trace = LockTrace::Ioctl(cmd);
+        game.check_spawn_delay(&device.inner.stats);
+        // Reading results back (or tuning playback) isn't playing.
+        if !matches!(
+            cmd,
+            TETRIS_IOCTL_GET_SCORE
+                | TETRIS_IOCTL_GET_SPRINT_TIME
+                | TETRIS_IOCTL_GET_REPLAY
+                | TETRIS_IOCTL_SAVE_STATE
+                | TETRIS_IOCTL_SET_REPLAY_SPEED
+                | TETRIS_IOCTL_TAKE_ATTACK
+                | TETRIS_IOCTL_HINT
+                | TETRIS_IOCTL_GET_SHARING
+                | TETRIS_IOCTL_SET_FORMAT
+                | TETRIS_IOCTL_GET_VERSION
+                | TETRIS_IOCTL_GET_BOARD
+                | TETRIS_IOCTL_SET_KEYMAP
+                | TETRIS_IOCTL_GET_HIGH_SCORE
+        ) {
+            game.note_input(&device.inner.stats);
+            game.player = Some(file.cred().euid());
+            game.frame_changed();
+        }
+        let mut ret: isize = 0;
+        let before = (game.lock_count(), game.lines);
+
+        match cmd {
+            TETRIS_IOCTL_LEFT => {
+                device.inner.stats.left.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::Left);
+                let moved = game.move_left();
+                if moved {
+                    device.inner.stats.left_ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, moved, 0);
+            }
+            TETRIS_IOCTL_RIGHT => {
+                device.inner.stats.right.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::Right);
+                let moved = game.move_right();
+                if moved {
+                    device.inner.stats.right_ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, moved, 0);
+            }
+            TETRIS_IOCTL_DOWN => {
+                device.inner.stats.down.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::Down);
+                let moved = game.move_down(&device.inner.stats);
+                if moved {
+                    device.inner.stats.down_ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, moved, moved as u32);
+            }
+            TETRIS_IOCTL_SOFT_DROP => {
+                device.inner.stats.down.fetch_add(1, Ordering::Relaxed);
+                game.record(if arg != 0 {
+                    ReplayInput::SoftDropToFloor
+                } else {
+                    ReplayInput::SoftDrop
+                });
+                let cells = game.soft_drop(&device.inner.stats, arg != 0);
+                if cells > 0 {
+                    device.inner.stats.down_ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, cells > 0, cells);
+            }
+            TETRIS_IOCTL_ROTATE => {
+                device.inner.stats.rotate.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::RotateCw);
+                let moved = game.rotate(Rotation::Cw);
+                if moved {
+                    device.inner.stats.rotate_ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, moved, 0);
+            }
+            TETRIS_IOCTL_ROTATE_CCW => {
+                device.inner.stats.rotate.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::RotateCcw);
+                let moved = game.rotate(Rotation::Ccw);
+                if moved {
+                    device.inner.stats.rotate_ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, moved, 0);
+            }
+            TETRIS_IOCTL_DROP => {
+                device.inner.stats.drop.fetch_add(1, Ordering::Relaxed);
+                game.record(ReplayInput::HardDrop);
+                // The drop distance lets UIs animate it.
+                let cells = game.hard_drop(&device.inner.stats);
+                ret = game.move_result(before, cells > 0, cells);
+            }
+            TETRIS_IOCTL_HOLD => {
+                game.record(ReplayInput::Hold);
+                let moved = game.hold_piece(&device.inner.stats);
+                ret = game.move_result(before, moved, 0);
+            }
+            TETRIS_IOCTL_RESET => {
+                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.reset(&device.inner.stats);
+            }
+            TETRIS_IOCTL_SET_PREVIEW => {
+                game.set_preview_count(arg)?;
+            }
+            TETRIS_IOCTL_SET_GRAVITY => {
+                let curve = u32::try_from(arg)
+                    .ok()
+                    .and_then(GravityCurve::from_raw)
+                    .ok_or(EINVAL)?;
+                game.gravity_curve = curve;
+                // Apply from the next tick rather than waiting a full period.
+                device
+                    .inner
+                    .gravity_ns
+                    .store(game.gravity_period_ns(), Ordering::Relaxed);
+            }
+            TETRIS_IOCTL_SET_MODE => {
+                game.mode = u32::try_from(arg)
+                    .ok()
+                    .and_then(GameMode::from_raw)
+                    .ok_or(EINVAL)?;
+                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.reset(&device.inner.stats);
+            }
+            TETRIS_IOCTL_GET_SPRINT_TIME => {
+                let ns = game.sprint_time_ns().ok_or(ENODATA)?;
+                ret = (ns / 1_000_000) as isize;
+            }
+            // Set above.
+            TETRIS_IOCTL_SET_INVISIBLE
+            | TETRIS_IOCTL_SET_COLOR
+            | TETRIS_IOCTL_SET_CLEAR_SCREEN
+            | TETRIS_IOCTL_SET_ASCII => {}
+            TETRIS_IOCTL_SET_CLEAR_DELAY => {
+                if arg > CLEAR_DELAY_MAX_MS {
+                    return Err(EINVAL);
+                }
+                game.clear_delay_ns = arg as u64 * 1_000_000;
+            }
+            TETRIS_IOCTL_SET_BIG => {
+                // The logical grid changes size, so start over.
+                game.set_big(arg != 0)?;
+                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.reset(&device.inner.stats);
+            }
+            TETRIS_IOCTL_ADD_GARBAGE => {
+                let lines = u32::try_from(arg).map_err(|_| EINVAL)?;
+                game.garbage_pending = game.garbage_pending.saturating_add(lines).min(GARBAGE_MAX);
+            }
+            TETRIS_IOCTL_TAKE_ATTACK => {
+                ret = game.attack_out as isize;
+                game.attack_out = 0;
+            }
+            TETRIS_IOCTL_SET_RANDOMIZER => {
+                let randomizer = u32::try_from(arg)
+                    .ok()
+                    .and_then(Randomizer::from_raw)
+                    .ok_or(EINVAL)?;
+                // Deal a fresh bag of the new kind once the preview needs one.
+                game.bag.set_randomizer(randomizer);
+            }
+            TETRIS_IOCTL_SET_ARE => {
+                if arg > ARE_MAX_MS {
+                    return Err(EINVAL);
+                }
+                game.are_ns = arg as u64 * 1_000_000;
+            }
+            TETRIS_IOCTL_SHIFT_PRESS => {
+                let dir = Shift::from_raw(arg).ok_or(EINVAL)?;
+                let (stat, ok) = match dir {
+                    Shift::Left => (&device.inner.stats.left, &device.inner.stats.left_ok),
+                    Shift::Right => (&device.inner.stats.right, &device.inner.stats.right_ok),
+                };
+                stat.fetch_add(1, Ordering::Relaxed);
+                game.record(dir.into());
+                let (moved, delay_ns) = game.shift_press(dir);
+                if moved {
+                    ok.fetch_add(1, Ordering::Relaxed);
+                }
+                ret = game.move_result(before, moved, 0);
+                TetrisDeviceInner::queue_auto_shift(device.inner.clone(), delay_ns);
+            }
+            TETRIS_IOCTL_SHIFT_RELEASE => {
+                game.shift_release(Shift::from_raw(arg).ok_or(EINVAL)?);
+            }
+            TETRIS_IOCTL_SET_DAS => {
+                if arg > AUTOSHIFT_MAX_MS {
+                    return Err(EINVAL);
+                }
+                game.das_ns = arg as u64 * 1_000_000;
+            }
+            TETRIS_IOCTL_SET_ARR => {
+                if arg > AUTOSHIFT_MAX_MS {
+                    return Err(EINVAL);
+                }
+                game.arr_ns = arg as u64 * 1_000_000;
+            }
+            TETRIS_IOCTL_SET_START_LEVEL => {
+                let level = u32::try_from(arg).map_err(|_| EINVAL)?;
+                if !(1..=START_LEVEL_MAX).contains(&level) {
+                    return Err(EINVAL);
+                }
+                game.start_level = level;
+                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.reset(&device.inner.stats);
+                device
+                    .inner
+                    .gravity_ns
+                    .store(game.gravity_period_ns(), Ordering::Relaxed);
+            }
+            TETRIS_IOCTL_SET_SIZE => {
+                game.set_size(arg & 0xffff, (arg >> 16) & 0xffff)?;
+                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.reset(&device.inner.stats);
+            }
+            TETRIS_IOCTL_GET_SCORE => {
+                let score = game.score;
+                drop(game);
+                UserSlice::new(UserPtr::from_addr(arg), core::mem::size_of::<u64>())
+                    .writer()
+                    .write(&score)?;
+                return Ok(0);
+            }
+            TETRIS_IOCTL_GET_HIGH_SCORE => {
+                let high_score = TetrisHighScore {
+                    score: game.high_score,
+                    uid: game
+                        .high_score_uid
+                        .map_or(u32::MAX, |uid| uid.into_uid_in_current_ns()),
+                    reserved: 0,
+                };
+                drop(game);
+                UserSlice::new(UserPtr::from_addr(arg), core::mem::size_of::<TetrisHighScore>())
+                    .writer()
+                    .write(&high_score)?;
+                return Ok(0);
+            }
+            TETRIS_IOCTL_GET_VERSION => {
+                drop(game);
+                UserSlice::new(UserPtr::from_addr(arg), core::mem::size_of::<TetrisVersion>())
+                    .writer()
+                    .write(&TetrisVersion::new())?;
+                return Ok(0);
+            }
+            TETRIS_IOCTL_END => {
+                game.record(ReplayInput::End);
+                if !game.abandon(&device.inner.stats) {
+                    return Err(EINVAL);
+                }
+            }
+            TETRIS_IOCTL_PAUSE => {
+                if !game.pause() {
+                    return Err(EINVAL);
+                }
+                game.record(ReplayInput::Pause);
+            }
+            TETRIS_IOCTL_RESUME => {
+                if !game.resume() {
+                    return Err(EINVAL);
+                }
+                game.record(ReplayInput::Resume);
+            }
+            TETRIS_IOCTL_GET_REPLAY => {
+                game.replay_blob(&mut blob)?;
+                drop(game);
+                return copy_blob_to_user(arg, &blob);
+            }
+            TETRIS_IOCTL_GET_BOARD => {
+                game.board_blob(&mut blob)?;
+                drop(game);
+                return copy_blob_to_user(arg, &blob);
+            }
+            TETRIS_IOCTL_SAVE_STATE => {
+                game.save_state(&mut blob)?;
+                drop(game);
+                return copy_blob_to_user(arg, &blob);
+            }
+            TETRIS_IOCTL_LOAD_STATE => {
+                drop(game);
+                let blob = read_blob_from_user(arg, STATE_MAX_LEN)?;
+                let mut game = device.inner.lock_game();
+                game.load_state(&blob)?;
+                game.frame_changed();
+                device
+                    .inner
+                    .gravity_ns
+                    .store(game.next_tick_ns(), Ordering::Relaxed);
+                return Ok(0);
+            }
+            TETRIS_IOCTL_SET_CONFIG => {
+                drop(game);
+                let config: TetrisConfig =
+                    UserSlice::new(UserPtr::from_addr(arg), core::mem::size_of::<TetrisConfig>())
+                        .reader()
+                        .read()?;
+                let mut options = device.inner.render.lock();
+                let mut game = device.inner.lock_game();
+                game.set_config(&config)?;
+                *options = RenderOptions::from_flags(config.render);
+                game.frame_changed();
+                device
+                    .inner
+                    .gravity_ns
+                    .store(game.gravity_period_ns(), Ordering::Relaxed);
+                return Ok(0);
+            }
+            TETRIS_IOCTL_SET_KEYMAP => {
+                drop(game);
+                let raw: [u8; 256] = UserSlice::new(UserPtr::from_addr(arg), 256)
+                    .reader()
+                    .read()?;
+                let keymap = KeyMap::from_raw(raw)?;
+                device.inner.lock_game().keymap = keymap;
+                return Ok(0);
+            }
+            TETRIS_IOCTL_PLAY_REPLAY => {
+                drop(game);
+                let blob = read_blob_from_user(arg, REPLAY_BLOB_MAX)?;
+                let mut game = device.inner.lock_game();
+                let first_ns = game.start_playback(&device.inner.stats, &blob)?;
+                game.frame_changed();
+                device
+                    .inner
+                    .gravity_ns
+                    .store(game.gravity_period_ns(), Ordering::Relaxed);
+                if game.replay_speed == 0 {
+                    // Up to `REPLAY_MAX_EVENTS` inputs: let go of the lock
+                    // between batches, as `write_keys()` does.
+                    let mut held = 0;
+                    while game.replay_step(&device.inner.stats).is_some() {
+                        game = device.inner.settle_cascade(game);
+                        held += 1;
+                        if held == KEYS_PER_LOCK {
+                            drop(game);
+                            game = device.inner.lock_game();
+                            held = 0;
+                        }
+                    }
+                    return Ok((game.replay_verdict != Some(true)) as isize);
+                }
+                drop(game);
+                TetrisDeviceInner::queue_replay(device.inner.clone(), first_ns);
+                return Ok(0);
+            }
+            TETRIS_IOCTL_SET_SEED => {
+                // Knowing the seed means knowing every piece to come.
+                if game.owner != Some(file.cred().euid()) && !admin {
+                    return Err(EPERM);
+                }
+                device.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+                game.restart(&device.inner.stats, arg as u64);
+            }
+            TETRIS_IOCTL_HINT => {
+                // The search is too slow for the game lock; see `bot_turn()`.
+                let view = BotView::new(&game).ok_or(ENODATA)?;
+                drop(game);
+                let (column, rotation) = view.hint().ok_or(ENODATA)?;
+                return Ok((column | (rotation as u32) << 8) as isize);
+            }
+            TETRIS_IOCTL_SET_FORMAT => {
+                let format = FrameFormat::from_raw(arg).ok_or(EINVAL)?;
+                device.format.store(format as u32, Ordering::Relaxed);
+                // The next read shouldn't wait for the game to redraw it.
+                device.frame_seen.store(0, Ordering::Relaxed);
+            }
+            TETRIS_IOCTL_GET_SHARING => {
+                ret = match device.private {
+                    Some(_) => GameSharing::Private,
+                    None => GameSharing::Shared,
+                } as isize;
+            }
+            TETRIS_IOCTL_SET_AUTOPILOT => {
+                if arg > AUTOPILOT_MAX_MS {
+                    return Err(EINVAL);
+                }
+                game.autopilot_ms = arg as u32;
+                device.inner.autopilot_wake.notify_all();
+            }
+            TETRIS_IOCTL_SET_REPLAY_SPEED => {
+                if arg > REPLAY_SPEED_MAX {
+                    return Err(EINVAL);
+                }
+                game.replay_speed = arg as u32;
+            }
+            TETRIS_IOCTL_SET_CASCADE => {
+                game.cascade = arg != 0;
+            }
+            TETRIS_IOCTL_SET_20G => {
+                game.instant_gravity = arg != 0;
+                game.apply_instant_gravity();
+                device
+                    .inner
+                    .gravity_ns
+                    .store(game.gravity_period_ns(), Ordering::Relaxed);
+            }
+            _ => {
+                device
+                    .inner
+                    .stats
+                    .invalid_ioctls
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(EINVAL);
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// A `/dev/tetris-events` file. Reads return whole event records (see
+/// `GameEvent::to_bytes()`) of the shared game, starting with the first
+/// event after the open; like frames, they wait for something new unless
+/// the file is `O_NONBLOCK`.
+pub(crate) struct TetrisEvents {
+    inner: Arc<TetrisDeviceInner>,
+    /// Sequence number of the next event to read.
+    next: AtomicU64,
+}
+
+#[vtable]
+impl MiscDevice for TetrisEvents {
+    type Ptr = Arc<TetrisEvents>;
+
+    fn open(_file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        let inner: Arc<TetrisDeviceInner> = registered_data(misc);
+        let next = inner.game.lock().event_seq;
+        Ok(Arc::new(
+            TetrisEvents {
+                inner,
+                next: AtomicU64::new(next),
+            },
+            GFP_KERNEL,
+        )?)
+    }
+
+    /// Read as many records as fit, at least one; events the file fell too
+    /// far behind on are skipped.
+    fn read_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterDest<'_>) -> Result<usize> {
+        let events = kiocb.file();
+        if iov.len() < EVENT_RECORD_LEN {
+            return Err(EINVAL);
+        }
+
+        let max = iov.len() / EVENT_RECORD_LEN;
+        let mut records =
+            KVec::with_capacity(max.min(EVENT_RING_LEN) * EVENT_RECORD_LEN, GFP_KERNEL)?;
+        let mut game = events.inner.lock_game();
+        while game.event_seq == events.next.load(Ordering::Relaxed) {
+            if nonblocking(&kiocb) {
+                return Err(EAGAIN);
+            }
+            if game.wait_frame() {
+                return Err(ERESTARTSYS);
+            }
+        }
+        let mut next = events.next.load(Ordering::Relaxed);
+        game.read_events(&mut next, max, &mut records)?;
+        events.next.store(next, Ordering::Relaxed);
+        drop(game);
+
+        Ok(iov.copy_to_iter(&records))
+    }
+}
+
+/// Game device file operations: those `MiscDeviceRegistration` generates,
+/// plus `poll` and `llseek`, which `MiscDevice` has no hooks for. Filled in
+/// by the first `register_tetris_device()`.
+static mut TETRIS_FOPS: core::mem::MaybeUninit<bindings::file_operations> =
+    core::mem::MaybeUninit::zeroed();
+
+/// Event device file operations, plus `poll`. Filled in by the first
+/// `register_tetris_events()`.
+static mut TETRIS_EVENTS_FOPS: core::mem::MaybeUninit<bindings::file_operations> =
+    core::mem::MaybeUninit::zeroed();
+
+/// Readable once the frame has changed since this file last read it. Writes
+/// never block.
+unsafe extern "C" fn tetris_poll(
+    file: *mut bindings::file,
+    wait: *mut bindings::poll_table,
+) -> bindings::__poll_t {
+    // SAFETY: `MiscDevice::open()` stored an `Arc<TetrisDevice>` in `private_data`, and it is
+    // only released once no file operation can be running.
+    let device = unsafe { <Arc<TetrisDevice> as ForeignOwnable>::borrow((*file).private_data) };
+    // SAFETY: `file` is valid for the duration of the call.
+    let file = unsafe { File::from_raw_file(file) };
+    // SAFETY: `wait` is the poll table for this call.
+    let table = unsafe { PollTable::from_raw(wait) };
+
+    // Checked after registering, so a frame published meanwhile still wakes
+    // us; the published `frame_gen` spares taking the game lock.
+    table.register_wait(file, &device.inner.frame_wait);
+    let mut mask = bindings::POLLOUT | bindings::POLLWRNORM;
+    let frame_gen = device.inner.frame_gen.load(Ordering::Acquire);
+    if frame_gen != device.frame_seen.load(Ordering::Relaxed) {
+        mask |= bindings::POLLIN | bindings::POLLRDNORM;
+    }
+    mask as bindings::__poll_t
+}
+
+/// Move within the frame last read; `SEEK_END` is relative to its end,
+/// where the status lines are. Seeking to 0 makes the next read repeat the
+/// frame rather than render a new one.
+unsafe extern "C" fn tetris_llseek(
+    file: *mut bindings::file,
+    offset: bindings::loff_t,
+    whence: c_int,
+) -> bindings::loff_t {
+    // SAFETY: As in `tetris_poll()`.
+    let device = unsafe { <Arc<TetrisDevice> as ForeignOwnable>::borrow((*file).private_data) };
+    let mut frame = device.frame.lock();
+    // SAFETY: `file` is valid for the duration of the call.
+    let pos = unsafe { bindings::fixed_size_llseek(file, offset, whence, frame.bytes.len() as _) };
+    if pos == 0 {
+        frame.rewound = true;
+    }
+    pos
+}
+
+/// Readable while there are events this file hasn't read.
+unsafe extern "C" fn tetris_events_poll(
+    file: *mut bindings::file,
+    wait: *mut bindings::poll_table,
+) -> bindings::__poll_t {
+    // SAFETY: As in `tetris_poll()`.
+    let events = unsafe { <Arc<TetrisEvents> as ForeignOwnable>::borrow((*file).private_data) };
+    // SAFETY: `file` is valid for the duration of the call.
+    let file = unsafe { File::from_raw_file(file) };
+    // SAFETY: `wait` is the poll table for this call.
+    let table = unsafe { PollTable::from_raw(wait) };
+
+    // As in `tetris_poll()`, from the published `event_seq`.
+    table.register_wait(file, &events.inner.frame_wait);
+    let mut mask = 0;
+    let event_seq = events.inner.event_seq.load(Ordering::Acquire);
+    if event_seq != events.next.load(Ordering::Relaxed) {
+        mask |= bindings::POLLIN | bindings::POLLRDNORM;
+    }
+    mask as bindings::__poll_t
+}
+
+/// The read-only sysfs attributes of a game device, in the order
+/// `tetris_attr_show()` numbers them. There is also a write-only `reset`.
+const TETRIS_SYSFS_ATTRS: [&core::ffi::CStr; 6] = [
+    c"score",
+    c"level",
+    c"lines",
+    c"state",
+    c"high_score",
+    c"high_score_uid",
+];
+
+/// Show sysfs attribute `ATTR` of the game `dev` was registered for.
+unsafe extern "C" fn tetris_attr_show<const ATTR: usize>(
+    dev: *mut bindings::device,
+    _attr: *mut bindings::device_attribute,
+    buf: *mut c_char,
+) -> isize {
+    // SAFETY: sysfs only calls this while the game device `dev` is registered.
+    if unsafe { bindings::dev_get_drvdata(dev) }.is_null() {
+        // The files appear as `misc_register()` adds the device, before
+        // `register_misc()` has stored the game in it.
+        return ENODEV.to_errno() as isize;
+    }
+    // SAFETY: As above.
+    let dev = unsafe { device::Device::<device::CoreInternal>::from_raw(dev) };
+    // SAFETY: `register_misc()` stored an `Arc<TetrisDeviceInner>` for game devices, as the
+    // check above saw, and only takes it back once their sysfs files are gone.
+    let inner = unsafe { dev.drvdata_borrow::<Arc<TetrisDeviceInner>>() };
+    // SAFETY: sysfs hands `show()` a page to fill.
+    let page = unsafe { core::slice::from_raw_parts_mut(buf.cast::<u8>(), PAGE_SIZE) };
+
+    let summary = inner.summary.read();
+    let state = &summary.state;
+    let len = match ATTR {
+        0 => TetrisGame::write_number(page, 0, state.score),
+        1 => TetrisGame::write_number(page, 0, state.level),
+        2 => TetrisGame::write_number(page, 0, state.lines),
+        3 => TetrisGame::write_bytes(page, 0, state.state_name().as_bytes()),
+        4 => TetrisGame::write_number(page, 0, summary.high_score),
+        _ => match inner.game.lock().high_score_uid {
+            Some(uid) => TetrisGame::write_number(page, 0, uid.into_uid_in_current_ns()),
+            // Nobody has scored yet.
+            None => TetrisGame::write_bytes(page, 0, b"-1"),
+        },
+    };
+    page[len] = b'\n';
+    (len + 1) as isize
+}
+
+/// Start a new game when root writes anything to sysfs `reset`, for when a
+/// shared game is wedged.
+unsafe extern "C" fn tetris_attr_reset_store(
+    dev: *mut bindings::device,
+    _attr: *mut bindings::device_attribute,
+    _buf: *const c_char,
+    count: usize,
+) -> isize {
+    // SAFETY: As for `tetris_attr_show()`.
+    if unsafe { bindings::dev_get_drvdata(dev) }.is_null() {
+        return ENODEV.to_errno() as isize;
+    }
+    // SAFETY: As for `tetris_attr_show()`.
+    let dev = unsafe { device::Device::<device::CoreInternal>::from_raw(dev) };
+    // SAFETY: As for `tetris_attr_show()`.
+    let inner = unsafe { dev.drvdata_borrow::<Arc<TetrisDeviceInner>>() };
+
+    let mut game = inner.lock_game();
+    inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+    game.reset(&inner.stats);
+    game.frame_changed();
+    count as isize
+}
+
+/// The sysfs attribute groups of the game devices, for `struct miscdevice`.
+/// Built by the first call, from module init.
+fn tetris_sysfs_groups() -> *mut *const bindings::attribute_group {
+    type ShowFn = unsafe extern "C" fn(
+        *mut bindings::device,
+        *mut bindings::device_attribute,
+        *mut c_char,
+    ) -> isize;
+    const SHOW: [ShowFn; 6] = [
+        tetris_attr_show::<0>,
+        tetris_attr_show::<1>,
+        tetris_attr_show::<2>,
+        tetris_attr_show::<3>,
+        tetris_attr_show::<4>,
+        tetris_attr_show::<5>,
+    ];
+
+    static mut ATTRS: core::mem::MaybeUninit<[bindings::device_attribute; 7]> =
+        core::mem::MaybeUninit::zeroed();
+    static mut ATTR_LIST: [*mut bindings::attribute; 8] = [core::ptr::null_mut(); 8];
+    static mut GROUP: core::mem::MaybeUninit<bindings::attribute_group> =
+        core::mem::MaybeUninit::zeroed();
+    static mut GROUPS: [*const bindings::attribute_group; 2] = [core::ptr::null(); 2];
+
+    // SAFETY: Only written here, and only until the first game device is
+    // registered; later calls find `GROUPS` filled in and leave it.
+    unsafe {
+        let groups = core::ptr::addr_of_mut!(GROUPS).cast::<*const bindings::attribute_group>();
+        if groups.read().is_null() {
+            let attrs = core::ptr::addr_of_mut!(ATTRS).cast::<bindings::device_attribute>();
+            let list = core::ptr::addr_of_mut!(ATTR_LIST).cast::<*mut bindings::attribute>();
+            for (i, name) in TETRIS_SYSFS_ATTRS.iter().enumerate() {
+                let attr = attrs.add(i);
+                (*attr).attr.name = name.as_ptr();
+                (*attr).attr.mode = 0o444;
+                (*attr).show = Some(SHOW[i]);
+                list.add(i).write(core::ptr::addr_of_mut!((*attr).attr));
+            }
+            let reset = attrs.add(TETRIS_SYSFS_ATTRS.len());
+            (*reset).attr.name = c"reset".as_ptr();
+            (*reset).attr.mode = 0o200;
+            (*reset).store = Some(tetris_attr_reset_store);
+            list.add(TETRIS_SYSFS_ATTRS.len()).write(core::ptr::addr_of_mut!((*reset).attr));
+            let group = core::ptr::addr_of_mut!(GROUP).cast::<bindings::attribute_group>();
+            (*group).attrs = list;
+            groups.write(group);
+        }
+        groups
+    }
+}
+
+type PollFn =
+    unsafe extern "C" fn(*mut bindings::file, *mut bindings::poll_table) -> bindings::__poll_t;
+
+type LlseekFn =
+    unsafe extern "C" fn(*mut bindings::file, bindings::loff_t, c_int) -> bindings::loff_t;
+
+/// Permissions for the device nodes from the `device_mode` parameter, 0
+/// for the misc default (root only).
+fn device_mode() -> Result<bindings::umode_t> {
+    let mode = *crate::module_parameters::device_mode.value();
+    if mode > 0o777 {
+        return Err(EINVAL);
+    }
+    Ok(mode as bindings::umode_t)
+}
+
+/// A misc device registered for an `Arc<D>` by `register_misc()`. Dropping
+/// it deregisters the device, then drops that reference.
+pub(crate) struct MiscRegistration<T: MiscDevice, D: Send + Sync + 'static> {
+    reg: ManuallyDrop<Pin<kernel::alloc::KBox<MiscDeviceRegistration<T>>>>,
+    _data: PhantomData<Arc<D>>,
+}
+
+impl<T: MiscDevice, D: Send + Sync + 'static> Drop for MiscRegistration<T, D> {
+    fn drop(&mut self) {
+        // Hang on to the device to take the data back once it can't be
+        // opened any more.
+        let dev = kernel::types::ARef::from(self.reg.device());
+        // SAFETY: `reg` is not used again.
+        unsafe { ManuallyDrop::drop(&mut self.reg) };
+        // SAFETY: `register_misc()` stored an `Arc<D>`, and with the device deregistered no
+        // `open()` borrows it.
+        drop(unsafe { core_internal(&dev).drvdata_obtain::<Arc<D>>() });
+    }
+}
+
+/// Register a misc device for `data`, which its `open()` gets back from
+/// `registered_data()`, with node permissions `mode` (0 for the default)
+/// and sysfs attribute `groups` (null for none).
+fn register_misc<T: MiscDevice, D: Send + Sync + 'static>(
+    options: MiscDeviceOptions,
+    mode: bindings::umode_t,
+    groups: *mut *const bindings::attribute_group,
+    data: Arc<D>,
+) -> Result<MiscRegistration<T, D>> {
+    let reg = kernel::alloc::KBox::pin_init(MiscDeviceRegistration::register(options), GFP_KERNEL)?;
+
+    if mode != 0 || !groups.is_null() {
+        // `MiscDeviceOptions` has neither, and the node and sysfs files are
+        // created as the device registers, so register it again with them.
+        // SAFETY: `reg` is registered, so `as_raw()` points to its live `struct miscdevice`,
+        // which nothing else uses until `misc_register()` returns.
+        let ret = unsafe {
+            let misc = reg.as_raw();
+            bindings::misc_deregister(misc);
+            (*misc).mode = mode;
+            (*misc).groups = groups;
+            (*misc).minor = bindings::MISC_DYNAMIC_MINOR as c_int;
+            bindings::misc_register(misc)
+        };
+        if let Err(err) = kernel::error::to_result(ret) {
+            // Dropping it would deregister it again.
+            core::mem::forget(reg);
+            return Err(err);
+        }
+    }
+
+    // Store `data` as drvdata for this miscdevice's `struct device`.
+    core_internal(reg.device()).set_drvdata(unsafe {
+        pin_init::init_from_closure(move |slot| {
+            // SAFETY: `slot` is a valid pointer to uninitialized storage for `Arc<D>`.
+            core::ptr::write(slot, data);
+            Ok(())
+        })
+    })?;
+
+    Ok(MiscRegistration {
+        reg: ManuallyDrop::new(reg),
+        _data: PhantomData,
+    })
+}
+
+/// Add `poll` and `llseek` (if any) to the file operations of `misc`.
+///
+/// # Safety
+///
+/// `fops` must point to static storage that only devices of type `T` use,
+/// zeroed or filled in by an earlier call.
+unsafe fn add_fops<T: MiscDevice>(
+    misc: &MiscDeviceRegistration<T>,
+    fops: *mut bindings::file_operations,
+    poll: PollFn,
+    llseek: Option<LlseekFn>,
+) {
+    // misc_open() takes `fops` afresh for every open, so swapping in a copy
+    // with our extra hooks works from here on. Files opened in between just
+    // go without them. Every `T` gets the same operations, so the copy is
+    // only made once.
+    // SAFETY: `misc` is registered, so `as_raw()` points to its live `struct miscdevice`.
+    // `fops` is only written while no device uses it, before any open can see it.
+    unsafe {
+        let misc = misc.as_raw();
+        if (*fops).poll.is_none() {
+            fops.write(*(*misc).fops);
+            (*fops).poll = Some(poll);
+            if llseek.is_some() {
+                (*fops).llseek = llseek;
+            }
+        }
+        (*misc).fops = fops;
+    }
+}
+
+pub(crate) fn register_tetris_device(
+    inner: Arc<TetrisDeviceInner>,
+    name: &'static core::ffi::CStr,
+) -> Result<MiscRegistration<TetrisDevice, TetrisDeviceInner>> {
+    let misc = register_misc(
+        MiscDeviceOptions { name },
+        device_mode()?,
+        tetris_sysfs_groups(),
+        inner,
+    )?;
+    // SAFETY: `TETRIS_FOPS` is only used for game devices.
+    unsafe {
+        add_fops(
+            &misc.reg,
+            core::ptr::addr_of_mut!(TETRIS_FOPS).cast(),
+            tetris_poll,
+            Some(tetris_llseek),
+        )
+    };
+    Ok(misc)
+}
+
+pub(crate) fn register_tetris_events(
+    inner: Arc<TetrisDeviceInner>,
+    name: &'static core::ffi::CStr,
+) -> Result<MiscRegistration<TetrisEvents, TetrisDeviceInner>> {
+    let misc = register_misc(
+        MiscDeviceOptions { name },
+        device_mode()?,
+        core::ptr::null_mut(),
+        inner,
+    )?;
+    // SAFETY: `TETRIS_EVENTS_FOPS` is only used for event devices.
+    unsafe {
+        add_fops(
+            &misc.reg,
+            core::ptr::addr_of_mut!(TETRIS_EVENTS_FOPS).cast(),
+            tetris_events_poll,
+            None,
+        )
+    };
+    Ok(misc)
+}
+
+/// `/dev/tetris-control`, for starting and stopping numbered games. Root
+/// only, whatever `device_mode` says.
+pub(crate) fn register_tetris_control(
+    games: Arc<TetrisGames>,
+) -> Result<MiscRegistration<TetrisControl, TetrisGames>> {
+    register_misc(
+        MiscDeviceOptions {
+            name: c"tetris-control",
+        },
+        0,
+        core::ptr::null_mut(),
+        games,
+    )
+}
+
+/// An open `/dev/tetris-control`
+pub(crate) struct TetrisControl {
+    games: Arc<TetrisGames>,
+}
+
+#[vtable]
+impl MiscDevice for TetrisControl {
+    type Ptr = Arc<TetrisControl>;
+
+    fn open(_file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        Ok(Arc::new(
+            TetrisControl {
+                games: registered_data(misc),
+            },
+            GFP_KERNEL,
+        )?)
+    }
+
+    fn ioctl(
+        control: <Self::Ptr as ForeignOwnable>::Borrowed<'_>,
+        _file: &File,
+        cmd: u32,
+        arg: usize,
+    ) -> Result<isize> {
+        match cmd {
+            TETRIS_CONTROL_CREATE => Ok(control.games.create()? as isize),
+            TETRIS_CONTROL_LIST => Ok(control.games.running() as isize),
+            TETRIS_CONTROL_DESTROY => {
+                control.games.destroy(arg)?;
+                Ok(0)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+}
+
+// NOTE: `TetrisDevice` instances are created in `MiscDevice::open`.
+// (Removed unused `create_tetris_device` helper.)
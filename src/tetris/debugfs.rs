@@ -0,0 +1,755 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! The debugfs directory: state dumps and knobs for poking a game.
+
+use kernel::{cred::Kuid, debugfs, prelude::*, sync::Arc};
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{TetrisDeviceInner, TOTALS};
+use super::chardev::{EscapeState, WRITE_BATCH_MAX};
+use super::engine::{ktime_now_ns, BotWeight, KeyAction, DEFAULT_KEYMAP, REPLAY_BLOB_MAX};
+use super::render::{GameView, RENDER_BUFFER_SIZE};
+use super::rules::{Tetromino, TetrominoType, BOARD_MAX_HEIGHT, BOARD_MAX_WIDTH, HIDDEN_ROWS};
+
+/// Renders a read of debugfs `render_bench` times, until one is written.
+const RENDER_BENCH_DEFAULT: u32 = 100;
+
+/// Most renders `render_bench` can be asked to time.
+const RENDER_BENCH_MAX: u32 = 10000;
+
+#[allow(dead_code)]
+struct TetrisDebugState {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+struct TetrisDebugStats {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+#[allow(dead_code)]
+struct TetrisDebugStatsReset {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// The state as one line of JSON, for test harnesses. Keys are only ever
+/// added. `board` has a string per row, hidden rows first, of
+/// `Cell::letter()`s; the active piece is in `piece`, not on the board.
+struct TetrisDebugStateJson {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// The leaderboard, a line per player, best first: rank, uid, score and
+/// when it was set (seconds since the epoch).
+struct TetrisDebugLeaderboard {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// The key map; lists the bindings, and takes `<key> <action>`,
+/// `<key> none` or `default` (see `parse_debug_key()` for keys).
+struct TetrisDebugKeyMap {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// Takes keys as `/dev/tetris` does, to drive the game without opening it.
+/// Escape sequences and repeat counts end with each write.
+struct TetrisDebugInput {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// The visible stack as `#` and `.`, a line per row; writing a grid like
+/// it replaces the stack (see `TetrisGame::load_stack()`).
+struct TetrisDebugBoard {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// The seed the game was dealt from, in hex. Writing one (decimal, or hex
+/// with `0x`) starts a new game dealt from it, as `TETRIS_IOCTL_SET_SEED`.
+struct TetrisDebugSeed {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// Times the text render: a read renders the current frame `iterations`
+/// times and reports the fastest, mean and slowest in ns. Writing a
+/// number sets `iterations`. The game is locked for each render, not the
+/// whole run, so it plays on meanwhile.
+struct TetrisDebugRenderBench {
+    inner: Arc<TetrisDeviceInner>,
+    iterations: AtomicU32,
+}
+
+/// The game's replay, in the blob format of `TETRIS_IOCTL_GET_REPLAY`,
+/// to copy out with `cp`. Each read takes a fresh copy, so a game still
+/// being played may change between reads of a large replay.
+struct TetrisDebugReplay {
+    inner: Arc<TetrisDeviceInner>,
+}
+
+/// `TOTALS`, as `stats` shows a game's counters.
+pub(super) struct TetrisDebugTotals;
+
+/// `Tetromino::SHAPES` and the kick tables, as the game uses them: each
+/// piece's four rotations side by side, then its clockwise kicks out of
+/// each rotation, y up as in the guideline.
+pub(super) struct TetrisDebugPieces;
+
+/// One bot weight; reads and writes a decimal integer.
+struct TetrisDebugWeight {
+    inner: Arc<TetrisDeviceInner>,
+    weight: BotWeight,
+}
+
+impl core::fmt::Debug for TetrisDebugState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let render = *self.inner.render.lock();
+        let game = self.inner.game.lock();
+
+        writeln!(f, "score: {}", game.score)?;
+        writeln!(f, "level: {} (start {})", game.level, game.start_level)?;
+        writeln!(f, "lines: {}", game.lines)?;
+        writeln!(
+            f,
+            "gravity: {:?} ({} ns/row)",
+            game.gravity_curve,
+            game.gravity_period_ns()
+        )?;
+        writeln!(f, "instant_gravity: {}", game.instant_gravity)?;
+        writeln!(f, "invisible: {}", render.invisible)?;
+        writeln!(f, "color: {}", render.color)?;
+        writeln!(f, "clear_screen: {}", render.clear_screen)?;
+        writeln!(f, "ascii: {}", render.ascii)?;
+        writeln!(f, "size: {}x{}", game.width, game.height)?;
+        writeln!(f, "big: {}", game.big)?;
+        writeln!(f, "cascade: {}", game.cascade)?;
+        writeln!(f, "randomizer: {:?}", game.bag.randomizer)?;
+        writeln!(f, "paused: {}", game.paused())?;
+        writeln!(f, "demo: {}", game.demo)?;
+        writeln!(f, "autopilot_ms: {}", game.autopilot_ms)?;
+        writeln!(f, "bot_weights: {:?}", game.bot_weights)?;
+        writeln!(
+            f,
+            "owner: {:?}",
+            game.owner.map(|uid| uid.into_uid_in_current_ns())
+        )?;
+        writeln!(
+            f,
+            "high_score: {} uid: {:?}",
+            game.high_score,
+            game.high_score_uid.map(|uid| uid.into_uid_in_current_ns())
+        )?;
+        writeln!(
+            f,
+            "seed: {:#x} replay: {}/{} inputs{}",
+            game.seed,
+            game.replay_len,
+            game.replay.len(),
+            if game.replay_truncated { " (truncated)" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "replay_speed: {}% playback: {} verdict: {:?}",
+            game.replay_speed,
+            game.playback
+                .as_ref()
+                .map_or(0, |p| p.len - p.next),
+            game.replay_verdict
+        )?;
+        for (name, counts) in [("dealt", &game.dealt), ("placed", &game.placed)] {
+            write!(f, "{}:", name)?;
+            for piece in TetrominoType::ALL {
+                write!(f, " {:?}={}", piece, counts[piece.index()])?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "are_ns: {}", game.are_ns)?;
+        writeln!(
+            f,
+            "das_ns: {} arr_ns: {} shift_held: {:?}",
+            game.das_ns, game.arr_ns, game.shift_held
+        )?;
+        writeln!(
+            f,
+            "spawn_pending: {} (buffered inputs: {:?})",
+            game.spawn_at_ns.is_some(),
+            &game.input_buffer[..game.input_buffer_len]
+        )?;
+        writeln!(
+            f,
+            "clear_delay_ns: {} flash_rows: {:#x}",
+            game.clear_delay_ns, game.flash_rows
+        )?;
+        writeln!(f, "chain: {}", game.chain)?;
+        writeln!(f, "garbage_pending: {}", game.garbage_pending)?;
+        writeln!(f, "attack_out: {}", game.attack_out)?;
+        writeln!(f, "soft_drop_score: {}", game.soft_drop_score)?;
+        writeln!(f, "hard_drop_score: {}", game.hard_drop_score)?;
+        writeln!(f, "last_action: {:?}", game.last_action)?;
+        writeln!(f, "last_tspin: {:?}", game.last_tspin)?;
+        writeln!(f, "combo: {} (max {})", game.combo, game.max_combo)?;
+        writeln!(f, "perfect_clear: {}", game.perfect_clear)?;
+        writeln!(f, "game_over: {}", game.game_over)?;
+        writeln!(f, "mode: {:?}", game.mode)?;
+        writeln!(f, "end_reason: {:?}", game.end_reason)?;
+        let elapsed = game.elapsed_ns();
+        let secs = elapsed / 1_000_000_000;
+        writeln!(
+            f,
+            "elapsed: {:02}:{:02} ({} ns)",
+            secs / 60,
+            secs % 60,
+            elapsed
+        )?;
+        if let Some(ns) = game.sprint_time_ns() {
+            writeln!(f, "sprint_time_ns: {}", ns)?;
+        }
+        writeln!(
+            f,
+            "next_pieces: {:?} (showing {})",
+            &game.preview[..], game.preview_count
+        )?;
+        writeln!(f, "hold: {:?} (used {})", game.hold, game.hold_used)?;
+
+        match game.current_piece {
+            Some(p) => {
+                writeln!(
+                    f,
+                    "current_piece: type={:?} x={} y={} rotation={}",
+                    p.piece_type, p.x, p.y, p.rotation
+                )?;
+            }
+            None => {
+                writeln!(f, "current_piece: (none)")?;
+            }
+        }
+
+        writeln!(f, "board ({} hidden rows on top):", HIDDEN_ROWS)?;
+        for y in 0..game.rows() {
+            for x in 0..game.cols() {
+                write!(f, "{}", game.board[y][x].letter() as char)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugStateJson {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let game = self.inner.game.lock();
+        let letter = |piece: TetrominoType| piece.letter() as char;
+
+        write!(
+            f,
+            "{{\"score\":{},\"level\":{},\"lines\":{},\"state\":\"{}\"",
+            game.score,
+            game.level,
+            game.lines,
+            game.state_name()
+        )?;
+        write!(
+            f,
+            ",\"width\":{},\"height\":{},\"hidden_rows\":{}",
+            game.cols(),
+            game.rows() - HIDDEN_ROWS,
+            HIDDEN_ROWS
+        )?;
+        match game.current_piece {
+            Some(p) => write!(
+                f,
+                ",\"piece\":{{\"type\":\"{}\",\"x\":{},\"y\":{},\"rotation\":{}}}",
+                letter(p.piece_type),
+                p.x,
+                p.y,
+                p.rotation
+            )?,
+            None => write!(f, ",\"piece\":null")?,
+        }
+        match game.hold {
+            Some(piece) => write!(f, ",\"hold\":\"{}\"", letter(piece))?,
+            None => write!(f, ",\"hold\":null")?,
+        }
+        write!(f, ",\"next\":[")?;
+        for (i, &piece) in game.preview[..game.preview_count].iter().enumerate() {
+            let comma = if i > 0 { "," } else { "" };
+            write!(f, "{}\"{}\"", comma, letter(piece))?;
+        }
+        write!(f, "],\"board\":[")?;
+        for y in 0..game.rows() {
+            write!(f, "{}\"", if y > 0 { "," } else { "" })?;
+            for x in 0..game.cols() {
+                write!(f, "{}", game.board[y][x].letter() as char)?;
+            }
+            write!(f, "\"")?;
+        }
+        // debugfs ends the line.
+        write!(f, "]}}")
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugLeaderboard {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let game = self.inner.game.lock();
+        for (rank, entry) in game.leaderboard.iter().flatten().enumerate() {
+            writeln!(
+                f,
+                "{} {} {} {}",
+                rank + 1,
+                entry.uid.into_uid_in_current_ns(),
+                entry.score,
+                entry.time
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = &self.inner.stats;
+
+        // key=value per line for easy scripting.
+        writeln!(f, "uptime_ns={}", s.uptime_ns())?;
+
+        writeln!(f, "opens={}", s.opens.load(Ordering::Relaxed))?;
+        writeln!(f, "reads={}", s.reads.load(Ordering::Relaxed))?;
+        writeln!(f, "bytes_read={}", s.bytes_read.load(Ordering::Relaxed))?;
+        writeln!(f, "frames_rendered={}", s.frames_rendered.load(Ordering::Relaxed))?;
+        writeln!(f, "frames_cached={}", s.frames_cached.load(Ordering::Relaxed))?;
+        writeln!(f, "writes={}", s.writes.load(Ordering::Relaxed))?;
+        writeln!(f, "bytes_written={}", s.bytes_written.load(Ordering::Relaxed))?;
+        writeln!(f, "ioctls={}", s.ioctls.load(Ordering::Relaxed))?;
+        writeln!(f, "invalid_ioctls={}", s.invalid_ioctls.load(Ordering::Relaxed))?;
+        writeln!(f, "gravity_ticks={}", s.gravity_ticks.load(Ordering::Relaxed))?;
+        writeln!(f, "invalid_inputs={}", s.invalid_inputs.load(Ordering::Relaxed))?;
+
+        writeln!(f, "games_played={}", s.games_played.load(Ordering::Relaxed))?;
+        writeln!(f, "resets={}", s.resets.load(Ordering::Relaxed))?;
+        writeln!(f, "pieces_spawned={}", s.pieces_spawned.load(Ordering::Relaxed))?;
+        writeln!(f, "pieces_locked={}", s.pieces_locked.load(Ordering::Relaxed))?;
+        writeln!(f, "lines_cleared={}", s.lines_cleared.load(Ordering::Relaxed))?;
+        writeln!(f, "score_gained={}", s.score_gained.load(Ordering::Relaxed))?;
+        writeln!(f, "soft_drop_cells={}", s.soft_drop_cells.load(Ordering::Relaxed))?;
+        writeln!(f, "hard_drop_cells={}", s.hard_drop_cells.load(Ordering::Relaxed))?;
+        writeln!(f, "tspins={}", s.tspins.load(Ordering::Relaxed))?;
+        writeln!(f, "tspin_minis={}", s.tspin_minis.load(Ordering::Relaxed))?;
+        writeln!(f, "perfect_clears={}", s.perfect_clears.load(Ordering::Relaxed))?;
+        writeln!(f, "sprints_completed={}", s.sprints_completed.load(Ordering::Relaxed))?;
+        writeln!(f, "sprint_best_ns={}", s.sprint_best_ns.load(Ordering::Relaxed))?;
+        writeln!(f, "garbage_sent={}", s.garbage_sent.load(Ordering::Relaxed))?;
+        writeln!(f, "garbage_received={}", s.garbage_received.load(Ordering::Relaxed))?;
+        writeln!(f, "auto_shifts={}", s.auto_shifts.load(Ordering::Relaxed))?;
+        writeln!(f, "demo_games={}", s.demo_games.load(Ordering::Relaxed))?;
+        writeln!(f, "games_abandoned={}", s.games_abandoned.load(Ordering::Relaxed))?;
+
+        writeln!(f, "left={}", s.left.load(Ordering::Relaxed))?;
+        writeln!(f, "left_ok={}", s.left_ok.load(Ordering::Relaxed))?;
+        writeln!(f, "right={}", s.right.load(Ordering::Relaxed))?;
+        writeln!(f, "right_ok={}", s.right_ok.load(Ordering::Relaxed))?;
+        writeln!(f, "down={}", s.down.load(Ordering::Relaxed))?;
+        writeln!(f, "down_ok={}", s.down_ok.load(Ordering::Relaxed))?;
+        writeln!(f, "rotate={}", s.rotate.load(Ordering::Relaxed))?;
+        writeln!(f, "rotate_ok={}", s.rotate_ok.load(Ordering::Relaxed))?;
+        writeln!(f, "drop={}", s.drop.load(Ordering::Relaxed))?;
+
+        // Include a couple of live game fields for correlation.
+        let game = self.inner.game.lock();
+        writeln!(f, "current_score={}", game.score)?;
+        writeln!(f, "current_level={}", game.level)?;
+        writeln!(f, "game_over={}", game.game_over)?;
+
+        Ok(())
+    }
+}
+
+impl debugfs::BinaryWriter for TetrisDebugReplay {
+    fn write_to_slice(
+        &self,
+        writer: &mut kernel::uaccess::UserSliceWriter,
+        offset: &mut kernel::fs::file::Offset,
+    ) -> Result<usize> {
+        let mut blob = KVVec::with_capacity(REPLAY_BLOB_MAX, GFP_KERNEL)?;
+        self.inner.game.lock().replay_blob(&mut blob)?;
+        writer.write_slice_file(&blob, offset)
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugTotals {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let t = &TOTALS;
+        writeln!(f, "games_played={}", t.games_played.load(Ordering::Relaxed))?;
+        writeln!(f, "lines_cleared={}", t.lines_cleared.load(Ordering::Relaxed))?;
+        writeln!(f, "pieces_locked={}", t.pieces_locked.load(Ordering::Relaxed))?;
+        writeln!(f, "hard_drops={}", t.hard_drops.load(Ordering::Relaxed))?;
+        writeln!(f, "resets={}", t.resets.load(Ordering::Relaxed))?;
+        writeln!(f, "ioctls={}", t.ioctls.load(Ordering::Relaxed))?;
+        writeln!(f, "reads={}", t.reads.load(Ordering::Relaxed))?;
+        writeln!(f, "writes={}", t.writes.load(Ordering::Relaxed))?;
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugPieces {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const STATES: [&str; 4] = ["0", "R", "2", "L"];
+        for piece_type in TetrominoType::ALL {
+            writeln!(f, "{}:  0    R    2    L", piece_type.letter() as char)?;
+            let shapes = &Tetromino::SHAPES[piece_type.index()];
+            for row in 0..4 {
+                write!(f, "  ")?;
+                for (r, rotation) in shapes.rotations.iter().enumerate() {
+                    if r > 0 {
+                        write!(f, " ")?;
+                    }
+                    for &filled in &rotation[row] {
+                        write!(f, "{}", if filled { '#' } else { '.' })?;
+                    }
+                }
+                writeln!(f)?;
+            }
+            for (from, kicks) in Tetromino::kick_table(piece_type).iter().enumerate() {
+                write!(f, "  {}->{}:", STATES[from], STATES[(from + 1) % 4])?;
+                for &(x, y) in kicks {
+                    write!(f, " ({},{})", x, y)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugStatsReset {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "write any value to reset counters")
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugWeight {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let game = self.inner.game.lock();
+        writeln!(f, "{}", game.bot_weights.get(self.weight))
+    }
+}
+
+impl debugfs::Reader for TetrisDebugWeight {
+    fn read_from_slice(&self, reader: &mut kernel::uaccess::UserSliceReader) -> Result {
+        let mut buf = [0u8; 16];
+        let len = reader.len();
+        if len > buf.len() {
+            return Err(EINVAL);
+        }
+        reader.read_slice(&mut buf[..len])?;
+        let value = core::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|text| text.trim().parse::<i32>().ok())
+            .ok_or(EINVAL)?;
+
+        *self.inner.game.lock().bot_weights.get_mut(self.weight) = value;
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugSeed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "{:#x}", self.inner.game.lock().seed)
+    }
+}
+
+impl debugfs::Reader for TetrisDebugSeed {
+    fn read_from_slice(&self, reader: &mut kernel::uaccess::UserSliceReader) -> Result {
+        let mut buf = [0u8; 24];
+        let len = reader.len();
+        if len > buf.len() {
+            return Err(EINVAL);
+        }
+        reader.read_slice(&mut buf[..len])?;
+        let seed = core::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|text| {
+                let text = text.trim();
+                match text.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => text.parse::<u64>().ok(),
+                }
+            })
+            .ok_or(EINVAL)?;
+
+        let mut game = self.inner.lock_game();
+        self.inner.stats.resets.fetch_add(1, Ordering::Relaxed);
+        game.restart(&self.inner.stats, seed);
+        game.frame_changed();
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugRenderBench {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let iterations = self.iterations.load(Ordering::Relaxed);
+        let mut buffer = KVec::new();
+        if buffer.resize(RENDER_BUFFER_SIZE, 0, GFP_KERNEL).is_err() {
+            return Err(core::fmt::Error);
+        }
+
+        let (mut min_ns, mut max_ns, mut total_ns) = (u64::MAX, 0, 0);
+        let mut len = 0;
+        let options = self.inner.render.lock();
+        for _ in 0..iterations {
+            let view = GameView::new(&self.inner.game.lock());
+            let start_ns = ktime_now_ns();
+            len = view.render_to_buffer(&options, &mut buffer);
+            let elapsed_ns = ktime_now_ns().saturating_sub(start_ns);
+
+            min_ns = min_ns.min(elapsed_ns);
+            max_ns = max_ns.max(elapsed_ns);
+            total_ns += elapsed_ns;
+        }
+
+        writeln!(f, "iterations={iterations}")?;
+        writeln!(f, "bytes={len}")?;
+        writeln!(f, "min_ns={min_ns}")?;
+        writeln!(f, "avg_ns={}", total_ns / iterations as u64)?;
+        writeln!(f, "max_ns={max_ns}")
+    }
+}
+
+impl debugfs::Reader for TetrisDebugRenderBench {
+    fn read_from_slice(&self, reader: &mut kernel::uaccess::UserSliceReader) -> Result {
+        let mut buf = [0u8; 16];
+        let len = reader.len();
+        if len > buf.len() {
+            return Err(EINVAL);
+        }
+        reader.read_slice(&mut buf[..len])?;
+        let iterations = core::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|text| text.trim().parse::<u32>().ok())
+            .filter(|iterations| (1..=RENDER_BENCH_MAX).contains(iterations))
+            .ok_or(EINVAL)?;
+        self.iterations.store(iterations, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugKeyMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let keymap = self.inner.game.lock().keymap;
+        for key in 0..=u8::MAX {
+            let Some(action) = keymap.action(key) else {
+                continue;
+            };
+            match key {
+                b' ' => write!(f, "space")?,
+                0x21..=0x7e => write!(f, "{}", key as char)?,
+                _ => write!(f, "0x{key:02x}")?,
+            }
+            writeln!(f, " {}", action.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// A key as the debugfs `keymap` file names it: a printable character,
+/// `space`, or a byte in hex such as `0x09`.
+fn parse_debug_key(name: &str) -> Option<u8> {
+    match name.as_bytes() {
+        [key @ 0x21..=0x7e] => Some(*key),
+        _ if name == "space" => Some(b' '),
+        _ => u8::from_str_radix(name.strip_prefix("0x")?, 16).ok(),
+    }
+}
+
+impl debugfs::Reader for TetrisDebugKeyMap {
+    fn read_from_slice(&self, reader: &mut kernel::uaccess::UserSliceReader) -> Result {
+        let mut buf = [0u8; 32];
+        let len = reader.len();
+        if len > buf.len() {
+            return Err(EINVAL);
+        }
+        reader.read_slice(&mut buf[..len])?;
+        let text = core::str::from_utf8(&buf[..len]).map_err(|_| EINVAL)?.trim();
+
+        if text == "default" {
+            self.inner.game.lock().keymap = DEFAULT_KEYMAP;
+            return Ok(());
+        }
+        let (key, action) = text.split_once(' ').ok_or(EINVAL)?;
+        let key = parse_debug_key(key).ok_or(EINVAL)?;
+        let action = match action.trim() {
+            "none" => None,
+            name => Some(KeyAction::from_name(name).ok_or(EINVAL)?),
+        };
+        self.inner.game.lock().keymap.bind(key, action)
+    }
+}
+
+impl debugfs::Reader for TetrisDebugInput {
+    fn read_from_slice(&self, reader: &mut kernel::uaccess::UserSliceReader) -> Result {
+        let mut buffer = [0u8; WRITE_BATCH_MAX];
+        let mut escape = EscapeState::Ground;
+        let mut repeat = 0;
+        while !reader.is_empty() {
+            let len = reader.len().min(buffer.len());
+            reader.read_slice(&mut buffer[..len])?;
+            self.inner
+                .write_keys(&buffer[..len], Kuid::current_euid(), &mut escape, &mut repeat);
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for TetrisDebugBoard {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let game = self.inner.game.lock();
+        for y in HIDDEN_ROWS..game.rows() {
+            for x in 0..game.cols() {
+                let filled = game.board[y][x].is_filled();
+                write!(f, "{}", if filled { '#' } else { '.' })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl debugfs::Reader for TetrisDebugBoard {
+    fn read_from_slice(&self, reader: &mut kernel::uaccess::UserSliceReader) -> Result {
+        let mut buf = [0u8; (BOARD_MAX_WIDTH + 1) * BOARD_MAX_HEIGHT];
+        let len = reader.len();
+        if len > buf.len() {
+            return Err(EINVAL);
+        }
+        reader.read_slice(&mut buf[..len])?;
+        let mut game = self.inner.lock_game();
+        game.load_stack(&self.inner.stats, &buf[..len])
+    }
+}
+
+// NOTE: debugfs provides a blanket `Writer` impl for any `Debug` type.
+// We intentionally avoid a custom `Writer` impl here to prevent conflicting implementations.
+
+pub(crate) struct TetrisDebugFs {
+    _dir: debugfs::Dir,
+    _state_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugState>>>,
+    _state_json_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugStateJson>>>,
+    _stats_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugStats>>>,
+    _stats_reset_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugStatsReset>>>,
+    _leaderboard_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugLeaderboard>>>,
+    _keymap_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugKeyMap>>>,
+    _input_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugInput>>>,
+    _board_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugBoard>>>,
+    _seed_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugSeed>>>,
+    _replay_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugReplay>>>,
+    _render_bench_file: Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugRenderBench>>>,
+    _ai_dir: debugfs::Dir,
+    _ai_files: [Pin<kernel::alloc::KBox<kernel::debugfs::File<TetrisDebugWeight>>>; 4],
+}
+
+/// Create the debugfs files for `inner`'s game in `dir`.
+pub(crate) fn register_tetris_debugfs(
+    inner: Arc<TetrisDeviceInner>,
+    dir: debugfs::Dir,
+) -> Result<TetrisDebugFs> {
+
+    let _state_file = kernel::alloc::KBox::pin_init(
+        dir.read_only_file(c"state", TetrisDebugState { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _state_json_file = kernel::alloc::KBox::pin_init(
+        dir.read_only_file(c"state.json", TetrisDebugStateJson { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _stats_file = kernel::alloc::KBox::pin_init(
+        dir.read_only_file(c"stats", TetrisDebugStats { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _stats_reset_file = kernel::alloc::KBox::pin_init(
+        dir.read_only_file(c"stats_reset", TetrisDebugStatsReset { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _leaderboard_file = kernel::alloc::KBox::pin_init(
+        dir.read_only_file(c"leaderboard", TetrisDebugLeaderboard { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _keymap_file = kernel::alloc::KBox::pin_init(
+        dir.read_write_file(c"keymap", TetrisDebugKeyMap { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _input_file = kernel::alloc::KBox::pin_init(
+        dir.write_only_file(c"input", TetrisDebugInput { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _board_file = kernel::alloc::KBox::pin_init(
+        dir.read_write_file(c"board", TetrisDebugBoard { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _seed_file = kernel::alloc::KBox::pin_init(
+        dir.read_write_file(c"seed", TetrisDebugSeed { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _replay_file = kernel::alloc::KBox::pin_init(
+        dir.read_binary_file(c"replay", TetrisDebugReplay { inner: inner.clone() }),
+        GFP_KERNEL,
+    )?;
+
+    let _render_bench_file = kernel::alloc::KBox::pin_init(
+        dir.read_write_file(
+            c"render_bench",
+            TetrisDebugRenderBench {
+                inner: inner.clone(),
+                iterations: AtomicU32::new(RENDER_BENCH_DEFAULT),
+            },
+        ),
+        GFP_KERNEL,
+    )?;
+
+    let ai_dir = dir.subdir(c"ai");
+    let weight_file = |name, weight| {
+        kernel::alloc::KBox::pin_init(
+            ai_dir.read_write_file(
+                name,
+                TetrisDebugWeight {
+                    inner: inner.clone(),
+                    weight,
+                },
+            ),
+            GFP_KERNEL,
+        )
+    };
+    let _ai_files = [
+        weight_file(c"lines", BotWeight::Lines)?,
+        weight_file(c"height", BotWeight::Height)?,
+        weight_file(c"holes", BotWeight::Holes)?,
+        weight_file(c"bumpiness", BotWeight::Bumpiness)?,
+    ];
+
+    Ok(TetrisDebugFs {
+        _dir: dir,
+        _state_file,
+        _state_json_file,
+        _stats_file,
+        _stats_reset_file,
+        _leaderboard_file,
+        _keymap_file,
+        _input_file,
+        _board_file,
+        _seed_file,
+        _replay_file,
+        _render_bench_file,
+        _ai_dir: ai_dir,
+        _ai_files,
+    })
+}
+
+pub(crate) fn unregister_tetris_debugfs() {
+    // Everything is RAII via module fields now.
+}
@@ -0,0 +1,2533 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! The game itself, played by the rules in `rules`: timing, holds, garbage,
+//! the bot and the replay and save formats. Nothing in here draws the game
+//! or knows about files.
+
+use kernel::{bindings, cred::Kuid, prelude::*, time};
+
+use core::sync::atomic::Ordering;
+
+use super::{
+    sysctl_ns, tetris_sysctl_arr_ms, tetris_sysctl_das_ms, tetris_sysctl_gravity_base_ms,
+    tetris_sysctl_lock_delay_ms, TetrisStats,
+};
+use super::rules::{
+    attack, level_for, lock_score, next_combo, Bag, Board, Cell, LastAction,
+    Randomizer, Rotation, TSpin, Tetromino, TetrominoType, BOARD_MAX_HEIGHT, BOARD_MAX_WIDTH,
+    BOARD_MAX_ROWS, HIDDEN_ROWS, PRNG,
+};
+
+/// Default board size; `TETRIS_IOCTL_SET_SIZE` can change it per game.
+const BOARD_WIDTH: usize = 10;
+
+const BOARD_HEIGHT: usize = 20;
+
+/// Smallest logical board (after big mode scaling) a game can use.
+const BOARD_MIN_CELLS: usize = 4;
+
+/// Upper bound on the number of upcoming pieces shown to the player.
+pub(super) const PREVIEW_MAX: usize = 5;
+
+const PREVIEW_DEFAULT: usize = 3;
+
+/// Most lines a loaded game can claim: more than anyone clears, and far
+/// enough short of `u32::MAX` that clearing more can't overflow.
+const LINES_MAX: u32 = 1 << 30;
+
+/// Most pieces a loaded game can claim to have been dealt, for the same
+/// reason.
+const PIECES_MAX: u64 = 1 << 30;
+
+/// Highest level a game can be started at (the end of the NES curve).
+pub(super) const START_LEVEL_MAX: u32 = 30;
+
+/// Gravity period in milliseconds per level (guideline curve,
+/// `(0.8 - (level - 1) * 0.007) ^ (level - 1)` seconds per row). Levels past
+/// the end of the table keep the last entry.
+const GRAVITY_MS: [u32; 15] = [
+    1000, 793, 618, 473, 355, 262, 190, 135, 94, 64, 43, 28, 18, 11, 7,
+];
+
+/// NES Tetris frames per row for NES levels 0..=29 (our level 1 is NES level
+/// 0). Level 29 and beyond is the one-frame "kill screen" speed.
+const NES_GRAVITY_FRAMES: [u8; 30] = [
+    48, 43, 38, 33, 28, 23, 18, 13, 8, 6, // 0-9
+    5, 5, 5, 4, 4, 4, 3, 3, 3, 2, // 10-19
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 1, // 20-29
+];
+
+/// Length of one NTSC NES frame (60.0988 Hz) in nanoseconds.
+const NES_FRAME_NS: u64 = 16_639_267;
+
+/// Sprint ends once this many lines are cleared.
+pub(super) const SPRINT_LINES: u32 = 40;
+
+/// Ultra ends after this long.
+const ULTRA_NS: u64 = 120 * 1_000_000_000;
+
+/// Longest entry delay `TETRIS_IOCTL_SET_ARE` accepts, in ms.
+pub(super) const ARE_MAX_MS: usize = 1000;
+
+/// Longest line clear delay `TETRIS_IOCTL_SET_CLEAR_DELAY` accepts, in ms.
+pub(super) const CLEAR_DELAY_MAX_MS: usize = 1000;
+
+/// Inputs remembered while waiting for the next piece.
+const INPUT_BUFFER_LEN: usize = 8;
+
+/// Longest DAS or ARR `TETRIS_IOCTL_SET_DAS`/`TETRIS_IOCTL_SET_ARR` accept.
+pub(super) const AUTOSHIFT_MAX_MS: usize = 1000;
+
+/// Inputs a game can record for replay; later ones are dropped and the
+/// replay is flagged as truncated.
+const REPLAY_MAX_EVENTS: usize = 32768;
+
+/// Replay blob format, bumped on any layout change.
+const REPLAY_VERSION: u16 = 1;
+
+/// Players a game's leaderboard keeps.
+const LEADERBOARD_LEN: usize = 10;
+
+/// Size of the replay blob header in front of the events.
+const REPLAY_HEADER_LEN: usize = 40;
+
+/// Largest replay blob, of a replay that filled up.
+pub(super) const REPLAY_BLOB_MAX: usize = REPLAY_HEADER_LEN + 4 * REPLAY_MAX_EVENTS;
+
+/// Largest time step a replay event can encode, in ms.
+const REPLAY_DELTA_MAX: u64 = (1 << 28) - 1;
+
+/// Saved game format, bumped on any layout change. Version 1 saves,
+/// from before hold, still load.
+const STATE_VERSION: u16 = 2;
+
+/// Largest saved game `TETRIS_IOCTL_LOAD_STATE` accepts: the fixed fields
+/// plus a byte per cell of the biggest board.
+pub(super) const STATE_MAX_LEN: usize = 256 + BOARD_MAX_ROWS * BOARD_MAX_WIDTH;
+
+/// Largest `TETRIS_IOCTL_GET_BOARD` blob: the header, then a byte per cell.
+pub(super) const BOARD_BLOB_MAX: usize = 4 + BOARD_MAX_ROWS * BOARD_MAX_WIDTH;
+
+/// Events a game keeps for `/dev/tetris-events` readers; one that falls
+/// further behind loses the oldest.
+pub(super) const EVENT_RING_LEN: usize = 256;
+
+/// Size of a `/dev/tetris-events` record (see `GameEvent::to_bytes()`).
+pub(super) const EVENT_RECORD_LEN: usize = 24;
+
+/// Weights the bot scores a placement with, per line cleared and per unit
+/// of aggregate column height, covered holes and bumpiness.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct BotWeights {
+    lines: i32,
+    height: i32,
+    holes: i32,
+    bumpiness: i32,
+}
+
+/// One of the `BotWeights`, as exposed in debugfs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum BotWeight {
+    Lines,
+    Height,
+    Holes,
+    Bumpiness,
+}
+
+impl BotWeights {
+    pub(super) fn get(&self, weight: BotWeight) -> i32 {
+        match weight {
+            BotWeight::Lines => self.lines,
+            BotWeight::Height => self.height,
+            BotWeight::Holes => self.holes,
+            BotWeight::Bumpiness => self.bumpiness,
+        }
+    }
+
+    pub(super) fn get_mut(&mut self, weight: BotWeight) -> &mut i32 {
+        match weight {
+            BotWeight::Lines => &mut self.lines,
+            BotWeight::Height => &mut self.height,
+            BotWeight::Holes => &mut self.holes,
+            BotWeight::Bumpiness => &mut self.bumpiness,
+        }
+    }
+}
+
+/// Starting weights, hand-tuned to clear lines steadily rather than score
+/// well. Tunable at runtime under `tetris/ai/` in debugfs.
+const BOT_WEIGHTS: BotWeights = BotWeights {
+    lines: 760,
+    height: -510,
+    holes: -356,
+    bumpiness: -184,
+};
+
+// Movement ioctls (LEFT, RIGHT, DOWN, ROTATE, ROTATE_CCW, DROP, SOFT_DROP,
+// SHIFT_PRESS) return these bits; a clear MOVED bit means the input was
+// blocked.
+/// The piece moved, rotated or dropped.
+const TETRIS_RESULT_MOVED: isize = 1 << 0;
+
+/// The input locked a piece.
+const TETRIS_RESULT_LOCKED: isize = 1 << 1;
+
+/// The game is over after this input.
+const TETRIS_RESULT_GAME_OVER: isize = 1 << 2;
+
+/// Lines cleared by the lock, in bits 8..16.
+const TETRIS_RESULT_LINES_SHIFT: u32 = 8;
+
+/// Cells dropped, in bits 16..24 (drops only).
+const TETRIS_RESULT_CELLS_SHIFT: u32 = 16;
+
+/// Most garbage lines that can be waiting at once.
+pub(super) const GARBAGE_MAX: u32 = BOARD_MAX_HEIGHT as u32;
+
+pub(super) fn ktime_now_ns() -> u64 {
+    <time::Monotonic as time::ClockSource>::ktime_get() as u64
+}
+
+/// Rule set deciding how a game ends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum GameMode {
+    /// Endless; only topping out ends the game.
+    Marathon,
+    /// Clear `SPRINT_LINES` lines as fast as possible.
+    Sprint,
+    /// Score as much as possible within `ULTRA_NS`.
+    Ultra,
+}
+
+impl GameMode {
+    pub(super) fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(GameMode::Marathon),
+            1 => Some(GameMode::Sprint),
+            2 => Some(GameMode::Ultra),
+            _ => None,
+        }
+    }
+
+    pub(super) fn name(self) -> &'static [u8] {
+        match self {
+            GameMode::Marathon => b"Marathon",
+            GameMode::Sprint => b"Sprint",
+            GameMode::Ultra => b"Ultra",
+        }
+    }
+}
+
+/// Why a finished game ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum EndReason {
+    /// A new piece could not spawn (block out).
+    BlockOut,
+    /// A piece locked entirely above the visible field.
+    LockOut,
+    /// Garbage pushed the stack out through the top of the hidden rows.
+    TopOut,
+    /// Sprint goal reached.
+    Cleared,
+    /// Ultra time limit reached.
+    TimeUp,
+    /// The player ended it (`TETRIS_IOCTL_END`).
+    Abandoned,
+}
+
+/// What a `/dev/tetris-events` record reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GameEventKind {
+    /// A new piece entered the board.
+    Spawned = 0,
+    /// The active piece shifted, rotated or fell.
+    Moved = 1,
+    /// The active piece locked into the stack.
+    Locked = 2,
+    /// A lock cleared `value` lines.
+    LinesCleared = 3,
+    /// The game reached level `value`.
+    LevelUp = 4,
+    /// The game ended; `value` is the `EndReason` plus one.
+    GameOver = 5,
+}
+
+/// Something that happened in a game, as `/dev/tetris-events` streams it
+#[derive(Clone, Copy)]
+struct GameEvent {
+    /// Monotonic time the event happened at, in ns.
+    time_ns: u64,
+    kind: GameEventKind,
+    /// The piece involved, if the event is about one.
+    piece: Option<Tetromino>,
+    value: u32,
+}
+
+impl GameEvent {
+    const NONE: Self = GameEvent {
+        time_ns: 0,
+        kind: GameEventKind::Spawned,
+        piece: None,
+        value: 0,
+    };
+
+    /// Encode as the `seq`th record of the stream, little endian:
+    ///
+    /// ```text
+    ///  0  u64 CLOCK_MONOTONIC time in ns
+    ///  8  u32 sequence number, low bits; a gap means events were lost
+    /// 12  u32 value (lines cleared, new level, end reason + 1, else 0)
+    /// 16  u8 type (`GameEventKind`), piece (0xff = none), rotation, 0
+    /// 20  i8 x, i8 y, u8 reserved[2]
+    /// ```
+    ///
+    /// `x`/`y` place the top left of the piece's 4x4 shape matrix on the
+    /// board, hidden rows included, as in binary frames.
+    fn to_bytes(&self, seq: u64) -> [u8; EVENT_RECORD_LEN] {
+        let mut record = [0u8; EVENT_RECORD_LEN];
+        record[0..8].copy_from_slice(&self.time_ns.to_le_bytes());
+        record[8..12].copy_from_slice(&(seq as u32).to_le_bytes());
+        record[12..16].copy_from_slice(&self.value.to_le_bytes());
+        record[16] = self.kind as u8;
+        match self.piece {
+            Some(piece) => {
+                record[17] = piece.piece_type.index() as u8;
+                record[18] = piece.rotation % 4;
+                record[20] = piece.x as i8 as u8;
+                record[21] = piece.y as i8 as u8;
+            }
+            None => record[17] = 0xff,
+        }
+        record
+    }
+}
+
+/// Level-to-speed curve used by the gravity tick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum GravityCurve {
+    /// Tetris guideline curve, see `GRAVITY_MS`.
+    Modern,
+    /// Classic NES frame counts, see `NES_GRAVITY_FRAMES`.
+    Nes,
+}
+
+impl GravityCurve {
+    pub(super) fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(GravityCurve::Modern),
+            1 => Some(GravityCurve::Nes),
+            _ => None,
+        }
+    }
+
+    /// Time between gravity steps at `level` (1-based).
+    pub(super) fn period_ns(self, level: u32) -> u64 {
+        match self {
+            GravityCurve::Modern => {
+                let idx = (level as usize).clamp(1, GRAVITY_MS.len()) - 1;
+                GRAVITY_MS[idx] as u64 * 1_000_000
+            }
+            GravityCurve::Nes => {
+                let idx = (level as usize).clamp(1, NES_GRAVITY_FRAMES.len()) - 1;
+                NES_GRAVITY_FRAMES[idx] as u64 * NES_FRAME_NS
+            }
+        }
+    }
+}
+
+/// Direction held down for auto shift
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Shift {
+    Left,
+    Right,
+}
+
+impl Shift {
+    pub(super) fn from_raw(raw: usize) -> Option<Self> {
+        match raw {
+            0 => Some(Shift::Left),
+            1 => Some(Shift::Right),
+            _ => None,
+        }
+    }
+}
+
+/// Input recorded in a replay, stored as its discriminant
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub(super) enum ReplayInput {
+    Left = 0,
+    Right = 1,
+    /// One row down, as `TETRIS_IOCTL_DOWN`.
+    Down = 2,
+    SoftDrop = 3,
+    SoftDropToFloor = 4,
+    RotateCw = 5,
+    RotateCcw = 6,
+    HardDrop = 7,
+    /// A gravity tick moved the piece down or locked it.
+    Gravity = 8,
+    Pause = 9,
+    Resume = 10,
+    Hold = 11,
+    End = 12,
+    /// A cascade settled. That is done outside the game lock, whenever the
+    /// slow path gets to it, so playback has to be told when.
+    Settle = 13,
+}
+
+impl ReplayInput {
+    fn from_raw(raw: u32) -> Option<Self> {
+        Some(match raw {
+            0 => ReplayInput::Left,
+            1 => ReplayInput::Right,
+            2 => ReplayInput::Down,
+            3 => ReplayInput::SoftDrop,
+            4 => ReplayInput::SoftDropToFloor,
+            5 => ReplayInput::RotateCw,
+            6 => ReplayInput::RotateCcw,
+            7 => ReplayInput::HardDrop,
+            8 => ReplayInput::Gravity,
+            9 => ReplayInput::Pause,
+            10 => ReplayInput::Resume,
+            11 => ReplayInput::Hold,
+            12 => ReplayInput::End,
+            13 => ReplayInput::Settle,
+            _ => return None,
+        })
+    }
+}
+
+/// Cursor over a little endian blob passed in from userspace
+struct BlobReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BlobReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// The next `len` bytes, or `EINVAL` if the blob is too short.
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(EINVAL)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        self.bytes(N)?.try_into().map_err(|_| EINVAL)
+    }
+
+    /// Little endian integer of `len` bytes.
+    fn le(&mut self, len: usize) -> Result<u64> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .rev()
+            .fold(0, |v, &b| v << 8 | b as u64))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.le(4)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.le(8)
+    }
+}
+
+/// Replay being played back by `TETRIS_IOCTL_PLAY_REPLAY`
+pub(super) struct Playback {
+    /// Inputs in `TetrisGame::playback_events`.
+    pub(super) len: usize,
+    /// Index of the next input to apply.
+    pub(super) next: usize,
+    /// Score the recording ended on.
+    score: u64,
+    /// The replay has got to where the recording settled the cascade
+    /// waiting now; see `TetrisGame::settle_in_ns()`.
+    settle: bool,
+}
+
+impl From<Shift> for ReplayInput {
+    fn from(dir: Shift) -> Self {
+        match dir {
+            Shift::Left => ReplayInput::Left,
+            Shift::Right => ReplayInput::Right,
+        }
+    }
+}
+
+/// Input buffered during the entry delay and replayed on spawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum BufferedInput {
+    Left,
+    Right,
+    Rotate(Rotation),
+    Hold,
+}
+
+/// Seed for a new game, from the kernel RNG so nobody can predict it.
+fn random_seed() -> u64 {
+    // SAFETY: `get_random_u64()` has no preconditions.
+    unsafe { bindings::get_random_u64() }
+}
+
+/// A player's best score on a game's leaderboard.
+#[derive(Clone, Copy)]
+pub(super) struct LeaderboardEntry {
+    pub(super) uid: Kuid,
+    pub(super) score: u64,
+    /// When it was set, in seconds since the epoch.
+    pub(super) time: i64,
+}
+
+/// Game state
+pub(super) struct TetrisGame {
+    pub(super) board: Board,
+    /// Visible board size in rendered cells; `board` also holds the hidden
+    /// rows above.
+    pub(super) width: usize,
+    pub(super) height: usize,
+    pub(super) current_piece: Option<Tetromino>,
+    /// Piece put aside by `hold_piece()`.
+    pub(super) hold: Option<TetrominoType>,
+    /// Hold was used since the last piece came from the queue; it can't be
+    /// used again until the next one does.
+    pub(super) hold_used: bool,
+    pub(super) score: u64,
+    pub(super) mode: GameMode,
+    pub(super) end_reason: Option<EndReason>,
+    /// Monotonic timestamps bounding the current game; `ended_ns` is only
+    /// meaningful once `game_over` is set.
+    started_ns: u64,
+    ended_ns: u64,
+    /// Lines cleared this game.
+    pub(super) lines: u32,
+    pub(super) gravity_curve: GravityCurve,
+    /// Level new games start at; the level only rises from there.
+    pub(super) start_level: u32,
+    /// 20G: the active piece always sits on the stack, and the gravity tick
+    /// only serves as lock delay.
+    pub(super) instant_gravity: bool,
+    /// Big mode: every mino is drawn as a 2x2 block, so the game runs on a
+    /// `width / 2` x `height / 2` logical grid (plus the hidden rows) in the
+    /// top-left of `board` while the frame keeps its usual size.
+    pub(super) big: bool,
+    /// Cascade gravity: after a clear, every connected group of cells falls
+    /// as far as it can, and any lines that completes clear as a chain.
+    /// The slow path settles it, off the game lock; see `settle_in_ns()`.
+    pub(super) cascade: bool,
+    /// Number of clears the last lock chained together (0 if none).
+    pub(super) chain: u32,
+    /// Starts at 1 and goes up every `LINES_PER_LEVEL` lines; multiplies
+    /// line clear, T-spin, combo and perfect clear scores.
+    pub(super) level: u32,
+    /// Portion of `score` earned from soft drops (1 point per cell).
+    pub(super) soft_drop_score: u64,
+    /// Portion of `score` earned from hard drops (2 points per cell).
+    pub(super) hard_drop_score: u64,
+    pub(super) last_action: LastAction,
+    pub(super) last_tspin: TSpin,
+    /// Consecutive line-clearing locks minus one; -1 when no chain is active.
+    pub(super) combo: i32,
+    pub(super) max_combo: i32,
+    /// The most recent lock emptied the whole board.
+    pub(super) perfect_clear: bool,
+    /// Incoming garbage lines, raised on the next lock that clears nothing.
+    pub(super) garbage_pending: u32,
+    /// Outgoing garbage lines not yet collected by the opponent.
+    pub(super) attack_out: u32,
+    pub(super) game_over: bool,
+    /// The built-in bot is playing an attract mode game, which the next
+    /// real input replaces with a fresh one.
+    pub(super) demo: bool,
+    /// The bot has steered the piece to its target and drops it next step.
+    bot_aligned: bool,
+    /// Time between autopilot moves, 0 while the autopilot is off.
+    pub(super) autopilot_ms: u32,
+    /// How the bot judges placements, for the demo, autopilot and hints.
+    pub(super) bot_weights: BotWeights,
+    /// What the bytes written to the device do.
+    pub(super) keymap: KeyMap,
+    /// Last write or ioctl; the demo starts after `demo_idle_secs` without.
+    last_input_ns: u64,
+    /// When the game was paused, `None` while running.
+    paused_at_ns: Option<u64>,
+    /// Entry delay (ARE) between a lock and the next spawn, 0 for none.
+    pub(super) are_ns: u64,
+    /// When the next piece spawns, while an entry delay is running.
+    pub(super) spawn_at_ns: Option<u64>,
+    /// How long full rows flash before they are cleared, 0 for no flash.
+    pub(super) clear_delay_ns: u64,
+    /// Full rows waiting to be cleared, one bit per board row.
+    pub(super) flash_rows: u64,
+    /// When the flashing rows are cleared, while a clear delay is running.
+    /// The lock that filled them finishes then, with `last_tspin`.
+    flash_until_ns: Option<u64>,
+    /// Shifts and rotations made during the entry delay, oldest first.
+    pub(super) input_buffer: [BufferedInput; INPUT_BUFFER_LEN],
+    pub(super) input_buffer_len: usize,
+    /// Delayed auto shift: how long a direction is held before it repeats.
+    pub(super) das_ns: u64,
+    /// Auto repeat rate once DAS has charged, 0 to slide to the wall.
+    pub(super) arr_ns: u64,
+    /// Direction currently held down, if any.
+    pub(super) shift_held: Option<Shift>,
+    /// When the held direction next moves the piece.
+    shift_next_ns: u64,
+    /// Upcoming pieces, `preview[0]` spawns next. Always kept full; only the
+    /// first `preview_count` entries are shown.
+    pub(super) preview: [TetrominoType; PREVIEW_MAX],
+    pub(super) preview_count: usize,
+    pub(super) bag: Bag,
+    /// Pieces produced by the randomizer this game, by `TetrominoType::index()`.
+    pub(super) dealt: [u32; 7],
+    /// Pieces locked into the stack this game, by `TetrominoType::index()`.
+    pub(super) placed: [u32; 7],
+    /// Seed this game's piece sequence was dealt from.
+    pub(super) seed: u64,
+    /// User who first opened the device; besides root, only they may pick
+    /// the seed.
+    pub(super) owner: Option<Kuid>,
+    /// User whose input the game last took.
+    pub(super) player: Option<Kuid>,
+    /// Best score since load, and who was playing when it was set. Unlike
+    /// everything else, kept by `restart()`; the demo, replays and restored
+    /// games don't count.
+    pub(super) high_score: u64,
+    pub(super) high_score_uid: Option<Kuid>,
+    /// The score wasn't all earned by playing this game: it came from
+    /// `load_state()` or a replay, which stays on the board after playback.
+    score_borrowed: bool,
+    /// Best score of each player since load, best first; as the high
+    /// score, kept by `restart()`.
+    pub(super) leaderboard: [Option<LeaderboardEntry>; LEADERBOARD_LEN],
+    /// Bumped by anything that may change the frame (gravity, input, line
+    /// clears); each open file remembers the last one it read.
+    pub(super) frame_gen: u64,
+    prng: PRNG,
+    /// Inputs recorded this game, encoded as in `TETRIS_IOCTL_GET_REPLAY`.
+    /// Preallocated to `REPLAY_MAX_EVENTS`; the first `replay_len` are used.
+    pub(super) replay: KVVec<u32>,
+    pub(super) replay_len: usize,
+    /// Game time of the last recorded input, in ms.
+    replay_last_ms: u64,
+    /// Inputs were dropped because `replay` filled up.
+    pub(super) replay_truncated: bool,
+    pub(super) playback: Option<Playback>,
+    /// The inputs being played back, encoded as in the replay blob.
+    /// Preallocated to `REPLAY_MAX_EVENTS`, like `replay`.
+    playback_events: KVVec<u32>,
+    /// Timed playback speed in % of real time, 0 for instant.
+    pub(super) replay_speed: u32,
+    /// Whether the last playback ended on the recorded score, until the
+    /// next game.
+    pub(super) replay_verdict: Option<bool>,
+    /// Recent events for `/dev/tetris-events`: event `n` is kept at
+    /// `n % EVENT_RING_LEN` until it is overwritten.
+    events: KVVec<GameEvent>,
+    /// Events so far, across every game played on this struct.
+    pub(super) event_seq: u64,
+    /// Game clock during playback, advanced by the replayed inputs instead
+    /// of real time so deadlines fall exactly as they did when recorded.
+    pub(super) clock_ns: Option<u64>,
+}
+
+impl TetrisGame {
+    pub(super) fn new() -> Result<Self> {
+        let now = ktime_now_ns();
+        let seed = random_seed();
+        let start_level =
+            (*crate::module_parameters::start_level.value()).clamp(1, START_LEVEL_MAX);
+        let (mut width, mut height) = (
+            *crate::module_parameters::board_width.value() as usize,
+            *crate::module_parameters::board_height.value() as usize,
+        );
+        if !Self::size_ok(width, height, false) {
+            pr_warn!("tetris: invalid board size {}x{}, using default\n", width, height);
+            (width, height) = (BOARD_WIDTH, BOARD_HEIGHT);
+        }
+        let mut game = Self {
+            board: Board::new(width, HIDDEN_ROWS + height),
+            width,
+            height,
+            current_piece: None,
+            hold: None,
+            hold_used: false,
+            score: 0,
+            mode: GameMode::Marathon,
+            end_reason: None,
+            started_ns: now,
+            ended_ns: now,
+            lines: 0,
+            level: start_level,
+            start_level,
+            gravity_curve: GravityCurve::from_raw(*crate::module_parameters::gravity_curve.value())
+                .unwrap_or(GravityCurve::Modern),
+            instant_gravity: false,
+            big: false,
+            cascade: false,
+            chain: 0,
+            soft_drop_score: 0,
+            hard_drop_score: 0,
+            last_action: LastAction::None,
+            last_tspin: TSpin::None,
+            combo: -1,
+            max_combo: 0,
+            perfect_clear: false,
+            garbage_pending: 0,
+            attack_out: 0,
+            game_over: false,
+            demo: false,
+            bot_aligned: false,
+            autopilot_ms: 0,
+            bot_weights: BOT_WEIGHTS,
+            keymap: DEFAULT_KEYMAP,
+            last_input_ns: now,
+            paused_at_ns: None,
+            are_ns: 0,
+            spawn_at_ns: None,
+            clear_delay_ns: 0,
+            flash_rows: 0,
+            flash_until_ns: None,
+            input_buffer: [BufferedInput::Left; INPUT_BUFFER_LEN],
+            input_buffer_len: 0,
+            das_ns: sysctl_ns(tetris_sysctl_das_ms),
+            arr_ns: sysctl_ns(tetris_sysctl_arr_ms),
+            shift_held: None,
+            shift_next_ns: 0,
+            preview: [TetrominoType::I; PREVIEW_MAX],
+            preview_count: PREVIEW_DEFAULT,
+            bag: Bag::new(Randomizer::Bag7),
+            dealt: [0; 7],
+            placed: [0; 7],
+            seed,
+            owner: None,
+            player: None,
+            high_score: 0,
+            high_score_uid: None,
+            score_borrowed: false,
+            leaderboard: [None; LEADERBOARD_LEN],
+            frame_gen: 1,
+            prng: PRNG::new(seed),
+            replay: KVVec::new(),
+            replay_len: 0,
+            replay_last_ms: 0,
+            replay_truncated: false,
+            playback: None,
+            playback_events: KVVec::new(),
+            replay_speed: 100,
+            replay_verdict: None,
+            events: KVVec::new(),
+            event_seq: 0,
+            clock_ns: None,
+        };
+        game.replay.resize(REPLAY_MAX_EVENTS, 0, GFP_KERNEL)?;
+        game.playback_events.resize(REPLAY_MAX_EVENTS, 0, GFP_KERNEL)?;
+        game.events.resize(EVENT_RING_LEN, GameEvent::NONE, GFP_KERNEL)?;
+
+        game.reseed(seed);
+        Ok(game)
+    }
+
+    /// Deal the piece sequence afresh from `seed`: new bag, new preview.
+    /// A game started from the same seed and settings gets the same pieces.
+    fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.prng = PRNG::new(seed);
+        self.bag = Bag::new(self.bag.randomizer);
+        self.dealt = [0; 7];
+        for i in 0..PREVIEW_MAX {
+            self.preview[i] = self.next_piece_from_bag();
+        }
+    }
+
+    pub(super) fn reset(&mut self, stats: &TetrisStats) {
+        self.restart(stats, random_seed());
+    }
+
+    /// Start a new game dealt from `seed`.
+    pub(super) fn restart(&mut self, stats: &TetrisStats, seed: u64) {
+        // A game cut short still scored.
+        if !self.game_over {
+            self.note_leaderboard();
+        }
+        stats.games_played.fetch_add(1, Ordering::Relaxed);
+        self.board.clear();
+        self.current_piece = None;
+        self.hold = None;
+        self.hold_used = false;
+        self.score = 0;
+        self.lines = 0;
+        self.level = self.start_level;
+        self.soft_drop_score = 0;
+        self.hard_drop_score = 0;
+        self.last_action = LastAction::None;
+        self.last_tspin = TSpin::None;
+        self.combo = -1;
+        self.max_combo = 0;
+        self.perfect_clear = false;
+        self.garbage_pending = 0;
+        self.attack_out = 0;
+        self.chain = 0;
+        self.reseed(seed);
+        self.placed = [0; 7];
+        self.replay_len = 0;
+        self.replay_last_ms = 0;
+        self.replay_truncated = false;
+        self.replay_verdict = None;
+        self.score_borrowed = false;
+        self.game_over = false;
+        self.bot_aligned = false;
+        self.paused_at_ns = None;
+        self.spawn_at_ns = None;
+        self.flash_rows = 0;
+        self.flash_until_ns = None;
+        self.input_buffer_len = 0;
+        self.end_reason = None;
+        self.started_ns = self.now_ns();
+        self.spawn_piece(stats);
+    }
+
+    /// Take the score as the high score if it beats it.
+    pub(super) fn note_high_score(&mut self) {
+        if self.demo || self.playback.is_some() || self.score_borrowed {
+            return;
+        }
+        if self.score > self.high_score {
+            self.high_score = self.score;
+            self.high_score_uid = self.player;
+        }
+    }
+
+    /// Put a finished game's score on the leaderboard, if it is its
+    /// player's best and makes the top `LEADERBOARD_LEN`. The demo and
+    /// replays don't count.
+    fn note_leaderboard(&mut self) {
+        let Some(uid) = self.player else {
+            return;
+        };
+        if self.demo || self.playback.is_some() || self.score == 0 {
+            return;
+        }
+        let board = &mut self.leaderboard;
+        if let Some(i) = board.iter().position(|e| e.is_some_and(|e| e.uid == uid)) {
+            if board[i].is_some_and(|e| e.score >= self.score) {
+                return;
+            }
+            board.copy_within(i + 1.., i);
+            board[LEADERBOARD_LEN - 1] = None;
+        }
+        let Some(rank) = board
+            .iter()
+            .position(|e| !e.is_some_and(|e| e.score >= self.score))
+        else {
+            return;
+        };
+        board.copy_within(rank..LEADERBOARD_LEN - 1, rank + 1);
+        board[rank] = Some(LeaderboardEntry {
+            uid,
+            score: self.score,
+            // SAFETY: Just reads the clock.
+            time: unsafe { bindings::ktime_get_real_seconds() },
+        });
+    }
+
+    fn end_game(&mut self, reason: EndReason) {
+        self.game_over = true;
+        self.end_reason = Some(reason);
+        self.current_piece = None;
+        self.spawn_at_ns = None;
+        self.flash_rows = 0;
+        self.flash_until_ns = None;
+        self.ended_ns = match reason {
+            // Ticks are coarse; report exactly the limit.
+            EndReason::TimeUp => self.started_ns + ULTRA_NS,
+            _ => self.now_ns(),
+        };
+        self.emit(GameEventKind::GameOver, None, reason as u32 + 1);
+        self.note_leaderboard();
+    }
+
+    /// Time spent in the current game, frozen while paused and once it has
+    /// ended.
+    pub(super) fn elapsed_ns(&self) -> u64 {
+        let end = if self.game_over {
+            self.ended_ns
+        } else if let Some(at) = self.paused_at_ns {
+            at
+        } else {
+            self.now_ns()
+        };
+        end.saturating_sub(self.started_ns)
+    }
+
+    /// Current time on the game clock: real time, except during playback.
+    fn now_ns(&self) -> u64 {
+        self.clock_ns.unwrap_or_else(ktime_now_ns)
+    }
+
+    pub(super) fn paused(&self) -> bool {
+        self.paused_at_ns.is_some()
+    }
+
+    /// One of `playing`, `paused` or `game over`, for people.
+    pub(super) fn state_name(&self) -> &'static str {
+        self.state().state_name()
+    }
+
+    /// Stop the clock. Returns false if the game is over or already paused.
+    pub(super) fn pause(&mut self) -> bool {
+        if self.game_over || self.paused() {
+            return false;
+        }
+        self.paused_at_ns = Some(self.now_ns());
+        true
+    }
+
+    /// Restart the clock, pushing every running deadline back by the time
+    /// spent paused so none of it counts.
+    pub(super) fn resume(&mut self) -> bool {
+        let Some(at) = self.paused_at_ns.take() else {
+            return false;
+        };
+        let paused_for = self.now_ns().saturating_sub(at);
+        self.started_ns += paused_for;
+        if let Some(spawn_at) = self.spawn_at_ns.as_mut() {
+            *spawn_at += paused_for;
+        }
+        if let Some(flash_until) = self.flash_until_ns.as_mut() {
+            *flash_until += paused_for;
+        }
+        self.shift_next_ns += paused_for;
+        true
+    }
+
+    /// Completion time of the current game if it is a finished sprint.
+    pub(super) fn sprint_time_ns(&self) -> Option<u64> {
+        (self.end_reason == Some(EndReason::Cleared)).then(|| self.elapsed_ns())
+    }
+
+    /// End an Ultra game whose time is up. Called from the gravity tick.
+    pub(super) fn check_time_limit(&mut self) {
+        if self.mode == GameMode::Ultra && !self.game_over && self.elapsed_ns() >= ULTRA_NS {
+            self.end_game(EndReason::TimeUp);
+        }
+    }
+
+    pub(super) fn spawn_piece(&mut self, stats: &TetrisStats) {
+        self.spawn_piece_rotated(stats, 0);
+    }
+
+    /// Spawn the next piece already turned to `rotation` (IRS). If the
+    /// turned piece doesn't fit, it spawns in its normal orientation.
+    fn spawn_piece_rotated(&mut self, stats: &TetrisStats, rotation: u8) {
+        if self.game_over {
+            return;
+        }
+        if self.enter_piece(stats, self.preview[0], rotation) {
+            self.advance_preview();
+            self.hold_used = false;
+        }
+    }
+
+    /// Bring `piece_type` onto the board as the active piece, turned as in
+    /// `spawn_piece_rotated()`. Returns false if it blocked out instead.
+    fn enter_piece(
+        &mut self,
+        stats: &TetrisStats,
+        piece_type: TetrominoType,
+        rotation: u8,
+    ) -> bool {
+        let Some(new_piece) = self.board.spawn(piece_type, rotation) else {
+            self.end_game(EndReason::BlockOut);
+            return false;
+        };
+
+        self.current_piece = Some(new_piece);
+        self.last_action = LastAction::None;
+        self.apply_instant_gravity();
+        self.emit(GameEventKind::Spawned, self.current_piece, 0);
+
+        stats.pieces_spawned.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// End the game at the player's request. Returns false if it was
+    /// already over.
+    pub(super) fn abandon(&mut self, stats: &TetrisStats) -> bool {
+        if self.game_over {
+            return false;
+        }
+        self.end_game(EndReason::Abandoned);
+        stats.games_abandoned.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Put the active piece aside and bring out the held one, or the next
+    /// piece if nothing was held. Only once per piece from the queue; during
+    /// an entry delay it is buffered and done as the next piece spawns
+    /// (IHS). Returns whether a piece went into hold (or will).
+    pub(super) fn hold_piece(&mut self, stats: &TetrisStats) -> bool {
+        if self.paused() || self.game_over {
+            return false;
+        }
+        if self.awaiting_spawn() {
+            return self.buffer_input(BufferedInput::Hold);
+        }
+        if self.hold_used {
+            return false;
+        }
+        let Some(piece) = self.current_piece.take() else {
+            return false;
+        };
+        self.swap_hold(stats, piece.piece_type, 0);
+        true
+    }
+
+    /// Hold `piece_type` and bring out what was held, or the next piece,
+    /// turned to `rotation`.
+    fn swap_hold(&mut self, stats: &TetrisStats, piece_type: TetrominoType, rotation: u8) {
+        match self.hold.replace(piece_type) {
+            Some(held) => {
+                self.enter_piece(stats, held, rotation);
+            }
+            None => self.spawn_piece_rotated(stats, rotation),
+        }
+        self.hold_used = true;
+    }
+
+    /// Pieces locked this game.
+    pub(super) fn lock_count(&self) -> u32 {
+        self.placed.iter().fold(0, |sum, &n| sum.saturating_add(n))
+    }
+
+    /// Encode the outcome of an input for the movement ioctls. `before` is
+    /// `(lock_count(), lines)` sampled before the input ran.
+    pub(super) fn move_result(&self, before: (u32, u32), moved: bool, cells: u32) -> isize {
+        let mut ret = 0;
+        if moved {
+            ret |= TETRIS_RESULT_MOVED;
+        }
+        if self.lock_count() != before.0 {
+            ret |= TETRIS_RESULT_LOCKED;
+        }
+        if self.game_over {
+            ret |= TETRIS_RESULT_GAME_OVER;
+        }
+        let lines = self.lines.saturating_sub(before.1).min(0xff);
+        ret |= (lines as isize) << TETRIS_RESULT_LINES_SHIFT;
+        ret |= (cells.min(0xff) as isize) << TETRIS_RESULT_CELLS_SHIFT;
+        ret
+    }
+
+    /// Spawn the next piece after a lock, now or once the entry delay ends.
+    fn schedule_spawn(&mut self, stats: &TetrisStats) {
+        // Inputs buffered during a clear delay are replayed by
+        // `check_spawn_delay()`, so let it do the spawn.
+        if self.are_ns == 0 && self.input_buffer_len == 0 {
+            self.spawn_piece(stats);
+        } else if !self.game_over {
+            self.spawn_at_ns = Some(self.now_ns() + self.are_ns);
+        }
+    }
+
+    /// Spawn the next piece if its entry delay has run out, then replay the
+    /// inputs buffered meanwhile. Returns whether a piece spawned.
+    pub(super) fn check_spawn_delay(&mut self, stats: &TetrisStats) -> bool {
+        self.check_line_flash(stats);
+        match self.spawn_at_ns {
+            Some(at) if !self.paused() && self.now_ns() >= at => {}
+            _ => return false,
+        }
+        self.spawn_at_ns = None;
+
+        // Rotations made during the delay turn the piece before it appears
+        // (IRS), instead of being replayed as kicks after it spawns.
+        let buffered = self.input_buffer;
+        let len = core::mem::take(&mut self.input_buffer_len);
+        let rotation = buffered[..len].iter().fold(0u8, |r, input| match *input {
+            BufferedInput::Rotate(Rotation::Cw) => (r + 1) % 4,
+            BufferedInput::Rotate(Rotation::Ccw) => (r + 3) % 4,
+            _ => r,
+        });
+        // Likewise a hold swaps the piece before it appears (IHS).
+        if buffered[..len].contains(&BufferedInput::Hold) {
+            let next = self.preview[0];
+            self.advance_preview();
+            self.swap_hold(stats, next, rotation);
+        } else {
+            self.spawn_piece_rotated(stats, rotation);
+        }
+
+        for input in &buffered[..len] {
+            match *input {
+                BufferedInput::Left => self.move_left(),
+                BufferedInput::Right => self.move_right(),
+                BufferedInput::Rotate(_) | BufferedInput::Hold => false,
+            };
+        }
+        true
+    }
+
+    /// Finish the lock that filled the flashing rows once the clear delay
+    /// has run out, unless it is a cascade, which `finish_cascade()` does.
+    /// Returns whether it did.
+    fn check_line_flash(&mut self, stats: &TetrisStats) -> bool {
+        match self.flash_until_ns {
+            Some(at) if !self.paused() && !self.cascade && self.now_ns() >= at => {}
+            _ => return false,
+        }
+        self.flash_until_ns = None;
+        self.flash_rows = 0;
+        self.finish_lock(stats, self.last_tspin);
+        true
+    }
+
+    /// Between a lock and the next spawn: clearing lines or in ARE.
+    fn awaiting_spawn(&self) -> bool {
+        self.spawn_at_ns.is_some() || self.flash_until_ns.is_some()
+    }
+
+    /// Remember `input` for the next spawn if an entry delay is running.
+    /// Returns false when there is no delay or the buffer is full.
+    fn buffer_input(&mut self, input: BufferedInput) -> bool {
+        if !self.awaiting_spawn() || self.input_buffer_len >= INPUT_BUFFER_LEN {
+            return false;
+        }
+        self.input_buffer[self.input_buffer_len] = input;
+        self.input_buffer_len += 1;
+        true
+    }
+
+    fn shift(&mut self, dir: Shift) -> bool {
+        match dir {
+            Shift::Left => self.move_left(),
+            Shift::Right => self.move_right(),
+        }
+    }
+
+    /// Key-down for `dir`: move once now and start charging DAS. Returns
+    /// the delay until the auto shift work should next run.
+    pub(super) fn shift_press(&mut self, dir: Shift) -> (bool, u64) {
+        self.shift_held = Some(dir);
+        self.shift_next_ns = self.now_ns() + self.das_ns;
+        (self.shift(dir), self.das_ns)
+    }
+
+    /// Key-up for `dir`. Releasing a direction that isn't held is a no-op,
+    /// so a late key-up can't cancel the opposite key.
+    pub(super) fn shift_release(&mut self, dir: Shift) {
+        if self.shift_held == Some(dir) {
+            self.shift_held = None;
+        }
+    }
+
+    /// Repeat the held direction if it is due. Returns the cells moved and
+    /// the delay until the next check, or `None` once nothing is held.
+    pub(super) fn auto_shift(&mut self) -> Option<(u32, u64)> {
+        if self.paused() {
+            // Stop repeating; the key has to be pressed again after resume.
+            self.shift_held = None;
+        }
+        let dir = self.shift_held?;
+        let now = self.now_ns();
+        let mut moved = 0;
+
+        if now >= self.shift_next_ns {
+            if self.arr_ns == 0 {
+                while self.shift(dir) {
+                    self.record(dir.into());
+                    moved += 1;
+                }
+                // Keep pressing against the wall in case the way clears.
+                self.shift_next_ns = now + NES_FRAME_NS;
+            } else {
+                if self.shift(dir) {
+                    self.record(dir.into());
+                    moved += 1;
+                }
+                self.shift_next_ns = now + self.arr_ns;
+            }
+        }
+
+        Some((moved, self.shift_next_ns.saturating_sub(now)))
+    }
+
+    /// Append `input` to the replay. Called by the device entry points, not
+    /// the game logic, so internal moves (buffered inputs, the demo bot)
+    /// aren't recorded twice or at all.
+    pub(super) fn record(&mut self, input: ReplayInput) {
+        if self.game_over || self.demo {
+            return;
+        }
+        if self.replay_len >= self.replay.len() {
+            self.replay_truncated = true;
+            return;
+        }
+        let now_ms = self.elapsed_ns() / 1_000_000;
+        let delta = now_ms.saturating_sub(self.replay_last_ms).min(REPLAY_DELTA_MAX);
+        self.replay[self.replay_len] = (delta as u32) << 4 | input as u32;
+        self.replay_len += 1;
+        self.replay_last_ms = now_ms;
+    }
+
+    /// The replay so far, laid out as described at `TETRIS_IOCTL_GET_REPLAY`.
+    /// Appended to `blob`, which the caller made room in for
+    /// `REPLAY_BLOB_MAX` bytes, so it doesn't allocate under the game lock.
+    pub(super) fn replay_blob(&self, blob: &mut KVVec<u8>) -> Result {
+        let events = &self.replay[..self.replay_len];
+        let options =
+            self.big as u8 | (self.cascade as u8) << 1 | (self.instant_gravity as u8) << 2;
+
+        blob.extend_from_slice(b"TRPL", GFP_NOWAIT)?;
+        blob.extend_from_slice(&REPLAY_VERSION.to_le_bytes(), GFP_NOWAIT)?;
+        blob.extend_from_slice(&(self.replay_truncated as u16).to_le_bytes(), GFP_NOWAIT)?;
+        blob.extend_from_slice(&self.seed.to_le_bytes(), GFP_NOWAIT)?;
+        blob.extend_from_slice(&self.score.to_le_bytes(), GFP_NOWAIT)?;
+        blob.extend_from_slice(&(events.len() as u32).to_le_bytes(), GFP_NOWAIT)?;
+        blob.extend_from_slice(
+            &[
+                self.mode as u8,
+                self.bag.randomizer as u8,
+                self.width as u8,
+                self.height as u8,
+                self.start_level as u8,
+                options,
+            ],
+            GFP_NOWAIT,
+        )?;
+        blob.extend_from_slice(&((self.are_ns / 1_000_000) as u16).to_le_bytes(), GFP_NOWAIT)?;
+        blob.extend_from_slice(
+            &((self.clear_delay_ns / 1_000_000) as u16).to_le_bytes(),
+            GFP_NOWAIT,
+        )?;
+        blob.extend_from_slice(&[0; 2], GFP_NOWAIT)?;
+        for event in events {
+            blob.extend_from_slice(&event.to_le_bytes(), GFP_NOWAIT)?;
+        }
+        Ok(())
+    }
+
+    /// The whole game, laid out as described at `TETRIS_IOCTL_SAVE_STATE`.
+    /// The board as `TETRIS_IOCTL_GET_BOARD` copies it out, appended to
+    /// `blob` as `replay_blob()` does, with room for `BOARD_BLOB_MAX`.
+    pub(super) fn board_blob(&self, blob: &mut KVVec<u8>) -> Result {
+        blob.extend_from_slice(
+            &[self.cols() as u8, self.rows() as u8, HIDDEN_ROWS as u8, 0],
+            GFP_NOWAIT,
+        )?;
+        for y in 0..self.rows() {
+            for cell in &self.board[y][..self.cols()] {
+                blob.push(cell.to_raw(), GFP_NOWAIT)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appended to `blob` as `replay_blob()` does, with room for
+    /// `STATE_MAX_LEN`.
+    pub(super) fn save_state(&self, blob: &mut KVVec<u8>) -> Result {
+        let options =
+            self.big as u8 | (self.cascade as u8) << 1 | (self.instant_gravity as u8) << 2;
+        let pending = if self.flash_until_ns.is_some() {
+            2
+        } else {
+            self.spawn_at_ns.is_some() as u8
+        };
+
+        let mut put = |bytes: &[u8]| blob.extend_from_slice(bytes, GFP_NOWAIT);
+        put(b"TSAV")?;
+        put(&STATE_VERSION.to_le_bytes())?;
+        put(&[0; 2])?;
+        put(&[
+            self.width as u8,
+            self.height as u8,
+            self.mode as u8,
+            self.bag.randomizer as u8,
+            self.start_level as u8,
+            options,
+            self.game_over as u8,
+            self.end_reason.map_or(0, |reason| reason as u8 + 1),
+            self.paused() as u8,
+            pending,
+            self.last_tspin as u8,
+            self.preview_count as u8,
+            self.bag.next as u8,
+        ])?;
+        for n in [self.score, self.soft_drop_score, self.hard_drop_score] {
+            put(&n.to_le_bytes())?;
+        }
+        for n in [
+            self.lines,
+            self.level,
+            self.combo as u32,
+            self.max_combo as u32,
+            self.garbage_pending,
+            self.attack_out,
+        ] {
+            put(&n.to_le_bytes())?;
+        }
+        for n in [self.elapsed_ns(), self.seed, self.prng.state] {
+            put(&n.to_le_bytes())?;
+        }
+        for piece in self.bag.pieces.iter().chain(&self.preview) {
+            put(&[piece.index() as u8])?;
+        }
+        match self.current_piece {
+            Some(piece) => put(&[
+                1,
+                piece.piece_type.index() as u8,
+                piece.x as i8 as u8,
+                piece.y as i8 as u8,
+                piece.rotation % 4,
+            ])?,
+            None => put(&[0; 5])?,
+        }
+        put(&[
+            self.hold.map_or(0xff, |piece| piece.index() as u8),
+            self.hold_used as u8,
+        ])?;
+        for n in self.dealt.iter().chain(&self.placed) {
+            put(&n.to_le_bytes())?;
+        }
+        for y in 0..self.rows() {
+            for x in 0..self.cols() {
+                put(&[self.board[y][x].to_raw()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the game with one from `save_state()`. Nothing changes
+    /// unless the whole blob is valid.
+    pub(super) fn load_state(&mut self, blob: &[u8]) -> Result {
+        let mut r = BlobReader::new(blob);
+        if r.bytes(4)? != b"TSAV" {
+            return Err(EINVAL);
+        }
+        let version = r.le(2)?;
+        if !(1..=STATE_VERSION as u64).contains(&version) {
+            return Err(EINVAL);
+        }
+        r.bytes(2)?;
+        let [
+            width,
+            height,
+            mode,
+            randomizer,
+            start_level,
+            options,
+            game_over,
+            end_reason,
+            paused,
+            pending,
+            last_tspin,
+            preview_count,
+            bag_idx,
+        ] = r.array()?;
+        let (width, height, big) = (width as usize, height as usize, options & 1 != 0);
+        if !Self::size_ok(width, height, big)
+            || !(1..=START_LEVEL_MAX).contains(&(start_level as u32))
+            || !(1..=PREVIEW_MAX).contains(&(preview_count as usize))
+            || bag_idx as usize > self.bag.pieces.len()
+        {
+            return Err(EINVAL);
+        }
+        let mode = GameMode::from_raw(mode as u32).ok_or(EINVAL)?;
+        let randomizer = Randomizer::from_raw(randomizer as u32).ok_or(EINVAL)?;
+        let end_reason = match end_reason {
+            0 => None,
+            1 => Some(EndReason::BlockOut),
+            2 => Some(EndReason::LockOut),
+            3 => Some(EndReason::TopOut),
+            4 => Some(EndReason::Cleared),
+            5 => Some(EndReason::TimeUp),
+            6 => Some(EndReason::Abandoned),
+            _ => return Err(EINVAL),
+        };
+        let last_tspin = match last_tspin {
+            0 => TSpin::None,
+            1 => TSpin::Mini,
+            2 => TSpin::Full,
+            _ => return Err(EINVAL),
+        };
+        let piece = |raw: u8| TetrominoType::ALL.get(raw as usize).copied().ok_or(EINVAL);
+
+        let (score, soft_drop_score, hard_drop_score) = (r.u64()?, r.u64()?, r.u64()?);
+        let (lines, level) = (r.u32()?, r.u32()?);
+        let (combo, max_combo) = (r.u32()? as i32, r.u32()? as i32);
+        // Each clearing lock clears at least a line, so a combo can't be
+        // longer than the lines cleared, and the level follows from them.
+        if lines > LINES_MAX
+            || level != level_for(start_level as u32, lines)
+            || !(-1..=max_combo).contains(&combo)
+            || max_combo < 0
+            || max_combo as u32 > lines.saturating_sub(1)
+            || (combo + 1) as u32 > lines
+        {
+            return Err(EINVAL);
+        }
+        let (garbage_pending, attack_out) = (r.u32()?.min(GARBAGE_MAX), r.u32()?);
+        let (elapsed_ns, seed, prng_state) = (r.u64()?, r.u64()?, r.u64()?);
+        let mut bag = Bag {
+            randomizer,
+            next: bag_idx as usize,
+            ..self.bag
+        };
+        for slot in &mut bag.pieces {
+            *slot = piece(r.array::<1>()?[0])?;
+        }
+        let mut preview = self.preview;
+        for slot in &mut preview {
+            *slot = piece(r.array::<1>()?[0])?;
+        }
+        let [present, piece_type, x, y, rotation] = r.array()?;
+        let current_piece = match present {
+            0 => None,
+            _ => Some(Tetromino {
+                piece_type: piece(piece_type)?,
+                x: x as i8 as i32,
+                y: y as i8 as i32,
+                rotation: rotation % 4,
+            }),
+        };
+        let (hold, hold_used) = match version {
+            1 => (None, false),
+            _ => {
+                let [held, used] = r.array()?;
+                let hold = match held {
+                    0xff => None,
+                    _ => Some(piece(held)?),
+                };
+                (hold, used != 0)
+            }
+        };
+        let (mut dealt, mut placed) = ([0; 7], [0; 7]);
+        for n in dealt.iter_mut().chain(&mut placed) {
+            *n = r.u32()?;
+        }
+        // A piece is dealt before it can be placed, and drops score no more
+        // than the whole game did.
+        if dealt.iter().map(|&n| n as u64).sum::<u64>() > PIECES_MAX
+            || placed.iter().zip(&dealt).any(|(placed, dealt)| placed > dealt)
+            || soft_drop_score.saturating_add(hard_drop_score) > score
+        {
+            return Err(EINVAL);
+        }
+
+        let scale = if big { 2 } else { 1 };
+        let mut board = Board::new(width / scale, HIDDEN_ROWS + height / scale);
+        for y in 0..HIDDEN_ROWS + height / scale {
+            for x in 0..width / scale {
+                board.set(y, x, Cell::from_raw(r.array::<1>()?[0]).ok_or(EINVAL)?);
+            }
+        }
+
+        // The piece has to fit on the new board; put the old one back if not.
+        let old = (
+            core::mem::replace(&mut self.board, board),
+            self.width,
+            self.height,
+            self.big,
+        );
+        (self.width, self.height, self.big) = (width, height, big);
+        if current_piece.is_some_and(|piece| self.board.collides(&piece)) {
+            (self.board, self.width, self.height, self.big) = old;
+            return Err(EINVAL);
+        }
+
+        let now = ktime_now_ns();
+        self.mode = mode;
+        self.start_level = start_level as u32;
+        self.cascade = options & 2 != 0;
+        self.instant_gravity = options & 4 != 0;
+        self.game_over = game_over != 0;
+        self.end_reason = end_reason;
+        self.started_ns = now.saturating_sub(elapsed_ns);
+        self.ended_ns = now;
+        self.paused_at_ns = (paused != 0).then_some(now);
+        self.last_tspin = last_tspin;
+        self.last_action = LastAction::None;
+        self.preview_count = preview_count as usize;
+        self.bag = bag;
+        self.preview = preview;
+        self.score = score;
+        self.soft_drop_score = soft_drop_score;
+        self.hard_drop_score = hard_drop_score;
+        self.lines = lines;
+        self.level = level;
+        self.combo = combo;
+        self.max_combo = max_combo;
+        self.perfect_clear = false;
+        self.chain = 0;
+        self.garbage_pending = garbage_pending;
+        self.attack_out = attack_out;
+        self.seed = seed;
+        self.prng.state = prng_state;
+        self.current_piece = current_piece;
+        self.hold = hold;
+        self.hold_used = hold_used;
+        self.dealt = dealt;
+        self.placed = placed;
+        self.shift_held = None;
+        self.input_buffer_len = 0;
+        self.bot_aligned = false;
+        // Pick up where the save left off: the spawn or clear happens on
+        // the next check.
+        self.spawn_at_ns = (pending == 1).then_some(now);
+        self.flash_rows = if pending == 2 { self.board.full_rows() } else { 0 };
+        self.flash_until_ns = (pending == 2).then_some(now);
+        if pending == 0 && current_piece.is_none() && !self.game_over {
+            self.spawn_at_ns = Some(now);
+        }
+        self.replay_len = 0;
+        self.replay_last_ms = 0;
+        self.replay_truncated = true;
+        self.replay_verdict = None;
+        self.score_borrowed = true;
+        Ok(())
+    }
+
+    /// Replace the stack with `grid`: a line per visible row, top first, of
+    /// a `#` (filled) or `.` (empty) per column. The hidden rows empty. If
+    /// the active piece no longer fits, it enters again from the top.
+    pub(super) fn load_stack(&mut self, stats: &TetrisStats, grid: &[u8]) -> Result {
+        let grid = grid.strip_suffix(b"\n").unwrap_or(grid);
+        let lines = || grid.split(|&byte| byte == b'\n');
+        if lines().count() != self.rows() - HIDDEN_ROWS
+            || lines().any(|line| {
+                line.len() != self.cols() || line.iter().any(|&byte| byte != b'#' && byte != b'.')
+            })
+        {
+            return Err(EINVAL);
+        }
+
+        self.board.clear();
+        for (y, line) in lines().enumerate() {
+            for (x, &byte) in line.iter().enumerate() {
+                if byte == b'#' {
+                    self.board.set(HIDDEN_ROWS + y, x, Cell::Garbage);
+                }
+            }
+        }
+        if let Some(piece) = self.current_piece {
+            if self.board.collides(&piece) {
+                self.current_piece = None;
+                self.enter_piece(stats, piece.piece_type, 0);
+            }
+        }
+        self.bot_aligned = false;
+        // The replay can't reproduce a stack set by hand.
+        self.replay_truncated = true;
+        self.frame_changed();
+        Ok(())
+    }
+
+    /// Load `blob` and start playing it back on a fresh game with its
+    /// seed and options. Returns the real time until the first input.
+    pub(super) fn start_playback(&mut self, stats: &TetrisStats, blob: &[u8]) -> Result<u64> {
+        let mut r = BlobReader::new(blob);
+        if r.bytes(4)? != b"TRPL" || r.le(2)? != REPLAY_VERSION as u64 {
+            return Err(EINVAL);
+        }
+        let _flags = r.le(2)?;
+        let seed = r.u64()?;
+        let score = r.u64()?;
+        let count = r.u32()? as usize;
+        let [mode, randomizer, width, height, start_level, options] = r.array()?;
+        let (are_ms, clear_delay_ms) = (r.le(2)?, r.le(2)?);
+        r.bytes(2)?;
+
+        let mode = GameMode::from_raw(mode as u32).ok_or(EINVAL)?;
+        let randomizer = Randomizer::from_raw(randomizer as u32).ok_or(EINVAL)?;
+        let (width, height, start_level) = (width as usize, height as usize, start_level as u32);
+        if count > REPLAY_MAX_EVENTS
+            || !Self::size_ok(width, height, options & 1 != 0)
+            || !(1..=START_LEVEL_MAX).contains(&start_level)
+            || are_ms > ARE_MAX_MS as u64
+            || clear_delay_ms > CLEAR_DELAY_MAX_MS as u64
+        {
+            return Err(EINVAL);
+        }
+
+        let events = r.bytes(4 * count)?;
+
+        self.stop_playback();
+        for (slot, event) in self.playback_events.iter_mut().zip(events.chunks_exact(4)) {
+            *slot = u32::from_le_bytes([event[0], event[1], event[2], event[3]]);
+        }
+        let first_ns = match count {
+            0 => 0,
+            _ => self.replay_delay_ns(self.playback_events[0]),
+        };
+        self.big = options & 1 != 0;
+        self.set_size(width, height)?;
+        self.mode = mode;
+        self.bag.randomizer = randomizer;
+        self.start_level = start_level;
+        self.cascade = options & 2 != 0;
+        self.instant_gravity = options & 4 != 0;
+        self.are_ns = are_ms * 1_000_000;
+        self.clear_delay_ns = clear_delay_ms * 1_000_000;
+        self.shift_held = None;
+
+        self.clock_ns = Some(ktime_now_ns());
+        self.playback = Some(Playback {
+            len: count,
+            next: 0,
+            score,
+            settle: false,
+        });
+        self.restart(stats, seed);
+        self.score_borrowed = true;
+        Ok(first_ns)
+    }
+
+    /// Real time to wait before replaying `event` at the current speed.
+    fn replay_delay_ns(&self, event: u32) -> u64 {
+        if self.replay_speed == 0 {
+            return 0;
+        }
+        (event >> 4) as u64 * 1_000_000 * 100 / self.replay_speed as u64
+    }
+
+    /// Apply the next input of the replay being played back. Returns the
+    /// real time until the one after, or `None` once the replay is over.
+    pub(super) fn replay_step(&mut self, stats: &TetrisStats) -> Option<u64> {
+        let playback = self.playback.as_mut()?;
+        let events = &self.playback_events[..playback.len];
+        let Some(&event) = events.get(playback.next) else {
+            self.finish_playback();
+            return None;
+        };
+        playback.next += 1;
+        let following = events.get(playback.next).copied();
+
+        if let Some(clock) = self.clock_ns.as_mut() {
+            *clock += (event >> 4) as u64 * 1_000_000;
+        }
+        // Same order as the entry points: catch up on deadlines, then act.
+        self.check_time_limit();
+        self.check_spawn_delay(stats);
+        if let Some(input) = ReplayInput::from_raw(event & 0xf) {
+            self.record(input);
+            self.apply_input(stats, input);
+        }
+        Some(following.map_or(0, |next| self.replay_delay_ns(next)))
+    }
+
+    /// Replay `input` the way the entry point that recorded it applied it.
+    fn apply_input(&mut self, stats: &TetrisStats, input: ReplayInput) {
+        match input {
+            ReplayInput::Left => {
+                self.move_left();
+            }
+            ReplayInput::Right => {
+                self.move_right();
+            }
+            ReplayInput::Down | ReplayInput::Gravity => {
+                self.move_down(stats);
+            }
+            ReplayInput::SoftDrop => {
+                self.soft_drop(stats, false);
+            }
+            ReplayInput::SoftDropToFloor => {
+                self.soft_drop(stats, true);
+            }
+            ReplayInput::RotateCw => {
+                self.rotate(Rotation::Cw);
+            }
+            ReplayInput::RotateCcw => {
+                self.rotate(Rotation::Ccw);
+            }
+            ReplayInput::HardDrop => {
+                self.hard_drop(stats);
+            }
+            ReplayInput::Pause => {
+                self.pause();
+            }
+            ReplayInput::Resume => {
+                self.resume();
+            }
+            ReplayInput::Hold => {
+                self.hold_piece(stats);
+            }
+            ReplayInput::End => {
+                self.abandon(stats);
+            }
+            ReplayInput::Settle => {
+                let pending = self.cascade && self.flash_until_ns.is_some();
+                if let Some(playback) = self.playback.as_mut() {
+                    playback.settle = pending;
+                }
+            }
+        }
+    }
+
+    /// End playback and carry on from here in real time.
+    pub(super) fn stop_playback(&mut self) -> Option<Playback> {
+        if let Some(clock) = self.clock_ns.take() {
+            self.rebase_clock(clock, ktime_now_ns());
+        }
+        self.playback.take()
+    }
+
+    fn finish_playback(&mut self) {
+        if let Some(playback) = self.stop_playback() {
+            self.replay_verdict = Some(self.score == playback.score);
+        }
+    }
+
+    /// Move every timestamp and deadline from a clock reading `from` to
+    /// `to`, as if the game had been running on the other clock all along.
+    fn rebase_clock(&mut self, from: u64, to: u64) {
+        let rebase = |t: &mut u64| *t = t.wrapping_sub(from).wrapping_add(to);
+        rebase(&mut self.started_ns);
+        rebase(&mut self.ended_ns);
+        rebase(&mut self.shift_next_ns);
+        for t in [
+            &mut self.paused_at_ns,
+            &mut self.spawn_at_ns,
+            &mut self.flash_until_ns,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            rebase(t);
+        }
+    }
+
+    /// Drop the head of the preview queue and refill the tail from the bag.
+    fn advance_preview(&mut self) {
+        self.preview.copy_within(1.., 0);
+        self.preview[PREVIEW_MAX - 1] = self.next_piece_from_bag();
+    }
+
+    pub(super) fn set_preview_count(&mut self, count: usize) -> Result {
+        if count == 0 || count > PREVIEW_MAX {
+            return Err(EINVAL);
+        }
+        self.preview_count = count;
+        Ok(())
+    }
+
+    fn next_piece_from_bag(&mut self) -> TetrominoType {
+        let piece = self.bag.deal(&mut self.prng);
+        self.dealt[piece.index()] = self.dealt[piece.index()].saturating_add(1);
+        piece
+    }
+}
+
+impl TetrisGame {
+    /// Rendered cells per logical cell along each axis.
+    pub(super) fn scale(&self) -> usize {
+        if self.big {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Logical board width the game is played on.
+    pub(super) fn cols(&self) -> usize {
+        self.board.cols()
+    }
+
+    /// Logical board height the game is played on, hidden rows included.
+    pub(super) fn rows(&self) -> usize {
+        self.board.rows()
+    }
+
+    /// Whether a `width` x `height` board is supported, leaving at least
+    /// `BOARD_MIN_CELLS` logical cells each way in big mode too.
+    pub(super) fn size_ok(width: usize, height: usize, big: bool) -> bool {
+        let scale = if big { 2 } else { 1 };
+        width <= BOARD_MAX_WIDTH
+            && height <= BOARD_MAX_HEIGHT
+            && width / scale >= BOARD_MIN_CELLS
+            && height / scale >= BOARD_MIN_CELLS
+    }
+
+    /// Switch to a `width` x `height` board. The caller starts a new game.
+    pub(super) fn set_size(&mut self, width: usize, height: usize) -> Result {
+        if !Self::size_ok(width, height, self.big) {
+            return Err(EINVAL);
+        }
+        self.width = width;
+        self.height = height;
+        self.board = Board::new(width / self.scale(), HIDDEN_ROWS + height / self.scale());
+        Ok(())
+    }
+
+    /// Switch big mode on or off. The caller starts a new game.
+    pub(super) fn set_big(&mut self, big: bool) -> Result {
+        if !Self::size_ok(self.width, self.height, big) {
+            return Err(EINVAL);
+        }
+        self.big = big;
+        self.board = Board::new(
+            self.width / self.scale(),
+            HIDDEN_ROWS + self.height / self.scale(),
+        );
+        Ok(())
+    }
+
+    pub(super) fn move_left(&mut self) -> bool {
+        if self.paused() {
+            return false;
+        }
+        if self.awaiting_spawn() {
+            return self.buffer_input(BufferedInput::Left);
+        }
+        if let Some(mut piece) = self.current_piece {
+            piece.x -= 1;
+            if !self.board.collides(&piece) {
+                self.current_piece = Some(piece);
+                self.last_action = LastAction::Shift;
+                self.apply_instant_gravity();
+                self.emit(GameEventKind::Moved, self.current_piece, 0);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub(super) fn move_right(&mut self) -> bool {
+        if self.paused() {
+            return false;
+        }
+        if self.awaiting_spawn() {
+            return self.buffer_input(BufferedInput::Right);
+        }
+        if let Some(mut piece) = self.current_piece {
+            piece.x += 1;
+            if !self.board.collides(&piece) {
+                self.current_piece = Some(piece);
+                self.last_action = LastAction::Shift;
+                self.apply_instant_gravity();
+                self.emit(GameEventKind::Moved, self.current_piece, 0);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub(super) fn move_down(&mut self, stats: &TetrisStats) -> bool {
+        if self.paused() {
+            return false;
+        }
+        if let Some(mut piece) = self.current_piece {
+            piece.y += 1;
+            if !self.board.collides(&piece) {
+                self.current_piece = Some(piece);
+                self.last_action = LastAction::Shift;
+                self.emit(GameEventKind::Moved, self.current_piece, 0);
+                return true;
+            } else {
+                self.lock_piece(stats);
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Rotate the active piece, trying each SRS kick offset before giving up.
+    pub(super) fn rotate(&mut self, dir: Rotation) -> bool {
+        if self.paused() {
+            return false;
+        }
+        if self.awaiting_spawn() {
+            return self.buffer_input(BufferedInput::Rotate(dir));
+        }
+        let Some((piece, kick)) = self
+            .current_piece
+            .and_then(|piece| self.board.rotate(&piece, dir))
+        else {
+            return false;
+        };
+        self.current_piece = Some(piece);
+        self.last_action = LastAction::Rotate { kick };
+        self.apply_instant_gravity();
+        self.emit(GameEventKind::Moved, self.current_piece, 0);
+        true
+    }
+
+    /// Soft drop the active piece, scoring 1 point per cell descended.
+    ///
+    /// A single-step soft drop locks on contact like `move_down`; with
+    /// `to_contact` the piece slides down until it rests on the stack but is
+    /// left unlocked. Returns the number of cells descended.
+    pub(super) fn soft_drop(&mut self, stats: &TetrisStats, to_contact: bool) -> u32 {
+        if self.paused() {
+            return 0;
+        }
+        let mut cells = 0;
+        if to_contact {
+            cells = self.drop_to_contact();
+        } else if self.move_down(stats) {
+            cells = 1;
+        }
+
+        if cells > 0 {
+            self.score = self.score.saturating_add(cells as u64);
+            self.soft_drop_score = self.soft_drop_score.saturating_add(cells as u64);
+            stats
+                .soft_drop_cells
+                .fetch_add(cells as u64, Ordering::Relaxed);
+            stats.score_gained.fetch_add(cells as u64, Ordering::Relaxed);
+        }
+        cells
+    }
+
+    /// Hard drop the active piece and lock it, scoring 2 points per cell.
+    ///
+    /// Returns the drop distance so callers can report it to userspace.
+    pub(super) fn hard_drop(&mut self, stats: &TetrisStats) -> u32 {
+        if self.paused() || self.current_piece.is_none() {
+            return 0;
+        }
+
+        let cells = self.drop_to_contact();
+        if cells > 0 {
+            self.score = self.score.saturating_add(2 * cells as u64);
+            self.hard_drop_score = self.hard_drop_score.saturating_add(2 * cells as u64);
+            stats
+                .hard_drop_cells
+                .fetch_add(cells as u64, Ordering::Relaxed);
+            stats
+                .score_gained
+                .fetch_add(2 * cells as u64, Ordering::Relaxed);
+        }
+
+        self.lock_piece(stats);
+        cells
+    }
+
+    /// Slide the active piece down until it rests on the stack, without
+    /// locking it. Returns the number of cells descended.
+    fn drop_to_contact(&mut self) -> u32 {
+        let cells = self.sink();
+        if cells > 0 {
+            self.last_action = LastAction::Shift;
+            self.emit(GameEventKind::Moved, self.current_piece, 0);
+        }
+        cells
+    }
+
+    /// Move the active piece onto the stack without counting as a player
+    /// manoeuvre.
+    fn sink(&mut self) -> u32 {
+        let mut cells = 0;
+        while let Some(mut piece) = self.current_piece {
+            piece.y += 1;
+            if self.board.collides(&piece) {
+                break;
+            }
+            self.current_piece = Some(piece);
+            cells += 1;
+        }
+        cells
+    }
+
+    /// In 20G mode gravity is applied immediately after every spawn, shift
+    /// and rotation, so kicks and T-spins are judged on the stack surface.
+    pub(super) fn apply_instant_gravity(&mut self) {
+        if self.instant_gravity {
+            self.sink();
+        }
+    }
+
+    fn lock_piece(&mut self, stats: &TetrisStats) {
+        if let Some(piece) = self.current_piece.take() {
+            // Corners must be judged before the piece joins the stack.
+            let tspin = self.board.detect_tspin(&piece, self.last_action);
+            self.board.lock(&piece);
+
+            stats.pieces_locked.fetch_add(1, Ordering::Relaxed);
+            let placed = &mut self.placed[piece.piece_type.index()];
+            *placed = placed.saturating_add(1);
+            self.emit(GameEventKind::Locked, Some(piece), 0);
+            if piece.locks_out() {
+                self.end_game(EndReason::LockOut);
+                return;
+            }
+
+            match tspin {
+                TSpin::Full => {
+                    stats.tspins.fetch_add(1, Ordering::Relaxed);
+                }
+                TSpin::Mini => {
+                    stats.tspin_minis.fetch_add(1, Ordering::Relaxed);
+                }
+                TSpin::None => {}
+            }
+            self.last_tspin = tspin;
+
+            // Leave full rows on screen for the clear delay; the tick
+            // finishes the lock afterwards. A cascade always waits, even
+            // without a delay, as only the slow path settles it.
+            let full = self.board.full_rows();
+            if (self.clear_delay_ns > 0 || self.cascade) && full != 0 {
+                self.flash_rows = full;
+                self.flash_until_ns = Some(self.now_ns() + self.clear_delay_ns);
+                return;
+            }
+
+            self.finish_lock(stats, tspin);
+        }
+    }
+
+    /// Second half of a lock, once the piece is in the stack: clear lines,
+    /// score, trade garbage and move on to the next piece.
+    fn finish_lock(&mut self, stats: &TetrisStats, tspin: TSpin) {
+        let lines = self.board.collapse_full_rows();
+        self.finish_clear(stats, tspin, (lines, (lines > 0) as u32, 0));
+    }
+
+    /// Time until the cascade waiting on the slow path is due to settle:
+    /// `Some(0)` once it is, `None` if there is none or it waits on more
+    /// than time, a resume or, in playback, the replay getting to where the
+    /// recording settled it.
+    pub(super) fn settle_in_ns(&self) -> Option<u64> {
+        let at = self.flash_until_ns.filter(|_| self.cascade && !self.paused())?;
+        match &self.playback {
+            Some(playback) => playback.settle.then_some(0),
+            None => Some(at.saturating_sub(self.now_ns())),
+        }
+    }
+
+    /// Finish a cascade lock with `board`, a copy of the stack it locked
+    /// into after `Board::cascade()` returned `cleared`. Settling takes too
+    /// long to do under the game lock, so the slow path does it on a copy.
+    pub(super) fn finish_cascade(
+        &mut self,
+        stats: &TetrisStats,
+        board: Board,
+        cleared: (u32, u32, u64),
+    ) {
+        self.board = board;
+        self.flash_until_ns = None;
+        self.flash_rows = 0;
+        if let Some(playback) = self.playback.as_mut() {
+            playback.settle = false;
+        }
+        self.finish_clear(stats, self.last_tspin, cleared);
+    }
+
+    /// The rest of a lock once its lines are gone, `cleared` as
+    /// `Board::cascade()` returns it: score, trade garbage and move on to
+    /// the next piece.
+    fn finish_clear(&mut self, stats: &TetrisStats, tspin: TSpin, cleared: (u32, u32, u64)) {
+        let (lines, score_delta) = self.score_lines(tspin, cleared);
+        if self.perfect_clear {
+            stats.perfect_clears.fetch_add(1, Ordering::Relaxed);
+        }
+        if lines > 0 {
+            stats.lines_cleared.fetch_add(lines as u64, Ordering::Relaxed);
+        }
+        if self.mode == GameMode::Sprint && self.lines >= SPRINT_LINES {
+            self.end_game(EndReason::Cleared);
+            let time = self.elapsed_ns();
+            stats.sprints_completed.fetch_add(1, Ordering::Relaxed);
+            let _ = stats
+                .sprint_best_ns
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |best| {
+                    (best == 0 || time < best).then_some(time)
+                });
+        }
+        if score_delta > 0 {
+            stats
+                .score_gained
+                .fetch_add(score_delta, Ordering::Relaxed);
+        }
+
+        // Outgoing attack cancels incoming garbage first; only a lock
+        // that clears nothing lets the rest of the garbage in.
+        let attack = attack(lines, tspin, self.combo, self.perfect_clear);
+        let cancelled = attack.min(self.garbage_pending);
+        self.garbage_pending -= cancelled;
+        if attack > cancelled {
+            self.attack_out = self.attack_out.saturating_add(attack - cancelled);
+            stats
+                .garbage_sent
+                .fetch_add((attack - cancelled) as u64, Ordering::Relaxed);
+        }
+        if lines == 0 && self.garbage_pending > 0 {
+            let n = self.garbage_pending;
+            self.garbage_pending = 0;
+            stats.garbage_received.fetch_add(n as u64, Ordering::Relaxed);
+            self.raise_garbage(n);
+        }
+
+        self.schedule_spawn(stats);
+    }
+
+    /// Raise `n` rows of garbage with a hole in one random column, the same
+    /// for the whole batch. Cells pushed off the top end the game.
+    fn raise_garbage(&mut self, n: u32) {
+        let hole = self.prng.next_range(self.cols() as u32);
+        if self.board.raise_garbage(n, hole) {
+            self.end_game(EndReason::TopOut);
+        }
+    }
+
+    /// Score the lines a lock cleared, as `finish_clear()` takes them.
+    /// Returns the lines and the points scored for them.
+    fn score_lines(&mut self, tspin: TSpin, cleared: (u32, u32, u64)) -> (u32, u64) {
+        let (lines_cleared, chain, chain_bonus) = cleared;
+        self.chain = chain;
+        self.combo = next_combo(self.combo, lines_cleared);
+        self.max_combo = self.max_combo.max(self.combo);
+        // Clearing lines is the only way the board can end up empty.
+        self.perfect_clear = lines_cleared > 0 && self.board.is_empty();
+
+        // Scored at the level the clear happened on, then level up.
+        let score_delta = lock_score(
+            tspin,
+            lines_cleared,
+            chain_bonus,
+            self.combo,
+            self.perfect_clear,
+        )
+        .saturating_mul(self.level as u64);
+        self.score = self.score.saturating_add(score_delta);
+        self.lines += lines_cleared;
+        let level = self.level;
+        self.level = level_for(self.level, self.lines);
+        if lines_cleared > 0 {
+            self.emit(GameEventKind::LinesCleared, None, lines_cleared);
+        }
+        if self.level != level {
+            self.emit(GameEventKind::LevelUp, None, self.level);
+        }
+
+        (lines_cleared, score_delta)
+    }
+
+    /// Time between gravity steps at the current level.
+    pub(super) fn gravity_period_ns(&self) -> u64 {
+        if self.instant_gravity {
+            // Pieces are always grounded, so the tick only ever locks: the
+            // lock delay is how long a grounded piece may still move.
+            return sysctl_ns(tetris_sysctl_lock_delay_ms);
+        }
+        // The curves are for a first period of `GRAVITY_MS[0]`.
+        self.gravity_curve.period_ns(self.level) * sysctl_ns(tetris_sysctl_gravity_base_ms)
+            / (GRAVITY_MS[0] as u64 * 1_000_000)
+    }
+
+    /// Whether the next gravity tick may do more than move a piece and lock
+    /// it: a demo tick searches for the bot's placement. The gravity timer
+    /// leaves those ticks to process context.
+    pub(super) fn tick_is_slow(&self) -> bool {
+        self.demo
+    }
+
+    /// Time until the next tick is due: the gravity period, or the rest of
+    /// the entry delay if that ends sooner.
+    pub(super) fn next_tick_ns(&self) -> u64 {
+        let period = self.gravity_period_ns();
+        match self.flash_until_ns.or(self.spawn_at_ns) {
+            Some(at) => at.saturating_sub(self.now_ns()).clamp(1, period),
+            None => period,
+        }
+    }
+}
+
+/// What the bot places the active piece from, copied out of the game so
+/// the search, a BPF bot's above all, runs after letting go of the game
+/// lock. The placement only holds while the game is still at `frame_gen`.
+pub(super) struct BotView {
+    pub(super) board: Board,
+    /// `board` as `TetrisGame::row_masks()`.
+    pub(super) masks: [u16; BOARD_MAX_ROWS],
+    pub(super) piece: Tetromino,
+    pub(super) preview: [TetrominoType; PREVIEW_MAX],
+    pub(super) preview_count: usize,
+    bot_weights: BotWeights,
+    pub(super) frame_gen: u64,
+}
+
+impl BotView {
+    /// `None` if there is no active piece.
+    pub(super) fn new(game: &TetrisGame) -> Option<Self> {
+        Some(Self {
+            board: game.board,
+            masks: game.row_masks(),
+            piece: game.current_piece?,
+            preview: game.preview,
+            preview_count: game.preview_count,
+            bot_weights: game.bot_weights,
+            frame_gen: game.frame_gen,
+        })
+    }
+
+    /// Best place for the active piece as `(rotation, x)`, judged by
+    /// `bot_weights` after dropping it straight down from its spawn column.
+    /// `None` if it fits nowhere.
+    fn best_placement(&self) -> Option<(u8, i32)> {
+        let mut best = None;
+        let mut best_score = i32::MIN;
+
+        for rotation in 0..4 {
+            for x in -3..self.board.cols() as i32 {
+                let mut candidate = Tetromino {
+                    rotation,
+                    x,
+                    ..self.piece
+                };
+                if self.board.collides(&candidate) {
+                    continue;
+                }
+                loop {
+                    candidate.y += 1;
+                    if self.board.collides(&candidate) {
+                        candidate.y -= 1;
+                        break;
+                    }
+                }
+
+                let score = self.score_placement(&candidate);
+                if score > best_score {
+                    best_score = score;
+                    best = Some((rotation, x));
+                }
+            }
+        }
+        best
+    }
+
+    /// Where the bot puts the active piece: an attached BPF bot's choice if
+    /// it gives a legal one, otherwise `best_placement()`.
+    pub(super) fn placement(&self) -> Option<(u8, i32)> {
+        #[cfg(all(CONFIG_BPF_JIT, CONFIG_DEBUG_INFO_BTF_MODULES))]
+        if let Some(placement) = super::bpf::placement(self) {
+            return Some(placement);
+        }
+        self.best_placement()
+    }
+
+    /// `placement()` as the leftmost column covered and the rotation.
+    pub(super) fn hint(&self) -> Option<(u32, u8)> {
+        let (rotation, x) = self.placement()?;
+        let piece = Tetromino {
+            rotation,
+            x,
+            ..self.piece
+        };
+        let (min_x, _, _, _) = piece.get_bounds();
+        Some(((x + min_x) as u32, rotation))
+    }
+
+    /// Score the board that locking `piece` where it is would leave.
+    fn score_placement(&self, piece: &Tetromino) -> i32 {
+        let (cols, rows) = (self.board.cols(), self.board.rows());
+        let full: u16 = ((1u32 << cols) - 1) as u16;
+
+        let mut masks = self.masks;
+        for (i, &row) in piece.get_masks().iter().enumerate() {
+            if row != 0 {
+                masks[(piece.y + i as i32) as usize] |=
+                    self.board.place_row(row, piece.x).unwrap_or(0);
+            }
+        }
+
+        // Drop full rows, keeping the rest packed against the floor.
+        let mut lines = 0;
+        let mut dest = rows;
+        for y in (0..rows).rev() {
+            if masks[y] == full {
+                lines += 1;
+                continue;
+            }
+            dest -= 1;
+            masks[dest] = masks[y];
+        }
+        for mask in &mut masks[..dest] {
+            *mask = 0;
+        }
+
+        let (mut height, mut holes, mut bumpiness) = (0, 0, 0);
+        let mut prev_height: Option<i32> = None;
+        for x in 0..cols {
+            let bit = 1u16 << x;
+            let top = (0..rows).find(|&y| masks[y] & bit != 0).unwrap_or(rows);
+            let column_height = (rows - top) as i32;
+            height += column_height;
+            holes += (top..rows).filter(|&y| masks[y] & bit == 0).count() as i32;
+            if let Some(prev) = prev_height {
+                bumpiness += (column_height - prev).abs();
+            }
+            prev_height = Some(column_height);
+        }
+
+        let w = &self.bot_weights;
+        w.lines
+            .saturating_mul(lines)
+            .saturating_add(w.height.saturating_mul(height))
+            .saturating_add(w.holes.saturating_mul(holes))
+            .saturating_add(w.bumpiness.saturating_mul(bumpiness))
+    }
+}
+
+impl TetrisGame {
+    /// The board's `Board::mask()`s, as an array to play moves out on.
+    pub(super) fn row_masks(&self) -> [u16; BOARD_MAX_ROWS] {
+        let mut masks = [0u16; BOARD_MAX_ROWS];
+        let rows = self.rows();
+        masks[..rows].copy_from_slice(&self.board.masks()[..rows]);
+        masks
+    }
+
+    /// Note that the frame may have changed, so readers should look again.
+    pub(super) fn frame_changed(&mut self) {
+        self.frame_gen = self.frame_gen.wrapping_add(1);
+    }
+
+    /// Add an event to the ring for `/dev/tetris-events`. Readers wait on
+    /// the frame, so it counts as a frame change.
+    fn emit(&mut self, kind: GameEventKind, piece: Option<Tetromino>, value: u32) {
+        let slot = (self.event_seq % EVENT_RING_LEN as u64) as usize;
+        self.events[slot] = GameEvent {
+            time_ns: ktime_now_ns(),
+            kind,
+            piece,
+            value,
+        };
+        self.event_seq += 1;
+        self.frame_changed();
+    }
+
+    /// Encode the events from `*next` on that are still in the ring, at
+    /// most `max`, into `records`, and move `*next` past them. `records`
+    /// must have room for them already, as it is filled under the game lock.
+    pub(super) fn read_events(&self, next: &mut u64, max: usize, records: &mut KVec<u8>) -> Result {
+        let oldest = self.event_seq.saturating_sub(EVENT_RING_LEN as u64);
+        let from = (*next).max(oldest);
+        let count = usize::try_from(self.event_seq - from).unwrap_or(usize::MAX).min(max);
+
+        for seq in from..from + count as u64 {
+            let event = &self.events[(seq % EVENT_RING_LEN as u64) as usize];
+            records.extend_from_slice(&event.to_bytes(seq), GFP_NOWAIT)?;
+        }
+        *next = from + count as u64;
+        Ok(())
+    }
+
+    /// Record a real input, ending any demo in favour of a fresh game.
+    pub(super) fn note_input(&mut self, stats: &TetrisStats) {
+        self.last_input_ns = ktime_now_ns();
+        if self.demo {
+            self.demo = false;
+            self.reset(stats);
+        }
+        if self.stop_playback().is_some() {
+            self.reset(stats);
+        }
+    }
+
+    /// Start a demo if nobody has touched the game for `demo_idle_secs`.
+    pub(super) fn check_demo_idle(&mut self, stats: &TetrisStats) {
+        let idle_secs = *crate::module_parameters::demo_idle_secs.value();
+        if self.demo || self.playback.is_some() || self.autopilot_ms != 0 || idle_secs == 0 {
+            return;
+        }
+        if ktime_now_ns().saturating_sub(self.last_input_ns) < idle_secs as u64 * 1_000_000_000 {
+            return;
+        }
+        self.start_demo(stats);
+    }
+
+    fn start_demo(&mut self, stats: &TetrisStats) {
+        stats.demo_games.fetch_add(1, Ordering::Relaxed);
+        self.reset(stats);
+        self.demo = true;
+    }
+
+    /// One demo tick, in place of gravity. A finished demo game is followed
+    /// by another. `placement` is for `bot_step()`.
+    pub(super) fn demo_step(&mut self, stats: &TetrisStats, placement: Option<(u8, i32)>) {
+        if self.game_over {
+            self.start_demo(stats);
+            return;
+        }
+        self.bot_step(stats, placement);
+    }
+
+    /// One autopilot move. A lost game is restarted. `placement` is for
+    /// `bot_step()`.
+    pub(super) fn autopilot_step(&mut self, stats: &TetrisStats, placement: Option<(u8, i32)>) {
+        if self.paused() {
+            return;
+        }
+        if self.game_over {
+            stats.resets.fetch_add(1, Ordering::Relaxed);
+            self.reset(stats);
+            return;
+        }
+        self.bot_step(stats, placement);
+    }
+
+    /// The copy to search for the bot's next step, or `None` if that step
+    /// doesn't place a piece: there is none, or it is lined up already.
+    pub(super) fn bot_view(&self) -> Option<BotView> {
+        if self.bot_aligned || self.paused() {
+            return None;
+        }
+        BotView::new(self)
+    }
+
+    /// Steer the new piece to `placement`, what `BotView::placement()` gave
+    /// for `bot_view()`, then hard drop it on the following step so readers
+    /// see it line up.
+    fn bot_step(&mut self, stats: &TetrisStats, placement: Option<(u8, i32)>) {
+        if self.current_piece.is_none() {
+            return;
+        }
+        if self.bot_aligned {
+            self.bot_aligned = false;
+            self.hard_drop(stats);
+            return;
+        }
+
+        if let Some((rotation, x)) = placement {
+            for _ in 0..4 {
+                if self.current_piece.is_some_and(|p| p.rotation % 4 == rotation) {
+                    break;
+                }
+                self.rotate(Rotation::Cw);
+            }
+            for _ in 0..self.cols() {
+                let moved = match self.current_piece {
+                    Some(p) if p.x < x => self.move_right(),
+                    Some(p) if p.x > x => self.move_left(),
+                    _ => false,
+                };
+                if !moved {
+                    break;
+                }
+            }
+        }
+        self.bot_aligned = true;
+    }
+}
+
+/// What a written key does, as numbered in `TETRIS_IOCTL_SET_KEYMAP`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum KeyAction {
+    Left = 1,
+    Right = 2,
+    SoftDrop = 3,
+    SoftDropToFloor = 4,
+    RotateCw = 5,
+    RotateCcw = 6,
+    HardDrop = 7,
+    Hold = 8,
+    Reset = 9,
+    Pause = 10,
+    End = 11,
+}
+
+impl KeyAction {
+    const ALL: [KeyAction; 11] = [
+        KeyAction::Left,
+        KeyAction::Right,
+        KeyAction::SoftDrop,
+        KeyAction::SoftDropToFloor,
+        KeyAction::RotateCw,
+        KeyAction::RotateCcw,
+        KeyAction::HardDrop,
+        KeyAction::Hold,
+        KeyAction::Reset,
+        KeyAction::Pause,
+        KeyAction::End,
+    ];
+
+    fn from_raw(raw: u8) -> Option<Self> {
+        Self::ALL.get((raw as usize).checked_sub(1)?).copied()
+    }
+
+    /// Name in the debugfs `keymap` file.
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            KeyAction::Left => "left",
+            KeyAction::Right => "right",
+            KeyAction::SoftDrop => "down",
+            KeyAction::SoftDropToFloor => "floor",
+            KeyAction::RotateCw => "rotate",
+            KeyAction::RotateCcw => "rotate_ccw",
+            KeyAction::HardDrop => "drop",
+            KeyAction::Hold => "hold",
+            KeyAction::Reset => "reset",
+            KeyAction::Pause => "pause",
+            KeyAction::End => "end",
+        }
+    }
+
+    pub(super) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|action| action.name() == name)
+    }
+}
+
+/// Which `KeyAction` each byte written does, 0 for none.
+#[derive(Clone, Copy)]
+pub(super) struct KeyMap([u8; 256]);
+
+/// The bindings the module ships with; letters work in either case.
+pub(super) const DEFAULT_KEYMAP: KeyMap = {
+    let bindings = [
+        (b'a', KeyAction::Left),
+        (b'd', KeyAction::Right),
+        (b's', KeyAction::SoftDrop),
+        (b'x', KeyAction::SoftDropToFloor),
+        (b'w', KeyAction::RotateCw),
+        (b'z', KeyAction::RotateCcw),
+        (b' ', KeyAction::HardDrop),
+        (b'c', KeyAction::Hold),
+        (b'r', KeyAction::Reset),
+        (b'p', KeyAction::Pause),
+        (b'q', KeyAction::End),
+    ];
+    let mut map = [0u8; 256];
+    let mut i = 0;
+    while i < bindings.len() {
+        let (key, action) = bindings[i];
+        map[key as usize] = action as u8;
+        map[key.to_ascii_uppercase() as usize] = action as u8;
+        i += 1;
+    }
+    KeyMap(map)
+};
+
+impl KeyMap {
+    /// Check a map from userspace: every entry a `KeyAction` or 0, and
+    /// nothing bound that the write path takes first.
+    pub(super) fn from_raw(raw: [u8; 256]) -> Result<Self> {
+        let mut map = KeyMap([0; 256]);
+        for (key, &action) in raw.iter().enumerate() {
+            let action = match action {
+                0 => None,
+                raw => Some(KeyAction::from_raw(raw).ok_or(EINVAL)?),
+            };
+            map.bind(key as u8, action)?;
+        }
+        Ok(map)
+    }
+
+    pub(super) fn action(&self, key: u8) -> Option<KeyAction> {
+        KeyAction::from_raw(self.0[key as usize])
+    }
+
+    /// Bind `key` to `action`, or unbind it for `None`.
+    pub(super) fn bind(&mut self, key: u8, action: Option<KeyAction>) -> Result {
+        if action.is_some() && matches!(key, b'1'..=b'9' | 0x1b) {
+            return Err(EINVAL);
+        }
+        self.0[key as usize] = action.map_or(0, |action| action as u8);
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Drawing the game: text frames from a `GameView` and binary frames.
+
+use kernel::prelude::*;
+
+use super::tetris_sysctl_render_flags;
+use super::engine::{ktime_now_ns, EndReason, GameMode, TetrisGame, PREVIEW_MAX, SPRINT_LINES};
+use super::rules::{Board, Cell, TetrominoType, BOARD_MAX_ROWS, BOARD_MAX_WIDTH, HIDDEN_ROWS};
+
+/// Fits the frame for the largest board, colored.
+pub(super) const RENDER_BUFFER_SIZE: usize = 16384;
+
+/// Cleared rows blink on and off at this period during the clear delay.
+const FLASH_BLINK_NS: u64 = 50_000_000;
+
+/// Layout version of binary frames.
+const BINARY_FRAME_VERSION: u16 = 1;
+
+/// Size of a binary frame: header, then one byte per cell.
+pub(super) const BINARY_FRAME_LEN: usize = 40 + BOARD_MAX_ROWS * BOARD_MAX_WIDTH;
+
+/// SGR sequence ending a colored run.
+const ANSI_RESET: &[u8] = b"\x1b[0m";
+
+/// Erase the display, then move the cursor to the top left.
+const VT100_CLEAR_HOME: &[u8] = b"\x1b[2J\x1b[H";
+
+/// Strings the board frame is drawn with. Cells are two columns wide;
+/// `horizontal` is written twice per cell.
+struct Glyphs {
+    top_left: &'static [u8],
+    top_right: &'static [u8],
+    bottom_left: &'static [u8],
+    bottom_right: &'static [u8],
+    horizontal: &'static [u8],
+    vertical: &'static [u8],
+    filled: &'static [u8],
+    garbage: &'static [u8],
+    empty: &'static [u8],
+}
+
+const GLYPHS_UTF8: Glyphs = Glyphs {
+    top_left: b"\xE2\x95\x94",
+    top_right: b"\xE2\x95\x97",
+    bottom_left: b"\xE2\x95\x9A",
+    bottom_right: b"\xE2\x95\x9D",
+    horizontal: b"\xE2\x95\x90",
+    vertical: b"\xE2\x95\x91",
+    filled: b"\xE2\x96\x88\xE2\x96\x88",
+    garbage: b"\xE2\x96\x88\xE2\x96\x88",
+    empty: b"  ",
+};
+
+const GLYPHS_ASCII: Glyphs = Glyphs {
+    top_left: b"+",
+    top_right: b"+",
+    bottom_left: b"+",
+    bottom_right: b"+",
+    horizontal: b"-",
+    vertical: b"|",
+    filled: b"[]",
+    garbage: b"##",
+    empty: b"  ",
+};
+
+impl TetrisGame {
+    /// Lay the game out as a binary frame (see `TETRIS_IOCTL_SET_FORMAT`).
+    /// `buffer` must hold `BINARY_FRAME_LEN` bytes.
+    pub(super) fn render_binary(&self, buffer: &mut [u8]) -> usize {
+        let frame = &mut buffer[..BINARY_FRAME_LEN];
+        frame.fill(0);
+
+        frame[0..4].copy_from_slice(b"TFRM");
+        frame[4..6].copy_from_slice(&BINARY_FRAME_VERSION.to_le_bytes());
+        frame[6] = self.cols() as u8;
+        frame[7] = self.rows() as u8;
+        frame[8..16].copy_from_slice(&self.score.to_le_bytes());
+        frame[16..20].copy_from_slice(&self.lines.to_le_bytes());
+        frame[20..24].copy_from_slice(&self.level.to_le_bytes());
+        frame[24] = self.game_over as u8 | (self.paused() as u8) << 1;
+        frame[25] = HIDDEN_ROWS as u8;
+        frame[26] = self.preview_count as u8;
+        match self.current_piece {
+            Some(piece) => frame[28..32].copy_from_slice(&[
+                piece.piece_type.index() as u8,
+                piece.rotation % 4,
+                piece.x as i8 as u8,
+                piece.y as i8 as u8,
+            ]),
+            None => frame[28] = 0xff,
+        }
+        for (byte, piece) in frame[32..37].iter_mut().zip(&self.preview) {
+            *byte = piece.index() as u8;
+        }
+
+        for y in 0..self.rows() {
+            let row = &mut frame[40 + y * BOARD_MAX_WIDTH..][..self.cols()];
+            for (byte, cell) in row.iter_mut().zip(&self.board[y][..self.cols()]) {
+                *byte = cell.to_raw();
+            }
+        }
+        BINARY_FRAME_LEN
+    }
+
+    /// Until when, on `ktime_now_ns()`, the text frame stays what rendering
+    /// it again would give while `frame_gen` stays put: the clock in it
+    /// moves on at the precision it is shown, `m:ss.cc` while a sprint is
+    /// on and `mm:ss` otherwise, and cleared rows blink.
+    fn frame_valid_until_ns(&self) -> u64 {
+        if self.flash_rows != 0 {
+            return 0;
+        }
+        // Stopped, or only moved on by replayed inputs.
+        if self.game_over || self.paused() || self.clock_ns.is_some() {
+            return u64::MAX;
+        }
+        let tick_ns = if self.mode == GameMode::Sprint {
+            10_000_000
+        } else {
+            1_000_000_000
+        };
+        ktime_now_ns() + (tick_ns - self.elapsed_ns() % tick_ns)
+    }
+
+    pub(super) fn write_bytes(buffer: &mut [u8], pos: usize, bytes: &[u8]) -> usize {
+        let mut written = 0;
+        for &byte in bytes {
+            if pos + written < buffer.len() {
+                buffer[pos + written] = byte;
+                written += 1;
+            } else {
+                break;
+            }
+        }
+        written
+    }
+
+    /// Write a duration as `m:ss.cc`.
+    fn write_time(buffer: &mut [u8], pos: usize, ns: u64) -> usize {
+        let centis = ns / 10_000_000;
+        let secs = (centis / 100 % 60) as u8;
+        let frac = (centis % 100) as u8;
+
+        let mut written = Self::write_number(buffer, pos, (centis / 6000) as u32);
+        written += Self::write_bytes(
+            buffer,
+            pos + written,
+            &[
+                b':',
+                b'0' + secs / 10,
+                b'0' + secs % 10,
+                b'.',
+                b'0' + frac / 10,
+                b'0' + frac % 10,
+            ],
+        );
+        written
+    }
+
+    /// Write `ns` as mm:ss, with minutes growing past two digits if needed.
+    fn write_clock(buffer: &mut [u8], pos: usize, ns: u64) -> usize {
+        let secs = ns / 1_000_000_000;
+        let mins = secs / 60;
+        let secs = (secs % 60) as u8;
+
+        let mut written = 0;
+        if mins < 10 {
+            written += Self::write_bytes(buffer, pos, b"0");
+        }
+        written += Self::write_number(buffer, pos + written, mins as u32);
+        written += Self::write_bytes(
+            buffer,
+            pos + written,
+            &[b':', b'0' + secs / 10, b'0' + secs % 10],
+        );
+        written
+    }
+
+    pub(super) fn write_number(buffer: &mut [u8], pos: usize, num: impl Into<u64>) -> usize {
+        let mut num = num.into();
+        let mut digits = [0u8; 20];
+        let mut digit_count = 0;
+
+        if num == 0 {
+            digits[0] = b'0';
+            digit_count = 1;
+        } else {
+            while num > 0 && digit_count < 20 {
+                digits[digit_count] = (num % 10) as u8 + b'0';
+                num /= 10;
+                digit_count += 1;
+            }
+        }
+
+        let mut written = 0;
+        for i in (0..digit_count).rev() {
+            if pos + written < buffer.len() {
+                buffer[pos + written] = digits[i];
+                written += 1;
+            }
+        }
+        written
+    }
+}
+
+/// What a text frame shows, copied out of the game under the game lock so
+/// the frame is drawn after letting go of it.
+pub(super) struct GameView {
+    board: Board,
+    width: usize,
+    height: usize,
+    scale: usize,
+    cols: usize,
+    rows: usize,
+    big: bool,
+    /// The active piece's type, top row, and row masks on the board.
+    piece: Option<(TetrominoType, i32, [u16; 4])>,
+    flash_rows: u64,
+    paused: bool,
+    elapsed_ns: u64,
+    score: u64,
+    high_score: u64,
+    level: u32,
+    lines: u32,
+    instant_gravity: bool,
+    preview: [TetrominoType; PREVIEW_MAX],
+    preview_count: usize,
+    hold: Option<TetrominoType>,
+    placed: [u32; 7],
+    perfect_clear: bool,
+    garbage_pending: u32,
+    chain: u32,
+    combo: i32,
+    demo: bool,
+    /// A replay is being played back.
+    playback: bool,
+    replay_verdict: Option<bool>,
+    mode: GameMode,
+    game_over: bool,
+    end_reason: Option<EndReason>,
+    /// `frame_gen` at the copy.
+    pub(super) frame_gen: u64,
+    /// `TetrisGame::frame_valid_until_ns()` at the copy.
+    pub(super) valid_until_ns: u64,
+}
+
+impl GameView {
+    pub(super) fn new(game: &TetrisGame) -> Self {
+        // The active piece is drawn over the stack from its row masks as
+        // each cell is written, rather than into a copy of the board.
+        let piece = game.current_piece.map(|piece| {
+            let rows = piece
+                .get_masks()
+                .map(|row| game.board.place_row(row, piece.x).unwrap_or(0));
+            (piece.piece_type, piece.y, rows)
+        });
+        Self {
+            board: game.board,
+            width: game.width,
+            height: game.height,
+            scale: game.scale(),
+            cols: game.cols(),
+            rows: game.rows(),
+            big: game.big,
+            piece,
+            flash_rows: game.flash_rows,
+            paused: game.paused(),
+            elapsed_ns: game.elapsed_ns(),
+            score: game.score,
+            high_score: game.high_score,
+            level: game.level,
+            lines: game.lines,
+            instant_gravity: game.instant_gravity,
+            preview: game.preview,
+            preview_count: game.preview_count,
+            hold: game.hold,
+            placed: game.placed,
+            perfect_clear: game.perfect_clear,
+            garbage_pending: game.garbage_pending,
+            chain: game.chain,
+            combo: game.combo,
+            demo: game.demo,
+            playback: game.playback.is_some(),
+            replay_verdict: game.replay_verdict,
+            mode: game.mode,
+            game_over: game.game_over,
+            end_reason: game.end_reason,
+            frame_gen: game.frame_gen,
+            valid_until_ns: game.frame_valid_until_ns(),
+        }
+    }
+
+    pub(super) fn render_to_buffer(&self, options: &RenderOptions, buffer: &mut [u8]) -> usize {
+        let mut pos = 0;
+
+        for i in 0..buffer.len() {
+            buffer[i] = b' ';
+        }
+
+        // Rows waiting to be cleared blink.
+        let blink_off = (ktime_now_ns() / FLASH_BLINK_NS) % 2 == 1;
+
+        if options.clear_screen {
+            pos += TetrisGame::write_bytes(buffer, pos, VT100_CLEAR_HOME);
+        }
+
+        let g = if options.ascii { &GLYPHS_ASCII } else { &GLYPHS_UTF8 };
+
+        pos += TetrisGame::write_bytes(buffer, pos, g.top_left);
+        for _ in 0..self.width {
+            pos += TetrisGame::write_bytes(buffer, pos, g.horizontal);
+            pos += TetrisGame::write_bytes(buffer, pos, g.horizontal);
+        }
+        pos += TetrisGame::write_bytes(buffer, pos, g.top_right);
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        let scale = self.scale;
+        for y in 0..self.height {
+            pos += TetrisGame::write_bytes(buffer, pos, g.vertical);
+            if self.paused {
+                // Hide the field so pausing can't be used to plan ahead.
+                let text: &[u8] = if y == self.height / 2 { b"PAUSED" } else { b"" };
+                let pad = self.width * 2 - text.len();
+                for _ in 0..pad / 2 {
+                    pos += TetrisGame::write_bytes(buffer, pos, b" ");
+                }
+                pos += TetrisGame::write_bytes(buffer, pos, text);
+                for _ in 0..pad - pad / 2 {
+                    pos += TetrisGame::write_bytes(buffer, pos, b" ");
+                }
+                pos += TetrisGame::write_bytes(buffer, pos, g.vertical);
+                pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+                continue;
+            }
+            // An odd size in big mode leaves a last half row and column blank.
+            let board_y = HIDDEN_ROWS + y / scale;
+            let in_field = board_y < self.rows;
+            let stack_shown = in_field
+                && !options.invisible
+                && !(blink_off && self.flash_rows & (1 << board_y) != 0);
+            let piece_row = self.piece.and_then(|(piece_type, piece_y, rows)| {
+                let i = usize::try_from(board_y as i32 - piece_y).ok()?;
+                Some((piece_type, *rows.get(i)?))
+            });
+
+            // Only emit an escape code where the color changes.
+            let mut color: &[u8] = b"";
+            for x in 0..self.width {
+                let board_x = x / scale;
+                let cell = match piece_row {
+                    _ if !in_field || board_x >= self.cols => Cell::Empty,
+                    Some((piece_type, mask)) if mask & 1 << board_x != 0 => {
+                        Cell::Piece(piece_type)
+                    }
+                    _ if stack_shown => self.board[board_y][board_x],
+                    _ => Cell::Empty,
+                };
+                if options.color && cell.ansi_color() != color {
+                    color = cell.ansi_color();
+                    let sgr = if color.is_empty() { ANSI_RESET } else { color };
+                    pos += TetrisGame::write_bytes(buffer, pos, sgr);
+                }
+                let bytes = match cell {
+                    Cell::Empty => g.empty,
+                    Cell::Piece(_) => g.filled,
+                    Cell::Garbage => g.garbage,
+                };
+                pos += TetrisGame::write_bytes(buffer, pos, bytes);
+            }
+            if !color.is_empty() {
+                pos += TetrisGame::write_bytes(buffer, pos, ANSI_RESET);
+            }
+            pos += TetrisGame::write_bytes(buffer, pos, g.vertical);
+            pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+        }
+
+        pos += TetrisGame::write_bytes(buffer, pos, g.bottom_left);
+        for _ in 0..self.width {
+            pos += TetrisGame::write_bytes(buffer, pos, g.horizontal);
+            pos += TetrisGame::write_bytes(buffer, pos, g.horizontal);
+        }
+        pos += TetrisGame::write_bytes(buffer, pos, g.bottom_right);
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        pos += TetrisGame::write_bytes(buffer, pos, b"Score: ");
+        pos += TetrisGame::write_number(buffer, pos, self.score);
+        pos += TetrisGame::write_bytes(buffer, pos, b"  High: ");
+        pos += TetrisGame::write_number(buffer, pos, self.high_score);
+        pos += TetrisGame::write_bytes(buffer, pos, b"  Time: ");
+        pos += TetrisGame::write_clock(buffer, pos, self.elapsed_ns);
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        pos += TetrisGame::write_bytes(buffer, pos, b"Level: ");
+        pos += TetrisGame::write_number(buffer, pos, self.level);
+        pos += TetrisGame::write_bytes(buffer, pos, b"  Lines: ");
+        pos += TetrisGame::write_number(buffer, pos, self.lines);
+        if self.instant_gravity {
+            pos += TetrisGame::write_bytes(buffer, pos, b"  20G");
+        }
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        pos += TetrisGame::write_bytes(buffer, pos, b"Next: ");
+        for i in 0..self.preview_count {
+            if i > 0 {
+                pos += TetrisGame::write_bytes(buffer, pos, b" ");
+            }
+            pos += TetrisGame::write_bytes(buffer, pos, &[self.preview[i].letter()]);
+        }
+        if let Some(piece) = self.hold {
+            pos += TetrisGame::write_bytes(buffer, pos, b"  Hold: ");
+            pos += TetrisGame::write_bytes(buffer, pos, &[piece.letter()]);
+        }
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        pos += TetrisGame::write_bytes(buffer, pos, b"Pieces:");
+        for piece in TetrominoType::ALL {
+            pos += TetrisGame::write_bytes(buffer, pos, &[b' ', piece.letter()]);
+            pos += TetrisGame::write_number(buffer, pos, self.placed[piece.index()]);
+        }
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        if self.perfect_clear {
+            pos += TetrisGame::write_bytes(buffer, pos, b"PERFECT CLEAR!\n");
+        }
+
+        if self.garbage_pending > 0 {
+            pos += TetrisGame::write_bytes(buffer, pos, b"Garbage: ");
+            pos += TetrisGame::write_number(buffer, pos, self.garbage_pending);
+            pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+        }
+
+        if self.chain > 1 {
+            pos += TetrisGame::write_bytes(buffer, pos, b"Chain: ");
+            pos += TetrisGame::write_number(buffer, pos, self.chain);
+            pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+        }
+
+        if self.combo > 0 {
+            pos += TetrisGame::write_bytes(buffer, pos, b"Combo: ");
+            pos += TetrisGame::write_number(buffer, pos, self.combo as u32);
+            pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+        }
+
+        if self.demo {
+            pos += TetrisGame::write_bytes(buffer, pos, b"DEMO - press any key\n");
+        }
+        if self.playback {
+            pos += TetrisGame::write_bytes(buffer, pos, b"REPLAY\n");
+        } else if let Some(matched) = self.replay_verdict {
+            let verdict: &[u8] = if matched { b"REPLAY OK\n" } else { b"REPLAY MISMATCH\n" };
+            pos += TetrisGame::write_bytes(buffer, pos, verdict);
+        }
+
+        pos += TetrisGame::write_bytes(buffer, pos, b"Mode: ");
+        pos += TetrisGame::write_bytes(buffer, pos, self.mode.name());
+        if options.invisible {
+            pos += TetrisGame::write_bytes(buffer, pos, b" (invisible)");
+        }
+        if self.big {
+            pos += TetrisGame::write_bytes(buffer, pos, b" (big)");
+        }
+        if self.mode == GameMode::Sprint && !self.game_over {
+            pos += TetrisGame::write_bytes(buffer, pos, b"  ");
+            pos += TetrisGame::write_number(buffer, pos, self.lines);
+            pos += TetrisGame::write_bytes(buffer, pos, b"/");
+            pos += TetrisGame::write_number(buffer, pos, SPRINT_LINES);
+            pos += TetrisGame::write_bytes(buffer, pos, b"  Time: ");
+            pos += TetrisGame::write_time(buffer, pos, self.elapsed_ns);
+        }
+        pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+
+        if self.game_over {
+            let banner: &[u8] = match self.end_reason {
+                Some(EndReason::Cleared) => b"SPRINT COMPLETE!\n",
+                Some(EndReason::TimeUp) => b"TIME UP!\n",
+                _ => b"GAME OVER!\n",
+            };
+            pos += TetrisGame::write_bytes(buffer, pos, banner);
+
+            pos += TetrisGame::write_bytes(buffer, pos, b"Final score: ");
+            pos += TetrisGame::write_number(buffer, pos, self.score);
+            pos += TetrisGame::write_bytes(buffer, pos, b"  Lines: ");
+            pos += TetrisGame::write_number(buffer, pos, self.lines);
+            pos += TetrisGame::write_bytes(buffer, pos, b"  Time: ");
+            pos += TetrisGame::write_time(buffer, pos, self.elapsed_ns);
+            pos += TetrisGame::write_bytes(buffer, pos, b"\n");
+        }
+
+        pos
+    }
+}
+
+/// A text frame as rendered for a read, shared by every file reading the
+/// game until it is out of date.
+pub(super) struct TextFrame {
+    /// `frame_gen` it was rendered at.
+    pub(super) gen: u64,
+    /// `GameView::valid_until_ns` of the view it was rendered from.
+    pub(super) valid_until_ns: u64,
+    pub(super) bytes: KVVec<u8>,
+}
+
+impl TextFrame {
+    /// Whether it is still what rendering the game at `gen` would give.
+    pub(super) fn is_current(&self, gen: u64) -> bool {
+        self.gen == gen && ktime_now_ns() < self.valid_until_ns
+    }
+}
+
+/// `TetrisConfig::render` flags.
+const RENDER_COLOR: u32 = 1 << 0;
+
+const RENDER_CLEAR_SCREEN: u32 = 1 << 1;
+
+const RENDER_ASCII: u32 = 1 << 2;
+
+const RENDER_INVISIBLE: u32 = 1 << 3;
+
+pub(super) const RENDER_ALL: u32 =
+    RENDER_COLOR | RENDER_CLEAR_SCREEN | RENDER_ASCII | RENDER_INVISIBLE;
+
+/// How text frames are drawn, as the `RENDER_*` flags say. Not part of the
+/// game: only rendering reads them, under `TetrisDeviceInner::render`.
+#[derive(Clone, Copy)]
+pub(super) struct RenderOptions {
+    /// Hide locked cells from the rendered frame (TGM invisible credits).
+    /// Only affects the device frame; debugfs still shows the real board.
+    pub(super) invisible: bool,
+    /// Color cells by piece type with ANSI escape codes.
+    pub(super) color: bool,
+    /// Prefix each frame with `VT100_CLEAR_HOME`.
+    pub(super) clear_screen: bool,
+    /// Draw with `GLYPHS_ASCII` instead of `GLYPHS_UTF8`.
+    pub(super) ascii: bool,
+}
+
+impl RenderOptions {
+    pub(super) fn from_flags(flags: u32) -> Self {
+        Self {
+            invisible: flags & RENDER_INVISIBLE != 0,
+            color: flags & RENDER_COLOR != 0,
+            clear_screen: flags & RENDER_CLEAR_SCREEN != 0,
+            ascii: flags & RENDER_ASCII != 0,
+        }
+    }
+
+    /// What new games start with, from the `render_flags` sysctl.
+    pub(super) fn from_sysctl() -> Self {
+        // SAFETY: Just reads the sysctl.
+        Self::from_flags(unsafe { tetris_sysctl_render_flags() } as u32)
+    }
+}
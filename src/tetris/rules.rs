@@ -0,0 +1,1227 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! The rules of the game, with nothing from the kernel: pieces and their
+//! SRS rotation, the board, line clears, scoring and the randomizer.
+//! `TetrisGame` plays by them; the KUnit suite at the bottom checks them.
+
+pub(super) const BOARD_MAX_WIDTH: usize = 16;
+
+pub(super) const BOARD_MAX_HEIGHT: usize = 32;
+
+/// Buffer rows above the visible field. Pieces can be kicked or pushed up
+/// into them; a piece locking entirely inside them is a lock out.
+pub(super) const HIDDEN_ROWS: usize = 2;
+
+/// Most rows a board can store, hidden ones first.
+pub(super) const BOARD_MAX_ROWS: usize = HIDDEN_ROWS + BOARD_MAX_HEIGHT;
+
+/// Cleared lines needed to advance one level.
+pub(super) const LINES_PER_LEVEL: u32 = 10;
+
+/// Extra garbage for consecutive clears, indexed by combo (guideline).
+const COMBO_ATTACK: [u32; 12] = [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5];
+
+/// Tetromino shapes (7 standard pieces)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum TetrominoType {
+    I,
+    O,
+    T,
+    S,
+    Z,
+    J,
+    L,
+}
+
+impl TetrominoType {
+    /// Every piece type, in `index()` order.
+    pub(super) const ALL: [TetrominoType; 7] = [
+        TetrominoType::I,
+        TetrominoType::O,
+        TetrominoType::T,
+        TetrominoType::S,
+        TetrominoType::Z,
+        TetrominoType::J,
+        TetrominoType::L,
+    ];
+
+    /// Position in `ALL`, `Tetromino::SHAPES` and per-type tables.
+    pub(super) fn index(self) -> usize {
+        match self {
+            TetrominoType::I => 0,
+            TetrominoType::O => 1,
+            TetrominoType::T => 2,
+            TetrominoType::S => 3,
+            TetrominoType::Z => 4,
+            TetrominoType::J => 5,
+            TetrominoType::L => 6,
+        }
+    }
+
+    /// Guideline color as an ANSI SGR sequence.
+    fn ansi_color(self) -> &'static [u8] {
+        match self {
+            TetrominoType::I => b"\x1b[36m",
+            TetrominoType::O => b"\x1b[33m",
+            TetrominoType::T => b"\x1b[35m",
+            TetrominoType::S => b"\x1b[32m",
+            TetrominoType::Z => b"\x1b[31m",
+            TetrominoType::J => b"\x1b[34m",
+            // No basic ANSI orange; use the 256-color palette.
+            TetrominoType::L => b"\x1b[38;5;208m",
+        }
+    }
+
+    pub(super) fn letter(self) -> u8 {
+        match self {
+            TetrominoType::I => b'I',
+            TetrominoType::O => b'O',
+            TetrominoType::T => b'T',
+            TetrominoType::S => b'S',
+            TetrominoType::Z => b'Z',
+            TetrominoType::J => b'J',
+            TetrominoType::L => b'L',
+        }
+    }
+}
+
+/// Precomputed shape matrix for all rotations
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ShapeMatrix {
+    pub(super) rotations: [[[bool; 4]; 4]; 4],
+    /// Each rotation's rows as bitmasks, bit `j` for column `j`, to test
+    /// against `Board::mask()`s.
+    masks: [[u8; 4]; 4],
+    /// Each rotation's filled cells' `(min_x, min_y, max_x, max_y)` in its
+    /// matrix.
+    bounds: [(i32, i32, i32, i32); 4],
+}
+
+impl ShapeMatrix {
+    /// Build all four rotations of `base`, turning within the top-left
+    /// `size`x`size` box (3 for JLSTZ, 4 for I and O) as SRS does.
+    const fn from_base(base: [[bool; 4]; 4], size: usize) -> Self {
+        let mut rotations = [[[false; 4]; 4]; 4];
+        rotations[0] = base;
+        rotations[1] = Self::rotate_once(base, size);
+        rotations[2] = Self::rotate_once(rotations[1], size);
+        rotations[3] = Self::rotate_once(rotations[2], size);
+
+        let mut masks = [[0u8; 4]; 4];
+        let mut bounds = [(4, 4, 0, 0); 4];
+        let mut r = 0;
+        while r < 4 {
+            let mut i = 0;
+            while i < 4 {
+                let mut j = 0;
+                while j < 4 {
+                    if rotations[r][i][j] {
+                        masks[r][i] |= 1 << j;
+                        let (min_x, min_y, max_x, max_y) = bounds[r];
+                        bounds[r] = (
+                            if (j as i32) < min_x { j as i32 } else { min_x },
+                            if (i as i32) < min_y { i as i32 } else { min_y },
+                            if (j as i32) > max_x { j as i32 } else { max_x },
+                            if (i as i32) > max_y { i as i32 } else { max_y },
+                        );
+                    }
+                    j += 1;
+                }
+                i += 1;
+            }
+            r += 1;
+        }
+        Self {
+            rotations,
+            masks,
+            bounds,
+        }
+    }
+
+    const fn rotate_once(matrix: [[bool; 4]; 4], size: usize) -> [[bool; 4]; 4] {
+        let mut rotated = [[false; 4]; 4];
+        let mut i = 0;
+        while i < size {
+            let mut j = 0;
+            while j < size {
+                rotated[j][size - 1 - i] = matrix[i][j];
+                j += 1;
+            }
+            i += 1;
+        }
+        rotated
+    }
+}
+
+/// How the piece sequence is generated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Randomizer {
+    /// Guideline 7-bag: each piece once per 7.
+    Bag7,
+    /// Two shuffled sets per bag: more variance, droughts capped at 24.
+    Bag14,
+}
+
+impl Randomizer {
+    pub(super) fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Randomizer::Bag7),
+            1 => Some(Randomizer::Bag14),
+            _ => None,
+        }
+    }
+
+    pub(super) fn bag_len(self) -> usize {
+        match self {
+            Randomizer::Bag7 => 7,
+            Randomizer::Bag14 => 14,
+        }
+    }
+}
+
+/// Rotation direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Rotation {
+    Cw,
+    Ccw,
+}
+
+/// Last successful manoeuvre of the active piece, for T-spin detection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum LastAction {
+    None,
+    Shift,
+    /// Rotation that succeeded with the given SRS kick index.
+    Rotate { kick: usize },
+}
+
+/// T-spin classification of a locked piece
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum TSpin {
+    None,
+    Mini,
+    Full,
+}
+
+/// Tetromino piece with position and rotation
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Tetromino {
+    pub(super) piece_type: TetrominoType,
+    pub(super) x: i32,
+    pub(super) y: i32,
+    pub(super) rotation: u8,
+}
+
+impl Tetromino {
+    pub(super) const SHAPES: [ShapeMatrix; 7] = [
+        ShapeMatrix::from_base(
+            [
+                [false, false, false, false],
+                [true, true, true, true],
+                [false, false, false, false],
+                [false, false, false, false],
+            ],
+            4,
+        ),
+        ShapeMatrix::from_base(
+            [
+                [false, false, false, false],
+                [false, true, true, false],
+                [false, true, true, false],
+                [false, false, false, false],
+            ],
+            4,
+        ),
+        ShapeMatrix::from_base(
+            [
+                [false, true, false, false],
+                [true, true, true, false],
+                [false, false, false, false],
+                [false, false, false, false],
+            ],
+            3,
+        ),
+        ShapeMatrix::from_base(
+            [
+                [false, true, true, false],
+                [true, true, false, false],
+                [false, false, false, false],
+                [false, false, false, false],
+            ],
+            3,
+        ),
+        ShapeMatrix::from_base(
+            [
+                [true, true, false, false],
+                [false, true, true, false],
+                [false, false, false, false],
+                [false, false, false, false],
+            ],
+            3,
+        ),
+        ShapeMatrix::from_base(
+            [
+                [true, false, false, false],
+                [true, true, true, false],
+                [false, false, false, false],
+                [false, false, false, false],
+            ],
+            3,
+        ),
+        ShapeMatrix::from_base(
+            [
+                [false, false, true, false],
+                [true, true, true, false],
+                [false, false, false, false],
+                [false, false, false, false],
+            ],
+            3,
+        ),
+    ];
+
+    /// SRS wall kick offsets for J, L, S, T and Z, indexed by the rotation
+    /// state being left when turning clockwise (0->R, R->2, 2->L, L->0).
+    ///
+    /// Offsets are `(x, y)` with y pointing *up*, as in the guideline tables.
+    /// Counter-clockwise kicks out of state `s` are the negated clockwise kicks
+    /// into `s`.
+    const KICKS_JLSTZ: [[(i8, i8); 5]; 4] = [
+        [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)],
+        [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
+        [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],
+        [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
+    ];
+
+    /// SRS wall kick offsets for the I piece, same layout as `KICKS_JLSTZ`.
+    const KICKS_I: [[(i8, i8); 5]; 4] = [
+        [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)],
+        [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)],
+        [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)],
+        [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)],
+    ];
+
+    /// The O piece never kicks.
+    const KICKS_O: [[(i8, i8); 5]; 4] = [[(0, 0); 5]; 4];
+
+    fn new(piece_type: TetrominoType, cols: usize) -> Self {
+        Self {
+            piece_type,
+            x: (cols / 2) as i32 - 2,
+            // Top of the visible field, just below the hidden rows.
+            y: HIDDEN_ROWS as i32,
+            rotation: 0,
+        }
+    }
+
+    fn get_shape(&self) -> [[bool; 4]; 4] {
+        Self::SHAPES[self.piece_type.index()].rotations[(self.rotation % 4) as usize]
+    }
+
+    /// `get_shape()` as row masks.
+    pub(super) fn get_masks(&self) -> [u8; 4] {
+        Self::SHAPES[self.piece_type.index()].masks[(self.rotation % 4) as usize]
+    }
+
+    /// The SRS kick table `piece_type` rotates with.
+    pub(super) fn kick_table(piece_type: TetrominoType) -> &'static [[(i8, i8); 5]; 4] {
+        match piece_type {
+            TetrominoType::I => &Self::KICKS_I,
+            TetrominoType::O => &Self::KICKS_O,
+            _ => &Self::KICKS_JLSTZ,
+        }
+    }
+
+    /// Board-space offsets to try, in order, when rotating out of `from`.
+    fn kick_offsets(&self, from: u8, dir: Rotation) -> [(i32, i32); 5] {
+        let table = Self::kick_table(self.piece_type);
+        let (row, sign) = match dir {
+            Rotation::Cw => (from % 4, 1),
+            Rotation::Ccw => ((from + 3) % 4, -1),
+        };
+
+        let mut offsets = [(0, 0); 5];
+        for (i, &(x, y)) in table[row as usize].iter().enumerate() {
+            // The tables use y-up; board rows grow downwards.
+            offsets[i] = (sign * x as i32, -sign * y as i32);
+        }
+        offsets
+    }
+
+    /// Whether the piece, locked where it is, lies wholly in the hidden
+    /// rows: a lock out.
+    pub(super) fn locks_out(&self) -> bool {
+        let (_, _, _, max_y) = self.get_bounds();
+        self.y + max_y < HIDDEN_ROWS as i32
+    }
+
+    /// `(min_x, min_y, max_x, max_y)` of `get_shape()`'s filled cells.
+    pub(super) fn get_bounds(&self) -> (i32, i32, i32, i32) {
+        Self::SHAPES[self.piece_type.index()].bounds[(self.rotation % 4) as usize]
+    }
+}
+
+/// Simple PRNG for kernel space
+///
+/// Deterministic on purpose: the pieces of a game follow from its seed
+/// alone, which is what lets `TETRIS_IOCTL_SET_SEED` and replays reproduce
+/// them. Unpredictability comes from `random_seed()`.
+pub(super) struct PRNG {
+    pub(super) state: u64,
+}
+
+impl PRNG {
+    pub(super) fn new(seed: u64) -> Self {
+        Self {
+            state: seed.wrapping_add(1),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_mul(6364136223846793005);
+        self.state = self.state.wrapping_add(1442695040888963407);
+        self.state
+    }
+
+    pub(super) fn next_range(&mut self, max: u32) -> u32 {
+        (self.next() % max as u64) as u32
+    }
+}
+
+/// The pieces still to be dealt: a shuffled bag of every piece once
+/// (`Bag7`) or twice (`Bag14`), reshuffled once it runs out.
+#[derive(Clone, Copy)]
+pub(super) struct Bag {
+    pub(super) randomizer: Randomizer,
+    /// Two copies of every piece; only the first `randomizer.bag_len()`
+    /// entries are shuffled and dealt.
+    pub(super) pieces: [TetrominoType; 14],
+    /// Index of the next piece in `pieces`.
+    pub(super) next: usize,
+}
+
+impl Bag {
+    /// An empty bag: the first `deal()` shuffles.
+    pub(super) fn new(randomizer: Randomizer) -> Self {
+        let mut pieces = [TetrominoType::I; 14];
+        for (i, slot) in pieces.iter_mut().enumerate() {
+            *slot = TetrominoType::ALL[i % TetrominoType::ALL.len()];
+        }
+        Self {
+            randomizer,
+            pieces,
+            next: pieces.len(),
+        }
+    }
+
+    /// Deal bags of the new kind once the current one runs out.
+    pub(super) fn set_randomizer(&mut self, randomizer: Randomizer) {
+        self.randomizer = randomizer;
+        self.next = randomizer.bag_len();
+    }
+
+    /// The next piece, shuffling a fresh bag with `prng` if this one is used
+    /// up.
+    pub(super) fn deal(&mut self, prng: &mut PRNG) -> TetrominoType {
+        let len = self.randomizer.bag_len();
+        if self.next >= len {
+            /* Fisher-Yates shuffle. */
+            for i in (1..len).rev() {
+                let j = prng.next_range((i + 1) as u32) as usize;
+                self.pieces.swap(i, j);
+            }
+            self.next = 0;
+        }
+
+        let piece = self.pieces[self.next];
+        self.next += 1;
+        piece
+    }
+}
+
+/// Points for a lock that cleared `lines` rows (and maybe none) as a
+/// `tspin`, before the level multiplier. T-spins score even when they clear
+/// nothing.
+pub(super) fn clear_score(tspin: TSpin, lines: u32) -> u64 {
+    match (tspin, lines) {
+        (TSpin::None, 0) => 0,
+        (TSpin::None, 1) => 100,
+        (TSpin::None, 2) => 300,
+        (TSpin::None, 3) => 500,
+        (TSpin::None, _) => 800,
+        (TSpin::Mini, 0) => 100,
+        (TSpin::Mini, 1) => 200,
+        (TSpin::Mini, _) => 400,
+        (TSpin::Full, 0) => 400,
+        (TSpin::Full, 1) => 800,
+        (TSpin::Full, 2) => 1200,
+        (TSpin::Full, _) => 1600,
+    }
+}
+
+/// Points for link `chain` of a cascade clearing `lines` rows: its plain
+/// line clear score times its position in the chain.
+pub(super) fn chain_score(chain: u32, lines: u32) -> u64 {
+    chain as u64 * clear_score(TSpin::None, lines)
+}
+
+/// Bonus for a clear of `lines` rows that left the board empty.
+pub(super) fn perfect_clear_score(lines: u32) -> u64 {
+    match lines {
+        0 => 0,
+        1 => 800,
+        2 => 1200,
+        3 => 1800,
+        _ => 2000,
+    }
+}
+
+/// `combo` after a lock that cleared `lines` rows: one more for a clear,
+/// back to -1 for none.
+pub(super) fn next_combo(combo: i32, lines: u32) -> i32 {
+    if lines > 0 {
+        combo.saturating_add(1)
+    } else {
+        -1
+    }
+}
+
+/// Points for a whole lock before the level multiplier: its clear as a
+/// `tspin`, `chain_bonus` for any cascade after it, 50 x `combo` (as of
+/// this lock) and the perfect clear bonus.
+pub(super) fn lock_score(
+    tspin: TSpin,
+    lines: u32,
+    chain_bonus: u64,
+    combo: i32,
+    perfect_clear: bool,
+) -> u64 {
+    let mut score = clear_score(tspin, lines) + chain_bonus;
+    if lines > 0 && combo > 0 {
+        score += 50 * combo as u64;
+    }
+    if perfect_clear {
+        score += perfect_clear_score(lines);
+    }
+    score
+}
+
+/// Level once `lines` lines have been cleared in all, for a game at
+/// `level`. Levels never go down, so a game started high stays there until
+/// its lines catch up.
+pub(super) fn level_for(level: u32, lines: u32) -> u32 {
+    level.max(1 + lines / LINES_PER_LEVEL)
+}
+
+/// Garbage rows sent for a clear of `lines` rows as a `tspin`, at `combo`
+/// consecutive clearing locks (-1 for none).
+pub(super) fn attack(lines: u32, tspin: TSpin, combo: i32, perfect_clear: bool) -> u32 {
+    if lines == 0 {
+        return 0;
+    }
+
+    let mut attack = match (tspin, lines) {
+        (TSpin::None, 1) => 0,
+        (TSpin::None, 2) => 1,
+        (TSpin::None, 3) => 2,
+        (TSpin::None, _) => 4,
+        (TSpin::Mini, 1) => 0,
+        (TSpin::Mini, _) => 1,
+        (TSpin::Full, 1) => 2,
+        (TSpin::Full, 2) => 4,
+        (TSpin::Full, _) => 6,
+    };
+    attack += COMBO_ATTACK[(combo.max(0) as usize).min(COMBO_ATTACK.len() - 1)];
+    if perfect_clear {
+        attack += 10;
+    }
+    attack
+}
+
+/// What occupies a board cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Cell {
+    Empty,
+    /// Part of a locked piece of this type.
+    Piece(TetrominoType),
+    /// Part of a garbage row received in versus play.
+    Garbage,
+}
+
+impl Cell {
+    pub(super) fn is_filled(self) -> bool {
+        self != Cell::Empty
+    }
+
+    /// ANSI SGR sequence to draw the cell with, empty for no color.
+    pub(super) fn ansi_color(self) -> &'static [u8] {
+        match self {
+            Cell::Empty => b"",
+            Cell::Piece(piece) => piece.ansi_color(),
+            Cell::Garbage => b"\x1b[90m",
+        }
+    }
+
+    /// Saved game encoding: 0 empty, 1-7 a piece by `TetrominoType` order,
+    /// 8 garbage.
+    pub(super) fn to_raw(self) -> u8 {
+        match self {
+            Cell::Empty => 0,
+            Cell::Piece(piece) => piece.index() as u8 + 1,
+            Cell::Garbage => 8,
+        }
+    }
+
+    pub(super) fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(Cell::Empty),
+            8 => Some(Cell::Garbage),
+            _ => TetrominoType::ALL.get(raw as usize - 1).map(|&piece| Cell::Piece(piece)),
+        }
+    }
+
+    /// Single character for dumps: the piece letter, `G` or `.`.
+    pub(super) fn letter(self) -> u8 {
+        match self {
+            Cell::Empty => b'.',
+            Cell::Piece(piece) => piece.letter(),
+            Cell::Garbage => b'G',
+        }
+    }
+}
+
+/// Locked cells of a game `cols` wide and `rows` high, hidden rows first,
+/// and which of them are filled as a bitmask per row, bit `x` for column
+/// `x`. Sized for the largest board, so resizing needn't allocate under the
+/// game lock; rows and columns past the game's stay empty. Indexing by row
+/// gives that row's `cols` cells, so cells read as `board[y][x]`. They are
+/// only written through `set()` and `set_row()`, which keep the masks in
+/// step.
+#[derive(Clone, Copy)]
+pub(super) struct Board {
+    cols: usize,
+    rows: usize,
+    cells: [[Cell; BOARD_MAX_WIDTH]; BOARD_MAX_ROWS],
+    masks: [u16; BOARD_MAX_ROWS],
+}
+
+impl Board {
+    pub(super) fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            cols,
+            rows,
+            cells: [[Cell::Empty; BOARD_MAX_WIDTH]; BOARD_MAX_ROWS],
+            masks: [0; BOARD_MAX_ROWS],
+        }
+    }
+
+    /// Logical width the game is played on.
+    pub(super) fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Logical height the game is played on, hidden rows included.
+    pub(super) fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.cells = [[Cell::Empty; BOARD_MAX_WIDTH]; BOARD_MAX_ROWS];
+        self.masks.fill(0);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.masks.iter().all(|&mask| mask == 0)
+    }
+
+    /// Every row's `mask()`.
+    pub(super) fn masks(&self) -> &[u16] {
+        &self.masks
+    }
+
+    pub(super) fn set(&mut self, y: usize, x: usize, cell: Cell) {
+        self.cells[y][x] = cell;
+        if cell.is_filled() {
+            self.masks[y] |= 1 << x;
+        } else {
+            self.masks[y] &= !(1 << x);
+        }
+    }
+
+    /// Make row `y` `cell` where `mask` has a bit set and empty elsewhere.
+    fn set_row(&mut self, y: usize, mask: u16, cell: Cell) {
+        for (x, slot) in self.cells[y][..self.cols].iter_mut().enumerate() {
+            *slot = if mask & 1 << x != 0 { cell } else { Cell::Empty };
+        }
+        self.masks[y] = if cell.is_filled() { mask } else { 0 };
+    }
+
+    /// Copy rows `src` so they start at row `dest`.
+    fn copy_rows(&mut self, src: core::ops::Range<usize>, dest: usize) {
+        self.cells.copy_within(src.clone(), dest);
+        self.masks.copy_within(src, dest);
+    }
+
+    /// `mask()` of a full row.
+    pub(super) fn full_mask(&self) -> u16 {
+        ((1u32 << self.cols) - 1) as u16
+    }
+
+    /// Bitmask of the rows that are full.
+    pub(super) fn full_rows(&self) -> u64 {
+        let full = self.full_mask();
+        (0..self.rows)
+            .filter(|&y| self.masks[y] == full)
+            .fold(0, |mask, y| mask | 1 << y)
+    }
+
+    fn is_out_of_bounds(&self, x: i32, y: i32) -> bool {
+        x < 0 || x >= self.cols as i32 || y < 0 || y >= self.rows as i32
+    }
+
+    /// Like `is_out_of_bounds`, but cells above the board count as free.
+    fn is_cell_blocked(&self, x: i32, y: i32) -> bool {
+        if y < 0 {
+            return x < 0 || x >= self.cols as i32;
+        }
+        self.is_out_of_bounds(x, y) || self[y as usize][x as usize].is_filled()
+    }
+
+    /// Shape row mask `row` moved to start at column `x`, or `None` if a
+    /// cell of it would be off the side of the board.
+    pub(super) fn place_row(&self, row: u8, x: i32) -> Option<u16> {
+        let row = row as u32;
+        let placed = if x >= 0 {
+            row.checked_shl(x as u32).filter(|&placed| placed >> x == row)?
+        } else {
+            // Cells shifted out on the left are off the board.
+            (row.trailing_zeros() >= x.unsigned_abs()).then(|| row >> x.unsigned_abs())?
+        };
+        (placed & !(self.full_mask() as u32) == 0).then_some(placed as u16)
+    }
+
+    /// Whether `piece` overlaps the stack or sticks out of the board.
+    pub(super) fn collides(&self, piece: &Tetromino) -> bool {
+        for (i, &row) in piece.get_masks().iter().enumerate() {
+            if row == 0 {
+                continue;
+            }
+            let y = piece.y + i as i32;
+            if y < 0 || y >= self.rows as i32 {
+                return true;
+            }
+            match self.place_row(row, piece.x) {
+                Some(cells) if cells & self.masks[y as usize] == 0 => {}
+                _ => return true,
+            }
+        }
+        false
+    }
+
+    /// `piece_type` entering the board, turned to `rotation` if it fits that
+    /// way (IRS) and in its normal orientation otherwise. `None` if it
+    /// doesn't fit at all: a block out.
+    pub(super) fn spawn(&self, piece_type: TetrominoType, rotation: u8) -> Option<Tetromino> {
+        let piece = Tetromino::new(piece_type, self.cols);
+        let turned = Tetromino {
+            rotation: rotation % 4,
+            ..piece
+        };
+        [turned, piece]
+            .into_iter()
+            .find(|piece| !self.collides(piece))
+    }
+
+    /// `piece` turned `dir` by SRS, at the first kick offset it fits at,
+    /// along with that offset's index. `None` if every kick is blocked.
+    pub(super) fn rotate(&self, piece: &Tetromino, dir: Rotation) -> Option<(Tetromino, usize)> {
+        let from = piece.rotation % 4;
+        let to = match dir {
+            Rotation::Cw => (from + 1) % 4,
+            Rotation::Ccw => (from + 3) % 4,
+        };
+
+        for (kick, (dx, dy)) in piece.kick_offsets(from, dir).into_iter().enumerate() {
+            let candidate = Tetromino {
+                x: piece.x + dx,
+                y: piece.y + dy,
+                rotation: to,
+                ..*piece
+            };
+            if !self.collides(&candidate) {
+                return Some((candidate, kick));
+            }
+        }
+        None
+    }
+
+    /// 3-corner T-spin check for `piece`, which is about to lock after
+    /// `last_action`.
+    ///
+    /// A T whose last move was a rotation is a T-spin when at least three of
+    /// the four cells diagonal to its centre are blocked. It is a mini unless
+    /// both corners on the side it points at are blocked or it got there with
+    /// the last SRS kick.
+    pub(super) fn detect_tspin(&self, piece: &Tetromino, last_action: LastAction) -> TSpin {
+        let kick = match last_action {
+            LastAction::Rotate { kick } => kick,
+            _ => return TSpin::None,
+        };
+        if piece.piece_type != TetrominoType::T {
+            return TSpin::None;
+        }
+
+        let (cx, cy) = (piece.x + 1, piece.y + 1);
+        // Clockwise from top-left, so rotation `r` points at corners r and r + 1.
+        let corners = [
+            self.is_cell_blocked(cx - 1, cy - 1),
+            self.is_cell_blocked(cx + 1, cy - 1),
+            self.is_cell_blocked(cx + 1, cy + 1),
+            self.is_cell_blocked(cx - 1, cy + 1),
+        ];
+        if corners.iter().filter(|&&c| c).count() < 3 {
+            return TSpin::None;
+        }
+
+        let r = (piece.rotation % 4) as usize;
+        if (corners[r] && corners[(r + 1) % 4]) || kick == 4 {
+            TSpin::Full
+        } else {
+            TSpin::Mini
+        }
+    }
+
+    /// Add `piece`'s cells to the stack.
+    pub(super) fn lock(&mut self, piece: &Tetromino) {
+        let shape = piece.get_shape();
+        let (min_x, min_y, max_x, max_y) = piece.get_bounds();
+
+        for i in min_y..=max_y {
+            for j in min_x..=max_x {
+                if shape[i as usize][j as usize] {
+                    let x = piece.x + j;
+                    let y = piece.y + i;
+
+                    if !self.is_out_of_bounds(x, y) {
+                        self.set(y as usize, x as usize, Cell::Piece(piece.piece_type));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Push the stack up by `n` rows and fill the bottom with garbage that
+    /// has its hole in column `hole`. Returns whether that pushed cells off
+    /// the top: a top out.
+    pub(super) fn raise_garbage(&mut self, n: u32, hole: u32) -> bool {
+        let rows = self.rows;
+        let n = (n as usize).min(rows);
+        let topped_out = (0..n).any(|y| self.masks[y] != 0);
+
+        self.copy_rows(n..rows, 0);
+        let garbage = self.full_mask() & !(1 << hole);
+        for y in rows - n..rows {
+            self.set_row(y, garbage, Cell::Garbage);
+        }
+        topped_out
+    }
+
+    /// Remove full rows and shift everything above them down by the number
+    /// of rows removed (naive gravity). Returns the number of rows removed.
+    pub(super) fn collapse_full_rows(&mut self) -> u32 {
+        let mut lines_cleared = 0;
+        let mut write_idx = self.rows;
+        let full = self.full_mask();
+
+        for y in (0..self.rows).rev() {
+            if self.masks[y] == full {
+                lines_cleared += 1;
+            } else {
+                write_idx -= 1;
+                if write_idx != y {
+                    self.copy_rows(y..y + 1, write_idx);
+                }
+            }
+        }
+
+        while write_idx > 0 {
+            write_idx -= 1;
+            self.set_row(write_idx, 0, Cell::Empty);
+        }
+
+        lines_cleared
+    }
+
+    /// Label 4-connected groups of filled cells, numbered from 1. Returns
+    /// the label grid and the number of groups. A checkerboard has one group
+    /// per filled cell, so neither fits in a `u8` on the largest board.
+    fn label_groups(&self) -> ([[u16; BOARD_MAX_WIDTH]; BOARD_MAX_ROWS], u16) {
+        let mut labels = [[0u16; BOARD_MAX_WIDTH]; BOARD_MAX_ROWS];
+        let mut stack = [(0u8, 0u8); BOARD_MAX_WIDTH * BOARD_MAX_ROWS];
+        let mut count = 0u16;
+
+        for y in 0..self.rows {
+            for x in 0..self.cols {
+                if !self[y][x].is_filled() || labels[y][x] != 0 {
+                    continue;
+                }
+                count += 1;
+                labels[y][x] = count;
+                stack[0] = (x as u8, y as u8);
+                let mut top = 1;
+                while top > 0 {
+                    top -= 1;
+                    let (cx, cy) = (stack[top].0 as usize, stack[top].1 as usize);
+                    let neighbours = [
+                        (cx.wrapping_sub(1), cy),
+                        (cx + 1, cy),
+                        (cx, cy.wrapping_sub(1)),
+                        (cx, cy + 1),
+                    ];
+                    for (nx, ny) in neighbours {
+                        if nx < self.cols
+                            && ny < self.rows
+                            && self[ny][nx].is_filled()
+                            && labels[ny][nx] == 0
+                        {
+                            labels[ny][nx] = count;
+                            stack[top] = (nx as u8, ny as u8);
+                            top += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        (labels, count)
+    }
+
+    /// Let every group of connected cells fall one row at a time until none
+    /// can move. Groups that land on each other are relabelled together on
+    /// the next pass, so they keep falling as one.
+    pub(super) fn settle_groups(&mut self) {
+        loop {
+            let (mut labels, count) = self.label_groups();
+            let mut moved = false;
+
+            for id in 1..=count {
+                let can_fall = (0..self.rows).all(|y| {
+                    (0..self.cols).all(|x| {
+                        labels[y][x] != id
+                            || (y + 1 < self.rows
+                                && (!self[y + 1][x].is_filled() || labels[y + 1][x] == id))
+                    })
+                });
+                if !can_fall {
+                    continue;
+                }
+
+                // Bottom-up, so each cell moves into an already vacated one.
+                for y in (0..self.rows - 1).rev() {
+                    for x in 0..self.cols {
+                        if labels[y][x] == id {
+                            let cell = self[y][x];
+                            self.set(y + 1, x, cell);
+                            labels[y + 1][x] = id;
+                            self.set(y, x, Cell::Empty);
+                            labels[y][x] = 0;
+                        }
+                    }
+                }
+                moved = true;
+            }
+
+            if !moved {
+                break;
+            }
+        }
+    }
+
+    /// Clear full rows, then settle the groups left and clear again for as
+    /// long as that fills rows: a cascade. Returns the lines cleared, the
+    /// number of clears chained together and the chain bonus, each link
+    /// after the first scoring `chain_score()`.
+    pub(super) fn cascade(&mut self) -> (u32, u32, u64) {
+        let mut lines = self.collapse_full_rows();
+        if lines == 0 {
+            return (0, 0, 0);
+        }
+        let (mut chain, mut bonus) = (1, 0);
+        loop {
+            self.settle_groups();
+            let n = self.collapse_full_rows();
+            if n == 0 {
+                break;
+            }
+            chain += 1;
+            lines += n;
+            bonus += chain_score(chain, n);
+        }
+        (lines, chain, bonus)
+    }
+}
+
+impl core::ops::Index<usize> for Board {
+    type Output = [Cell];
+
+    fn index(&self, y: usize) -> &[Cell] {
+        &self.cells[y][..self.cols]
+    }
+}
+
+#[cfg(CONFIG_KUNIT)]
+#[kernel::macros::kunit_tests(tetris_rules)]
+mod tests {
+    use super::*;
+
+    /// Every randomized test deals from this, so a failure replays exactly.
+    const SEED: u64 = 0x7e7;
+
+    const COLS: usize = 10;
+    const ROWS: usize = HIDDEN_ROWS + 20;
+
+    fn piece(piece_type: TetrominoType, x: i32, y: i32, rotation: u8) -> Tetromino {
+        Tetromino {
+            piece_type,
+            x,
+            y,
+            rotation,
+        }
+    }
+
+    fn fill_row(board: &mut Board, y: usize) {
+        for x in 0..board.cols() {
+            board.set(y, x, Cell::Garbage);
+        }
+    }
+
+    #[test]
+    fn rotation_states() {
+        for piece_type in TetrominoType::ALL {
+            for rotation in 0..4 {
+                let cells: u32 = piece(piece_type, 0, 0, rotation)
+                    .get_masks()
+                    .iter()
+                    .map(|row| row.count_ones())
+                    .sum();
+                assert_eq!(cells, 4);
+            }
+        }
+        assert_eq!(
+            piece(TetrominoType::T, 0, 0, 1).get_masks(),
+            [0b010, 0b110, 0b010, 0]
+        );
+    }
+
+    #[test]
+    fn rotation_cycles() {
+        let board = Board::new(COLS, ROWS);
+        for piece_type in TetrominoType::ALL {
+            let start = piece(piece_type, 3, 8, 0);
+            let mut turned = start;
+            for _ in 0..4 {
+                let kick;
+                (turned, kick) = board.rotate(&turned, Rotation::Cw).unwrap();
+                assert_eq!(kick, 0);
+            }
+            assert_eq!(turned.rotation, 0);
+            assert_eq!((turned.x, turned.y), (start.x, start.y));
+
+            let (cw, _) = board.rotate(&start, Rotation::Cw).unwrap();
+            let (back, _) = board.rotate(&cw, Rotation::Ccw).unwrap();
+            assert_eq!(back.rotation, 0);
+            assert_eq!((back.x, back.y), (start.x, start.y));
+        }
+    }
+
+    #[test]
+    fn wall_kick() {
+        let board = Board::new(COLS, ROWS);
+        // Pointing right against the left wall: turning to point down
+        // needs column -1, so SRS tries one column to the right next.
+        let t = piece(TetrominoType::T, -1, 8, 1);
+        assert!(!board.collides(&t));
+        let (kicked, kick) = board.rotate(&t, Rotation::Cw).unwrap();
+        assert_eq!(kick, 1);
+        assert_eq!((kicked.x, kicked.y, kicked.rotation), (0, 8, 2));
+    }
+
+    #[test]
+    fn rotation_blocked() {
+        // Everything but the T's own cells is filled, so no kick fits.
+        let t = piece(TetrominoType::T, 3, 8, 0);
+        let mut board = Board::new(COLS, ROWS);
+        for y in 0..ROWS {
+            fill_row(&mut board, y);
+        }
+        for (i, &row) in t.get_masks().iter().enumerate() {
+            for x in 0..4 {
+                if row & 1 << x != 0 {
+                    board.set(8 + i, 3 + x, Cell::Empty);
+                }
+            }
+        }
+        assert!(!board.collides(&t));
+        assert!(board.rotate(&t, Rotation::Cw).is_none());
+        assert!(board.rotate(&t, Rotation::Ccw).is_none());
+    }
+
+    #[test]
+    fn collision() {
+        let mut board = Board::new(COLS, ROWS);
+        let floor = ROWS as i32 - 2;
+        assert!(!board.collides(&piece(TetrominoType::T, 0, 8, 0)));
+        assert!(board.collides(&piece(TetrominoType::T, -1, 8, 0)));
+        assert!(!board.collides(&piece(TetrominoType::T, 7, 8, 0)));
+        assert!(board.collides(&piece(TetrominoType::T, 8, 8, 0)));
+        assert!(!board.collides(&piece(TetrominoType::T, 3, floor, 0)));
+        assert!(board.collides(&piece(TetrominoType::T, 3, floor + 1, 0)));
+
+        board.set(ROWS - 1, 4, Cell::Garbage);
+        assert!(board.collides(&piece(TetrominoType::T, 3, floor, 0)));
+        assert!(!board.collides(&piece(TetrominoType::T, 5, floor, 0)));
+    }
+
+    #[test]
+    fn line_clear() {
+        let mut board = Board::new(COLS, ROWS);
+        assert_eq!(board.full_mask(), 0x3ff);
+
+        fill_row(&mut board, ROWS - 1);
+        fill_row(&mut board, ROWS - 3);
+        board.set(ROWS - 2, 0, Cell::Garbage);
+        board.set(ROWS - 4, 3, Cell::Garbage);
+        assert_eq!(board.full_rows(), 1 << (ROWS - 1) | 1 << (ROWS - 3));
+
+        assert_eq!(board.collapse_full_rows(), 2);
+        assert_eq!(board.full_rows(), 0);
+        assert_eq!(board.masks()[ROWS - 1], 1);
+        assert_eq!(board.masks()[ROWS - 2], 1 << 3);
+        assert!(board.masks()[..ROWS - 2].iter().all(|&mask| mask == 0));
+    }
+
+    #[test]
+    fn scoring() {
+        assert_eq!(clear_score(TSpin::None, 0), 0);
+        assert_eq!(clear_score(TSpin::None, 4), 800);
+        assert_eq!(clear_score(TSpin::Mini, 0), 100);
+        assert_eq!(clear_score(TSpin::Full, 2), 1200);
+        assert_eq!(chain_score(2, 1), 200);
+        assert_eq!(chain_score(3, 4), 2400);
+        assert_eq!(perfect_clear_score(1), 800);
+        assert_eq!(perfect_clear_score(4), 2000);
+
+        assert_eq!(attack(0, TSpin::Full, 5, false), 0);
+        assert_eq!(attack(1, TSpin::None, -1, false), 0);
+        assert_eq!(attack(4, TSpin::None, -1, false), 4);
+        assert_eq!(attack(2, TSpin::Full, -1, false), 4);
+        assert_eq!(attack(1, TSpin::None, 3, false), 1);
+        assert_eq!(attack(4, TSpin::None, 0, true), 14);
+    }
+
+    #[test]
+    fn scoring_flow() {
+        // A single, then a T-spin double, then a perfect clear tetris, then
+        // a lock that clears nothing, all at level 1.
+        let mut combo = -1;
+        combo = next_combo(combo, 1);
+        assert_eq!(combo, 0);
+        assert_eq!(lock_score(TSpin::None, 1, 0, combo, false), 100);
+        combo = next_combo(combo, 2);
+        assert_eq!(combo, 1);
+        assert_eq!(lock_score(TSpin::Full, 2, 0, combo, false), 1250);
+        combo = next_combo(combo, 4);
+        assert_eq!(lock_score(TSpin::None, 4, 0, combo, true), 800 + 100 + 2000);
+        combo = next_combo(combo, 0);
+        assert_eq!(combo, -1);
+        assert_eq!(lock_score(TSpin::None, 0, 0, combo, false), 0);
+
+        // A cascade's chain adds its bonus on top.
+        assert_eq!(lock_score(TSpin::None, 3, chain_score(2, 1), 0, false), 500 + 200);
+        // A combo as long as a loaded game can claim doesn't wrap.
+        assert_eq!(next_combo(i32::MAX, 1), i32::MAX);
+
+        assert_eq!(level_for(1, 9), 1);
+        assert_eq!(level_for(1, 10), 2);
+        assert_eq!(level_for(1, 35), 4);
+        // Started high, the level waits for the lines to catch up.
+        assert_eq!(level_for(5, 12), 5);
+        assert_eq!(level_for(5, 50), 6);
+    }
+
+    /// Deal `rounds` bags of `randomizer` and check each holds every piece
+    /// the same number of times.
+    fn check_bags(randomizer: Randomizer, rounds: usize) {
+        let mut prng = PRNG::new(SEED);
+        let mut bag = Bag::new(randomizer);
+        let len = randomizer.bag_len();
+        for _ in 0..rounds {
+            let mut counts = [0; 7];
+            for _ in 0..len {
+                counts[bag.deal(&mut prng).index()] += 1;
+            }
+            assert!(counts.iter().all(|&n| n == len / 7));
+        }
+    }
+
+    #[test]
+    fn bag_distribution() {
+        check_bags(Randomizer::Bag7, 16);
+        check_bags(Randomizer::Bag14, 8);
+    }
+
+    #[test]
+    fn bag_seeded() {
+        use TetrominoType::*;
+        // Pinned, so a change to the PRNG or the shuffle shows up here.
+        const DEALT: [TetrominoType; 14] = [L, S, O, J, Z, I, T, Z, T, L, I, S, O, J];
+        let mut prng = PRNG::new(SEED);
+        let mut bag = Bag::new(Randomizer::Bag7);
+        let dealt: [TetrominoType; 14] = core::array::from_fn(|_| bag.deal(&mut prng));
+        assert_eq!(dealt, DEALT);
+    }
+
+    #[test]
+    fn block_out() {
+        let mut board = Board::new(COLS, ROWS);
+        assert!(board.spawn(TetrominoType::T, 0).is_some());
+        fill_row(&mut board, HIDDEN_ROWS + 1);
+        // IRS falls back to the normal orientation, which doesn't fit either.
+        assert!(board.spawn(TetrominoType::T, 1).is_none());
+    }
+
+    #[test]
+    fn lock_out() {
+        // The I's cells are in the second row of its box.
+        assert!(piece(TetrominoType::I, 3, 0, 0).locks_out());
+        assert!(!piece(TetrominoType::I, 3, 1, 0).locks_out());
+    }
+
+    #[test]
+    fn top_out() {
+        let mut board = Board::new(COLS, ROWS);
+        assert!(!board.raise_garbage(2, 3));
+        assert_eq!(board.masks()[ROWS - 1], 0x3ff & !(1 << 3));
+        assert_eq!(board.masks()[ROWS - 3], 0);
+
+        board.set(0, 5, Cell::Piece(TetrominoType::O));
+        assert!(board.raise_garbage(1, 0));
+    }
+
+    #[test]
+    fn cascade_checkerboard() {
+        // One group per filled cell: more than a `u8` label can count.
+        let mut board = Board::new(BOARD_MAX_WIDTH, BOARD_MAX_ROWS);
+        for y in 0..BOARD_MAX_ROWS {
+            for x in (y % 2..BOARD_MAX_WIDTH).step_by(2) {
+                board.set(y, x, Cell::Garbage);
+            }
+        }
+        assert_eq!(board.label_groups().1, 272);
+
+        // Every column holds half its cells, which all end up at the bottom.
+        board.settle_groups();
+        let half = BOARD_MAX_ROWS / 2;
+        assert!(board.masks()[..half].iter().all(|&mask| mask == 0));
+        assert!(board.masks()[half..].iter().all(|&mask| mask == board.full_mask()));
+    }
+}
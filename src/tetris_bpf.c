@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * BPF interface to the tetris game.
+ *
+ * Rust objects get no BTF, so everything the verifier checks programs
+ * against lives here. tetris.rs calls in through tetris_bpf_place()
+ * whenever the demo, the autopilot or TETRIS_IOCTL_HINT consult the bot,
+ * and through tetris_bpf_publish() whenever it lets go of the game lock.
+ *
+ * Tracing and struct_ops programs can read the game with the kfuncs below.
+ * They may run in any context, including under the game lock, so they read
+ * the last published copy instead of taking it.
+ *
+ * A bot attaches as
+ *
+ *	SEC(".struct_ops.link")
+ *	struct tetris_bot_ops my_bot = {
+ *		.place = (void *)my_place,
+ *		.name = "my_bot",
+ *	};
+ */
+
+#include <linux/bpf.h>
+#include <linux/btf.h>
+#include <linux/btf_ids.h>
+#include <linux/module.h>
+#include <linux/rcupdate.h>
+#include <linux/seqlock.h>
+#include <linux/spinlock.h>
+
+#include "tetris_bpf.h"
+
+/* Latched so NMI-context readers never wait on an interrupted writer. */
+static struct {
+	seqcount_latch_t seq;
+	struct tetris_bpf_state state[2];
+} tetris_latch = {
+	.seq = SEQCNT_LATCH_ZERO(tetris_latch.seq),
+};
+
+/* Keeps publishers apart; private games each publish under their own lock. */
+static DEFINE_SPINLOCK(tetris_latch_lock);
+
+/* With private games, the kfuncs see whichever game changed last. */
+void tetris_bpf_publish(const struct tetris_bpf_state *state)
+{
+	spin_lock(&tetris_latch_lock);
+	write_seqcount_latch_begin(&tetris_latch.seq);
+	tetris_latch.state[0] = *state;
+	write_seqcount_latch(&tetris_latch.seq);
+	tetris_latch.state[1] = *state;
+	write_seqcount_latch_end(&tetris_latch.seq);
+	spin_unlock(&tetris_latch_lock);
+}
+
+static void tetris_bpf_read(struct tetris_bpf_state *state)
+{
+	unsigned int seq;
+
+	do {
+		seq = read_seqcount_latch(&tetris_latch.seq);
+		*state = tetris_latch.state[seq & 1];
+	} while (read_seqcount_latch_retry(&tetris_latch.seq, seq));
+}
+
+__bpf_kfunc_start_defs();
+
+/**
+ * bpf_tetris_cols() - Board width in cells.
+ */
+__bpf_kfunc u32 bpf_tetris_cols(void)
+{
+	struct tetris_bpf_state state;
+
+	tetris_bpf_read(&state);
+	return state.cols;
+}
+
+/**
+ * bpf_tetris_rows() - Board height in cells, counting the hidden rows
+ * above the visible board.
+ */
+__bpf_kfunc u32 bpf_tetris_rows(void)
+{
+	struct tetris_bpf_state state;
+
+	tetris_bpf_read(&state);
+	return state.nr_rows;
+}
+
+/**
+ * bpf_tetris_board_row() - Filled cells in a board row.
+ * @row: Row to look at, 0 being the topmost hidden row.
+ *
+ * Return: One bit per column, bit 0 being the leftmost; 0 past the bottom.
+ */
+__bpf_kfunc u16 bpf_tetris_board_row(u32 row)
+{
+	struct tetris_bpf_state state;
+
+	tetris_bpf_read(&state);
+	if (row >= state.nr_rows)
+		return 0;
+	return state.rows[row];
+}
+
+/**
+ * bpf_tetris_piece() - The active piece.
+ * @piece: Filled in with the piece's type (0-6 for I, O, T, S, Z, J, L),
+ *	   position and rotation.
+ *
+ * Return: 0, or -ENOENT if no piece is in play.
+ */
+__bpf_kfunc int bpf_tetris_piece(struct tetris_bpf_piece *piece)
+{
+	struct tetris_bpf_state state;
+
+	tetris_bpf_read(&state);
+	if (state.piece < 0)
+		return -ENOENT;
+
+	piece->type = state.piece;
+	piece->x = state.x;
+	piece->y = state.y;
+	piece->rotation = state.rotation;
+	return 0;
+}
+
+/**
+ * bpf_tetris_score() - Score of the game in progress.
+ */
+__bpf_kfunc u64 bpf_tetris_score(void)
+{
+	struct tetris_bpf_state state;
+
+	tetris_bpf_read(&state);
+	return state.score;
+}
+
+__bpf_kfunc_end_defs();
+
+BTF_KFUNCS_START(tetris_kfunc_ids)
+BTF_ID_FLAGS(func, bpf_tetris_cols)
+BTF_ID_FLAGS(func, bpf_tetris_rows)
+BTF_ID_FLAGS(func, bpf_tetris_board_row)
+BTF_ID_FLAGS(func, bpf_tetris_piece)
+BTF_ID_FLAGS(func, bpf_tetris_score)
+BTF_KFUNCS_END(tetris_kfunc_ids)
+
+static const struct btf_kfunc_id_set tetris_kfunc_set = {
+	.owner = THIS_MODULE,
+	.set = &tetris_kfunc_ids,
+};
+
+/* The attached bot; only one at a time. */
+static struct tetris_bot_ops __rcu *tetris_bot;
+
+static const struct bpf_verifier_ops tetris_bot_verifier_ops = {
+	.get_func_proto = bpf_base_func_proto,
+	.is_valid_access = bpf_tracing_btf_ctx_access,
+};
+
+static int tetris_bot_init(struct btf *btf)
+{
+	return 0;
+}
+
+static int tetris_bot_init_member(const struct btf_type *t,
+				  const struct btf_member *member,
+				  void *kdata, const void *udata)
+{
+	const struct tetris_bot_ops *uops = udata;
+	struct tetris_bot_ops *ops = kdata;
+
+	if (__btf_member_bit_offset(t, member) / 8 !=
+	    offsetof(struct tetris_bot_ops, name))
+		return 0;
+
+	if (bpf_obj_name_cpy(ops->name, uops->name, sizeof(ops->name)) <= 0)
+		return -EINVAL;
+	return 1;
+}
+
+static int tetris_bot_reg(void *kdata, struct bpf_link *link)
+{
+	struct tetris_bot_ops *ops = kdata;
+
+	if (cmpxchg((struct tetris_bot_ops __force **)&tetris_bot, NULL, ops))
+		return -EBUSY;
+	return 0;
+}
+
+static void tetris_bot_unreg(void *kdata, struct bpf_link *link)
+{
+	struct tetris_bot_ops *ops = kdata;
+
+	if (cmpxchg((struct tetris_bot_ops __force **)&tetris_bot, ops, NULL) == ops)
+		synchronize_rcu();
+}
+
+static s32 tetris_bot_place_stub(struct tetris_bot_ctx *ctx)
+{
+	return -1;
+}
+
+static struct tetris_bot_ops __bpf_tetris_bot_ops = {
+	.place = tetris_bot_place_stub,
+};
+
+static struct bpf_struct_ops bpf_tetris_bot_ops = {
+	.verifier_ops = &tetris_bot_verifier_ops,
+	.init = tetris_bot_init,
+	.init_member = tetris_bot_init_member,
+	.reg = tetris_bot_reg,
+	.unreg = tetris_bot_unreg,
+	.cfi_stubs = &__bpf_tetris_bot_ops,
+	.name = "tetris_bot_ops",
+	.owner = THIS_MODULE,
+};
+
+int tetris_bpf_init(void)
+{
+	int ret;
+
+	ret = register_btf_kfunc_id_set(BPF_PROG_TYPE_TRACING, &tetris_kfunc_set);
+	if (ret)
+		return ret;
+	ret = register_btf_kfunc_id_set(BPF_PROG_TYPE_STRUCT_OPS, &tetris_kfunc_set);
+	if (ret)
+		return ret;
+	return register_bpf_struct_ops(&bpf_tetris_bot_ops, tetris_bot_ops);
+}
+
+s32 tetris_bpf_place(struct tetris_bot_ctx *ctx)
+{
+	struct tetris_bot_ops *ops;
+	s32 ret = -ENOENT;
+
+	rcu_read_lock();
+	ops = rcu_dereference(tetris_bot);
+	if (ops)
+		ret = ops->place(ctx);
+	rcu_read_unlock();
+	return ret;
+}
@@ -0,0 +1,71 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+/*
+ * BPF interface to the tetris game: a struct_ops hook that lets a loaded
+ * program play the bot, and kfuncs that read the game state.
+ */
+
+#ifndef TETRIS_BPF_H
+#define TETRIS_BPF_H
+
+#include <linux/types.h>
+
+/* `BOARD_MAX_ROWS` in tetris/rules.rs and `PREVIEW_MAX` in tetris/engine.rs. */
+#define TETRIS_BOT_MAX_ROWS	34
+#define TETRIS_BOT_PREVIEW_MAX	5
+
+/*
+ * What a bot sees. Piece types are 0-6 for I, O, T, S, Z, J, L. Mirrors
+ * `BotContext` in tetris.rs.
+ */
+struct tetris_bot_ctx {
+	/* One bit per column, top row first; hidden rows are included. */
+	u16 rows[TETRIS_BOT_MAX_ROWS];
+	u8 cols;
+	u8 nr_rows;
+	u8 piece;
+	u8 preview_count;
+	u8 preview[TETRIS_BOT_PREVIEW_MAX];
+};
+
+/*
+ * Game state as the kfuncs report it. `piece` is -1 when there is no
+ * active piece. Mirrors `BpfState` in tetris.rs.
+ */
+struct tetris_bpf_state {
+	u64 score;
+	u16 rows[TETRIS_BOT_MAX_ROWS];
+	u8 cols;
+	u8 nr_rows;
+	s8 piece;
+	u8 rotation;
+	s32 x;
+	s32 y;
+};
+
+/*
+ * The active piece, filled in by bpf_tetris_piece(). `x` and `y` place the
+ * top left of its 4x4 shape matrix, counting hidden rows.
+ */
+struct tetris_bpf_piece {
+	s32 type;
+	s32 x;
+	s32 y;
+	u32 rotation;
+};
+
+struct tetris_bot_ops {
+	/*
+	 * Where the active piece should go: the leftmost column it should
+	 * cover in bits 0-7 and its rotation (0 = spawn, clockwise) in bits
+	 * 8-9, the same as TETRIS_IOCTL_HINT. A negative return, or a
+	 * placement that doesn't fit, leaves the choice to the built-in bot.
+	 */
+	s32 (*place)(struct tetris_bot_ctx *ctx);
+	char name[16];
+};
+
+int tetris_bpf_init(void);
+s32 tetris_bpf_place(struct tetris_bot_ctx *ctx);
+void tetris_bpf_publish(const struct tetris_bpf_state *state);
+
+#endif /* TETRIS_BPF_H */
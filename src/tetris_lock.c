@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * The game lock is taken by the gravity timer in hardirq context, so
+ * everyone else has to take it with interrupts off. The kernel crate only
+ * has the plain spin_lock(); these wrap the irqsave macros for it.
+ */
+
+#include <linux/spinlock.h>
+
+#include "tetris_lock.h"
+
+unsigned long tetris_lock_irqsave(spinlock_t *lock)
+{
+	unsigned long flags;
+
+	spin_lock_irqsave(lock, flags);
+	return flags;
+}
+
+bool tetris_trylock_irqsave(spinlock_t *lock, unsigned long *flags)
+{
+	return spin_trylock_irqsave(lock, *flags);
+}
+
+void tetris_unlock_irqrestore(spinlock_t *lock, unsigned long flags)
+{
+	spin_unlock_irqrestore(lock, flags);
+}
@@ -0,0 +1,13 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+
+#ifndef TETRIS_LOCK_H
+#define TETRIS_LOCK_H
+
+#include <linux/spinlock.h>
+
+/* The game lock, `IrqSpinLockBackend` in tetris.rs. */
+unsigned long tetris_lock_irqsave(spinlock_t *lock);
+bool tetris_trylock_irqsave(spinlock_t *lock, unsigned long *flags);
+void tetris_unlock_irqrestore(spinlock_t *lock, unsigned long flags);
+
+#endif /* TETRIS_LOCK_H */
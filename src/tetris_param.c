@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * The `mode`, `tick` and `device_name` module parameters. Rust module
+ * parameters can only be numbers, and these read better as words:
+ * mode=shared or mode=private, tick=hrtimer or tick=workqueue,
+ * device_name=tetris.
+ */
+
+#include <linux/kernel.h>
+#include <linux/moduleparam.h>
+#include <linux/string.h>
+
+#include "tetris_param.h"
+
+static int mode = TETRIS_MODE_SHARED;
+
+static const char * const mode_names[] = {
+	[TETRIS_MODE_SHARED] = "shared",
+	[TETRIS_MODE_PRIVATE] = "private",
+};
+
+static int mode_set(const char *val, const struct kernel_param *kp)
+{
+	int ret = sysfs_match_string(mode_names, val);
+
+	if (ret < 0)
+		return ret;
+	WRITE_ONCE(mode, ret);
+	return 0;
+}
+
+static int mode_get(char *buffer, const struct kernel_param *kp)
+{
+	return scnprintf(buffer, PAGE_SIZE, "%s\n", mode_names[READ_ONCE(mode)]);
+}
+
+static const struct kernel_param_ops mode_ops = {
+	.set = mode_set,
+	.get = mode_get,
+};
+
+module_param_cb(mode, &mode_ops, NULL, 0644);
+MODULE_PARM_DESC(mode, "Whether opens of /dev/tetris share one game (shared) or each get their own (private)");
+
+/* Takes effect for the next open. */
+int tetris_game_mode(void)
+{
+	return READ_ONCE(mode);
+}
+
+static int tick = TETRIS_TICK_HRTIMER;
+
+static const char * const tick_names[] = {
+	[TETRIS_TICK_HRTIMER] = "hrtimer",
+	[TETRIS_TICK_WORKQUEUE] = "workqueue",
+};
+
+static int tick_set(const char *val, const struct kernel_param *kp)
+{
+	int ret = sysfs_match_string(tick_names, val);
+
+	if (ret < 0)
+		return ret;
+	WRITE_ONCE(tick, ret);
+	return 0;
+}
+
+static int tick_get(char *buffer, const struct kernel_param *kp)
+{
+	return scnprintf(buffer, PAGE_SIZE, "%s\n", tick_names[READ_ONCE(tick)]);
+}
+
+static const struct kernel_param_ops tick_ops = {
+	.set = tick_set,
+	.get = tick_get,
+};
+
+module_param_cb(tick, &tick_ops, NULL, 0644);
+MODULE_PARM_DESC(tick, "What drives gravity: an hrtimer (hrtimer) or delayed work in process context (workqueue)");
+
+/* Read as each game starts ticking, so it applies to games made after. */
+int tetris_tick_driver(void)
+{
+	return READ_ONCE(tick);
+}
+
+static char node_name[32] = "tetris";
+
+static int name_set(const char *val, const struct kernel_param *kp)
+{
+	size_t len = strcspn(val, "\n");
+
+	/* It names nodes right in /dev. */
+	if (!len || len >= sizeof(node_name) || memchr(val, '/', len))
+		return -EINVAL;
+	memcpy(node_name, val, len);
+	node_name[len] = '\0';
+	return 0;
+}
+
+static int name_get(char *buffer, const struct kernel_param *kp)
+{
+	return scnprintf(buffer, PAGE_SIZE, "%s\n", node_name);
+}
+
+static const struct kernel_param_ops name_ops = {
+	.set = name_set,
+	.get = name_get,
+};
+
+module_param_cb(device_name, &name_ops, NULL, 0444);
+MODULE_PARM_DESC(device_name, "Name of the game device nodes: /dev/<name>, or /dev/<name>0.. with instances set");
+
+/* Only set at load, so the string never changes under a reader. */
+const char *tetris_device_name(void)
+{
+	return node_name;
+}
@@ -0,0 +1,22 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+
+#ifndef TETRIS_PARAM_H
+#define TETRIS_PARAM_H
+
+/* Values of the `mode` parameter; `GameSharing` in tetris/chardev.rs. */
+enum tetris_mode {
+	TETRIS_MODE_SHARED,
+	TETRIS_MODE_PRIVATE,
+};
+
+/* Values of the `tick` parameter; `TickDriver` in tetris.rs. */
+enum tetris_tick {
+	TETRIS_TICK_HRTIMER,
+	TETRIS_TICK_WORKQUEUE,
+};
+
+int tetris_game_mode(void);
+int tetris_tick_driver(void);
+const char *tetris_device_name(void);
+
+#endif /* TETRIS_PARAM_H */
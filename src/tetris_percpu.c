@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Per-CPU counters for the statistics bumped on every read, write, ioctl
+ * and gravity tick, so that spectators reading on many CPUs don't all
+ * bounce one cache line. Each CPU adds to its own copy; reading a counter
+ * adds the copies up, which only debugfs does.
+ */
+
+#include <linux/cpumask.h>
+#include <linux/percpu.h>
+
+#include "tetris_percpu.h"
+
+static DEFINE_PER_CPU(u64, tetris_totals[TETRIS_TOTALS]);
+
+u64 __percpu *tetris_percpu_alloc(void)
+{
+	return alloc_percpu(u64);
+}
+
+void tetris_percpu_free(u64 __percpu *counter)
+{
+	free_percpu(counter);
+}
+
+/* Add @n to @counter and, unless @total is negative, to that total. */
+void tetris_percpu_add(u64 __percpu *counter, int total, u64 n)
+{
+	this_cpu_add(*counter, n);
+	if (total >= 0)
+		this_cpu_add(tetris_totals[total], n);
+}
+
+u64 tetris_percpu_sum(u64 __percpu *counter)
+{
+	u64 sum = 0;
+	int cpu;
+
+	for_each_possible_cpu(cpu)
+		sum += *per_cpu_ptr(counter, cpu);
+	return sum;
+}
+
+/* Adds racing on other CPUs may survive, as with any stats reset. */
+void tetris_percpu_zero(u64 __percpu *counter)
+{
+	int cpu;
+
+	for_each_possible_cpu(cpu)
+		*per_cpu_ptr(counter, cpu) = 0;
+}
+
+u64 tetris_percpu_total(int total)
+{
+	u64 sum = 0;
+	int cpu;
+
+	for_each_possible_cpu(cpu)
+		sum += per_cpu(tetris_totals[total], cpu);
+	return sum;
+}
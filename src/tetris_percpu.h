@@ -0,0 +1,24 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+
+#ifndef TETRIS_PERCPU_H
+#define TETRIS_PERCPU_H
+
+#include <linux/percpu.h>
+#include <linux/types.h>
+
+/* Module-wide totals, `PerCpuTotal` in tetris.rs. */
+enum tetris_total {
+	TETRIS_TOTAL_READS,
+	TETRIS_TOTAL_WRITES,
+	TETRIS_TOTAL_IOCTLS,
+	TETRIS_TOTALS
+};
+
+u64 __percpu *tetris_percpu_alloc(void);
+void tetris_percpu_free(u64 __percpu *counter);
+void tetris_percpu_add(u64 __percpu *counter, int total, u64 n);
+u64 tetris_percpu_sum(u64 __percpu *counter);
+void tetris_percpu_zero(u64 __percpu *counter);
+u64 tetris_percpu_total(int total);
+
+#endif /* TETRIS_PERCPU_H */
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Tuning knobs under /proc/sys/kernel/tetris/, for defaults admins may
+ * want to change without reloading the module:
+ *
+ *	gravity_base_ms	level 1 gravity period; other levels scale with it
+ *	lock_delay_ms	how long a grounded piece may move in 20G mode
+ *	das_ms, arr_ms	auto shift timing new games start with
+ *	render_flags	TetrisConfig::render bits new games start with
+ *
+ * The gravity knobs take effect at the next tick, the rest for new games.
+ */
+
+#include <linux/kernel.h>
+#include <linux/sysctl.h>
+
+#include "tetris_sysctl.h"
+
+static int gravity_base_ms = TETRIS_GRAVITY_BASE_MS;
+static int lock_delay_ms = TETRIS_LOCK_DELAY_MS;
+static int das_ms = TETRIS_DAS_DEFAULT_MS;
+static int arr_ms = TETRIS_ARR_DEFAULT_MS;
+static int render_flags;
+
+static int gravity_base_max = 10000;
+static int lock_delay_max = 5000;
+static int autoshift_max = TETRIS_AUTOSHIFT_MAX_MS;
+static int render_all = TETRIS_RENDER_ALL;
+
+static const struct ctl_table tetris_sysctls[] = {
+	{
+		.procname	= "gravity_base_ms",
+		.data		= &gravity_base_ms,
+		.maxlen		= sizeof(int),
+		.mode		= 0644,
+		.proc_handler	= proc_dointvec_minmax,
+		.extra1		= SYSCTL_ONE,
+		.extra2		= &gravity_base_max,
+	},
+	{
+		.procname	= "lock_delay_ms",
+		.data		= &lock_delay_ms,
+		.maxlen		= sizeof(int),
+		.mode		= 0644,
+		.proc_handler	= proc_dointvec_minmax,
+		.extra1		= SYSCTL_ONE,
+		.extra2		= &lock_delay_max,
+	},
+	{
+		.procname	= "das_ms",
+		.data		= &das_ms,
+		.maxlen		= sizeof(int),
+		.mode		= 0644,
+		.proc_handler	= proc_dointvec_minmax,
+		.extra1		= SYSCTL_ZERO,
+		.extra2		= &autoshift_max,
+	},
+	{
+		.procname	= "arr_ms",
+		.data		= &arr_ms,
+		.maxlen		= sizeof(int),
+		.mode		= 0644,
+		.proc_handler	= proc_dointvec_minmax,
+		.extra1		= SYSCTL_ZERO,
+		.extra2		= &autoshift_max,
+	},
+	{
+		.procname	= "render_flags",
+		.data		= &render_flags,
+		.maxlen		= sizeof(int),
+		.mode		= 0644,
+		.proc_handler	= proc_dointvec_minmax,
+		.extra1		= SYSCTL_ZERO,
+		.extra2		= &render_all,
+	},
+};
+
+static struct ctl_table_header *tetris_sysctl_header;
+
+int tetris_sysctl_register(void)
+{
+	/* Without sysctl the defaults just stay. */
+	if (!IS_ENABLED(CONFIG_SYSCTL))
+		return 0;
+	tetris_sysctl_header = register_sysctl("kernel/tetris", tetris_sysctls);
+	return tetris_sysctl_header ? 0 : -ENOMEM;
+}
+
+void tetris_sysctl_unregister(void)
+{
+	unregister_sysctl_table(tetris_sysctl_header);
+}
+
+int tetris_sysctl_gravity_base_ms(void)
+{
+	return READ_ONCE(gravity_base_ms);
+}
+
+int tetris_sysctl_lock_delay_ms(void)
+{
+	return READ_ONCE(lock_delay_ms);
+}
+
+int tetris_sysctl_das_ms(void)
+{
+	return READ_ONCE(das_ms);
+}
+
+int tetris_sysctl_arr_ms(void)
+{
+	return READ_ONCE(arr_ms);
+}
+
+int tetris_sysctl_render_flags(void)
+{
+	return READ_ONCE(render_flags);
+}
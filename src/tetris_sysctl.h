@@ -0,0 +1,25 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+
+#ifndef TETRIS_SYSCTL_H
+#define TETRIS_SYSCTL_H
+
+/* The first gravity period, `GRAVITY_MS[0]` in tetris.rs. */
+#define TETRIS_GRAVITY_BASE_MS		1000
+#define TETRIS_LOCK_DELAY_MS		500
+/* Delayed auto shift: 10 frames before a held direction repeats. */
+#define TETRIS_DAS_DEFAULT_MS		167
+/* Auto repeat rate: one cell every 2 frames once DAS is charged. */
+#define TETRIS_ARR_DEFAULT_MS		33
+/* `AUTOSHIFT_MAX_MS` and `RENDER_ALL` in tetris.rs. */
+#define TETRIS_AUTOSHIFT_MAX_MS		1000
+#define TETRIS_RENDER_ALL		15
+
+int tetris_sysctl_register(void);
+void tetris_sysctl_unregister(void);
+int tetris_sysctl_gravity_base_ms(void);
+int tetris_sysctl_lock_delay_ms(void);
+int tetris_sysctl_das_ms(void);
+int tetris_sysctl_arr_ms(void);
+int tetris_sysctl_render_flags(void);
+
+#endif /* TETRIS_SYSCTL_H */
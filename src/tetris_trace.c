@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * The tetris trace events. Rust can't expand TRACE_EVENT(), so tetris.rs
+ * fires them through the functions here.
+ */
+
+#define CREATE_TRACE_POINTS
+#include "tetris_trace.h"
+
+void tetris_trace_ioctl(u32 cmd, u64 wait_ns, u64 held_ns)
+{
+	trace_tetris_ioctl(cmd, wait_ns, held_ns);
+}
+
+void tetris_trace_render(u32 len, u64 wait_ns, u64 held_ns)
+{
+	trace_tetris_render(len, wait_ns, held_ns);
+}
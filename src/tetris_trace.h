@@ -0,0 +1,78 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+/*
+ * Trace events for time spent in the game lock, to see how gravity ticks,
+ * writers and readers contend for it:
+ *
+ *	echo 1 > /sys/kernel/tracing/events/tetris/enable
+ *	cat /sys/kernel/tracing/trace_pipe
+ *
+ * Both times are in ns: wait_ns until the lock was taken, held_ns from
+ * then until it was let go (or, for a render, from when the reader last
+ * woke up for a new frame). Text frames are drawn after letting go, from
+ * a copy of the game, so held_ns only covers taking the copy.
+ */
+
+#undef TRACE_SYSTEM
+#define TRACE_SYSTEM tetris
+
+#if !defined(TETRIS_TRACE_H) || defined(TRACE_HEADER_MULTI_READ)
+#define TETRIS_TRACE_H
+
+#include <linux/tracepoint.h>
+
+TRACE_EVENT(tetris_ioctl,
+
+	TP_PROTO(u32 cmd, u64 wait_ns, u64 held_ns),
+
+	TP_ARGS(cmd, wait_ns, held_ns),
+
+	TP_STRUCT__entry(
+		__field(u32, cmd)
+		__field(u64, wait_ns)
+		__field(u64, held_ns)
+	),
+
+	TP_fast_assign(
+		__entry->cmd = cmd;
+		__entry->wait_ns = wait_ns;
+		__entry->held_ns = held_ns;
+	),
+
+	TP_printk("cmd=%#x wait_ns=%llu held_ns=%llu",
+		  __entry->cmd, __entry->wait_ns, __entry->held_ns)
+);
+
+TRACE_EVENT(tetris_render,
+
+	TP_PROTO(u32 len, u64 wait_ns, u64 held_ns),
+
+	TP_ARGS(len, wait_ns, held_ns),
+
+	TP_STRUCT__entry(
+		__field(u32, len)
+		__field(u64, wait_ns)
+		__field(u64, held_ns)
+	),
+
+	TP_fast_assign(
+		__entry->len = len;
+		__entry->wait_ns = wait_ns;
+		__entry->held_ns = held_ns;
+	),
+
+	TP_printk("len=%u wait_ns=%llu held_ns=%llu",
+		  __entry->len, __entry->wait_ns, __entry->held_ns)
+);
+
+void tetris_trace_ioctl(u32 cmd, u64 wait_ns, u64 held_ns);
+void tetris_trace_render(u32 len, u64 wait_ns, u64 held_ns);
+
+#endif /* TETRIS_TRACE_H */
+
+/* Out of tree, so tell define_trace.h where this file is. */
+#undef TRACE_INCLUDE_PATH
+#define TRACE_INCLUDE_PATH .
+#undef TRACE_INCLUDE_FILE
+#define TRACE_INCLUDE_FILE tetris_trace
+
+#include <trace/define_trace.h>
@@ -3,6 +3,7 @@
 
 #include <errno.h>
 #include <fcntl.h>
+#include <poll.h>
 #include <signal.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -13,7 +14,6 @@
 #define TETRIS_DEV "/dev/tetris"
 #define BUFFER_SIZE 16384
 #define OUTPUT_BUFFER_SIZE (BUFFER_SIZE + 256)
-#define AUTO_DROP_INTERVAL 5
 #define FRAME_DELAY_US 100000
 
 static int fd = -1;
@@ -94,8 +94,10 @@ void set_nonblocking_mode(struct termios *old_tio) {
 
 int is_valid_command(char cmd) {
   return cmd == 'a' || cmd == 'A' || cmd == 'd' || cmd == 'D' || cmd == 's' ||
-         cmd == 'S' || cmd == 'w' || cmd == 'W' || cmd == ' ' || cmd == 'r' ||
-         cmd == 'R';
+         cmd == 'S' || cmd == 'x' || cmd == 'X' || cmd == 'w' || cmd == 'W' ||
+         cmd == 'z' || cmd == 'Z' || cmd == 'c' || cmd == 'C' || cmd == ' ' ||
+         cmd == 'r' || cmd == 'R' || cmd == 'p' || cmd == 'P' ||
+         (cmd >= '0' && cmd <= '9');
 }
 
 static void get_term_env(int *likely_qemu_console, int *likely_linux_console) {
@@ -141,10 +143,16 @@ static void print_controls(void) {
   write_str("Controls:\n");
   write_str("  a/A - Move left\n");
   write_str("  d/D - Move right\n");
-  write_str("  s/S - Move down\n");
+  write_str("  s/S - Soft drop\n");
+  write_str("  x/X - Soft drop to floor\n");
   write_str("  w/W - Rotate\n");
+  write_str("  z/Z - Rotate counter-clockwise\n");
+  write_str("  c/C - Hold\n");
+  write_str("  Arrow keys - Rotate, soft drop, move\n");
+  write_str("  <n><key> - Repeat a key n times, e.g. 4a\n");
   write_str("  Space - Hard drop\n");
   write_str("  r/R - Reset game\n");
+  write_str("  p/P - Pause/resume\n");
   write_str("  q/Q - Quit\n\n");
 
   if (use_ansi) {
@@ -178,6 +186,17 @@ void handle_input(char cmd) {
   }
 }
 
+/* Pass arrow keys on as they are; the device decodes ESC [ A and co. */
+static void handle_escape(void) {
+  char seq[8] = {'\033'};
+  ssize_t len = read(STDIN_FILENO, seq + 1, sizeof(seq) - 1);
+
+  if (write(fd, seq, len > 0 ? (size_t)len + 1 : 1) < 0) {
+    perror("Write error");
+    running = 0;
+  }
+}
+
 static void render_game(char *buffer, int len) {
   if (!use_ansi) {
     write_all(STDOUT_FILENO, buffer, (size_t)len);
@@ -237,7 +256,8 @@ static void render_game(char *buffer, int len) {
 int main() {
   use_ansi = isatty(STDOUT_FILENO) && isatty(STDIN_FILENO);
 
-  fd = open(TETRIS_DEV, O_RDWR);
+  /* Blocking reads wait for the next frame; poll() does that for us. */
+  fd = open(TETRIS_DEV, O_RDWR | O_NONBLOCK);
   if (fd < 0) {
     fprintf(stderr, "Failed to open %s: %s\n", TETRIS_DEV, strerror(errno));
     fprintf(stderr, "\nPlease load the kernel module first:\n");
@@ -259,10 +279,14 @@ int main() {
 
   char buffer[BUFFER_SIZE];
   char cmd;
-  int auto_drop_counter = 0;
+  struct pollfd fds[2] = {
+      {.fd = fd, .events = POLLIN},
+      {.fd = STDIN_FILENO, .events = POLLIN},
+  };
 
   while (running) {
-    ssize_t bytes = read(fd, buffer, sizeof(buffer) - 1);
+    /* Every read at offset 0 is a whole new frame. */
+    ssize_t bytes = pread(fd, buffer, sizeof(buffer) - 1, 0);
     if (bytes > 0) {
       buffer[bytes] = '\0';
       render_game(buffer, bytes);
@@ -277,17 +301,19 @@ int main() {
       if (cmd == 'q' || cmd == 'Q') {
         break;
       }
-      handle_input(cmd);
+      if (cmd == '\033') {
+        handle_escape();
+      } else {
+        handle_input(cmd);
+      }
     }
 
-    auto_drop_counter++;
-    if (auto_drop_counter >= AUTO_DROP_INTERVAL) {
-      auto_drop_counter = 0;
-      cmd = 's';
-      handle_input(cmd);
+    /* Sleep until the frame changes or a key arrives; the timeout keeps
+     * the clock ticking. */
+    if (poll(fds, 2, FRAME_DELAY_US / 1000) < 0 && errno != EINTR) {
+      perror("poll");
+      break;
     }
-
-    usleep(FRAME_DELAY_US);
   }
 
   cleanup();